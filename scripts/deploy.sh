@@ -1,2 +1,4 @@
 #!/bin/sh
-echo "Running deploy script"
+# Run by webhook_handler with the event in EVENT, REPO, REF, SHA, PUSHER and
+# DELIVERY_ID, and the raw payload on stdin.
+echo "Running deploy script for ${REPO:-unknown repository} ${REF} ${SHA}"
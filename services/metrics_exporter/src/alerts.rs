@@ -0,0 +1,590 @@
+//! Alert rules evaluated against our own registry on every scrape cycle.
+//!
+//! Rules use a small PromQL subset: an instant selector or `rate`/`increase`
+//! over a range selector, optionally compared against a number, e.g.
+//! `rate(http_requests_total{code=~"5.."}[5m]) > 0.1`. Active alerts are
+//! exposed as `ALERTS` series and transitions are posted to a webhook.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use std::{fmt, fs};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::Registry;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::exposition::{self, family, sample, FlatSample, Labels};
+use crate::federation::Federation;
+use crate::query::{parse_duration, Selector};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    alert: String,
+    expr: String,
+    /// How long the condition must hold before the alert fires, e.g. `5m`.
+    #[serde(rename = "for", default)]
+    for_: Option<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+struct Rule {
+    alert: String,
+    expr: Expr,
+    for_: Duration,
+    labels: BTreeMap<String, String>,
+    annotations: BTreeMap<String, String>,
+}
+
+struct Expr {
+    func: Option<Func>,
+    selector: Selector,
+    compare: Option<(CmpOp, f64)>,
+}
+
+#[derive(Clone, Copy)]
+enum Func {
+    Rate(Duration),
+    Increase(Duration),
+}
+
+#[derive(Clone, Copy)]
+enum CmpOp {
+    Gt,
+    Lt,
+    Ge,
+    Le,
+    Eq,
+    Ne,
+}
+
+impl Expr {
+    fn parse(input: &str) -> Result<Self> {
+        let (lhs, compare) = split_comparison(input)?;
+        let lhs = lhs.trim();
+        // A `(` is only a call when a function name precedes it; label
+        // regexes such as `{job=~"(a|b)"}` contain them too.
+        let call = lhs.split_once('(').filter(|(name, _)| is_identifier(name.trim()));
+        let (func, inner) = match call {
+            Some((name, args)) => {
+                let args = args.trim_end().strip_suffix(')').context("missing closing parenthesis")?;
+                let (sel, range) = args.trim().strip_suffix(']').and_then(|a| a.rsplit_once('[')).context(
+                    "rate and increase need a range selector such as metric[5m]",
+                )?;
+                let range = parse_duration(range)?;
+                let func = match name.trim() {
+                    "rate" => Func::Rate(range),
+                    "increase" => Func::Increase(range),
+                    other => bail!("unsupported function {:?}", other),
+                };
+                (Some(func), sel)
+            }
+            None => (None, lhs),
+        };
+        Ok(Self { func, selector: Selector::parse(inner.trim())?, compare })
+    }
+}
+
+fn is_identifier(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+/// Split `lhs OP number` at the first comparison operator outside braces,
+/// brackets and quotes.
+fn split_comparison(input: &str) -> Result<(&str, Option<(CmpOp, f64)>)> {
+    let bytes = input.as_bytes();
+    let (mut depth, mut quoted) = (0i32, false);
+    for i in 0..bytes.len() {
+        match bytes[i] {
+            b'"' if i == 0 || bytes[i - 1] != b'\\' => quoted = !quoted,
+            b'{' | b'(' | b'[' if !quoted => depth += 1,
+            b'}' | b')' | b']' if !quoted => depth -= 1,
+            b'>' | b'<' | b'=' | b'!' if !quoted && depth == 0 => {
+                let two = input.get(i..i + 2).unwrap_or("");
+                let (op, len) = match (two, bytes[i]) {
+                    (">=", _) => (CmpOp::Ge, 2),
+                    ("<=", _) => (CmpOp::Le, 2),
+                    ("==", _) => (CmpOp::Eq, 2),
+                    ("!=", _) => (CmpOp::Ne, 2),
+                    (_, b'>') => (CmpOp::Gt, 1),
+                    (_, b'<') => (CmpOp::Lt, 1),
+                    _ => bail!("unexpected {:?} in expression", &input[i..]),
+                };
+                let rhs = input[i + len..].trim();
+                let threshold: f64 = rhs.parse().with_context(|| format!("threshold {:?} is not a number", rhs))?;
+                return Ok((&input[..i], Some((op, threshold))));
+            }
+            _ => {}
+        }
+    }
+    Ok((input, None))
+}
+
+impl CmpOp {
+    fn holds(self, lhs: f64, rhs: f64) -> bool {
+        match self {
+            CmpOp::Gt => lhs > rhs,
+            CmpOp::Lt => lhs < rhs,
+            CmpOp::Ge => lhs >= rhs,
+            CmpOp::Le => lhs <= rhs,
+            CmpOp::Eq => lhs == rhs,
+            CmpOp::Ne => lhs != rhs,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Pending,
+    Firing,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            State::Pending => "pending",
+            State::Firing => "firing",
+        })
+    }
+}
+
+struct Active {
+    state: State,
+    active_at: DateTime<Utc>,
+    value: f64,
+    labels: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct Notification {
+    status: &'static str,
+    labels: BTreeMap<String, String>,
+    annotations: BTreeMap<String, String>,
+    #[serde(rename = "startsAt")]
+    starts_at: String,
+    #[serde(rename = "endsAt", skip_serializing_if = "Option::is_none")]
+    ends_at: Option<String>,
+    value: f64,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload {
+    alerts: Vec<Notification>,
+}
+
+/// Active alerts, keyed by rule index and label set, shared with the collector.
+type ActiveAlerts = Arc<RwLock<BTreeMap<(usize, Labels), Active>>>;
+
+pub struct AlertEngine {
+    rules: Vec<Rule>,
+    registry: Registry,
+    federation: Federation,
+    client: Client,
+    webhook: Option<String>,
+    /// Recent samples for series used by `rate`/`increase`, oldest first.
+    history: HashMap<(String, Labels), VecDeque<(f64, f64)>>,
+    active: ActiveAlerts,
+}
+
+impl AlertEngine {
+    pub fn load(path: &str, registry: Registry, federation: Federation, webhook: Option<String>) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+        let file: RulesFile = toml::from_str(&text).with_context(|| format!("parsing {}", path))?;
+        let rules = file
+            .rules
+            .into_iter()
+            .map(|r| {
+                let expr = Expr::parse(&r.expr).with_context(|| format!("rule {:?}: expr {:?}", r.alert, r.expr))?;
+                let for_ = match &r.for_ {
+                    Some(d) => parse_duration(d).with_context(|| format!("rule {:?}: for", r.alert))?,
+                    None => Duration::ZERO,
+                };
+                Ok(Rule { alert: r.alert, expr, for_, labels: r.labels, annotations: r.annotations })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let engine = Self {
+            rules,
+            registry: registry.clone(),
+            federation,
+            client: Client::new(),
+            webhook,
+            history: HashMap::new(),
+            active: Arc::default(),
+        };
+        registry.register(Box::new(AlertsCollector::new(engine.active.clone())?))?;
+        Ok(engine)
+    }
+
+    pub async fn run(mut self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let notifications = self.evaluate(Utc::now());
+            if let (Some(url), false) = (&self.webhook, notifications.is_empty()) {
+                let payload = WebhookPayload { alerts: notifications };
+                if let Err(e) = self.client.post(url).json(&payload).send().await.and_then(|r| r.error_for_status()) {
+                    eprintln!("failed to send alert notification: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Run every rule once and return the firing/resolved transitions.
+    fn evaluate(&mut self, now: DateTime<Utc>) -> Vec<Notification> {
+        let ts = now.timestamp_millis() as f64 / 1000.0;
+        let samples = exposition::flatten(&self.federation.gather(&self.registry));
+        self.record_history(&samples, ts);
+
+        let mut notifications = Vec::new();
+        let mut active = self.active.write().unwrap();
+        for (i, rule) in self.rules.iter().enumerate() {
+            let results = self.query(&rule.expr, &samples, ts);
+            for (labels, value) in &results {
+                let key = (i, labels.clone());
+                let alert = active.entry(key).or_insert_with(|| {
+                    let mut all: BTreeMap<String, String> = labels.iter().cloned().collect();
+                    all.extend(rule.labels.clone());
+                    all.insert("alertname".into(), rule.alert.clone());
+                    Active { state: State::Pending, active_at: now, value: *value, labels: all }
+                });
+                alert.value = *value;
+                let held = (now - alert.active_at).to_std().unwrap_or_default();
+                if alert.state == State::Pending && held >= rule.for_ {
+                    alert.state = State::Firing;
+                    notifications.push(notification(rule, alert, None));
+                }
+            }
+            active.retain(|(rule_index, labels), alert| {
+                if *rule_index != i || results.iter().any(|(l, _)| l == labels) {
+                    return true;
+                }
+                if alert.state == State::Firing {
+                    notifications.push(notification(rule, alert, Some(now)));
+                }
+                false
+            });
+        }
+        notifications
+    }
+
+    /// Current value of `expr` for every matching series that passes its comparison.
+    fn query(&self, expr: &Expr, samples: &[FlatSample], ts: f64) -> Vec<(Labels, f64)> {
+        let values: Vec<(Labels, f64)> = match expr.func {
+            None => samples
+                .iter()
+                .filter(|s| expr.selector.matches(&s.name, &s.labels))
+                .map(|s| (s.labels.clone(), s.value))
+                .collect(),
+            Some(Func::Rate(range) | Func::Increase(range)) => samples
+                .iter()
+                .filter(|s| expr.selector.matches(&s.name, &s.labels))
+                .filter_map(|s| {
+                    let points = self.history.get(&(s.name.clone(), s.labels.clone()))?;
+                    let window: Vec<&(f64, f64)> =
+                        points.iter().filter(|(t, _)| *t >= ts - range.as_secs_f64()).collect();
+                    let (first, last) = (window.first()?, window.last()?);
+                    if last.0 <= first.0 {
+                        return None;
+                    }
+                    // Counter resets: a drop means the counter restarted from zero.
+                    let increase = window.windows(2).fold(0.0, |acc, w| {
+                        let (prev, cur) = (w[0].1, w[1].1);
+                        acc + if cur >= prev { cur - prev } else { cur }
+                    });
+                    let value = match expr.func {
+                        Some(Func::Rate(_)) => increase / (last.0 - first.0),
+                        _ => increase,
+                    };
+                    Some((s.labels.clone(), value))
+                })
+                .collect(),
+        };
+        values
+            .into_iter()
+            .filter(|(_, v)| match expr.compare {
+                Some((op, threshold)) => op.holds(*v, threshold),
+                None => true,
+            })
+            .collect()
+    }
+
+    /// Keep samples for series referenced by range functions, trimmed to the longest range.
+    fn record_history(&mut self, samples: &[FlatSample], ts: f64) {
+        let ranged: Vec<(&Selector, Duration)> = self
+            .rules
+            .iter()
+            .filter_map(|r| match r.expr.func {
+                Some(Func::Rate(range) | Func::Increase(range)) => Some((&r.expr.selector, range)),
+                None => None,
+            })
+            .collect();
+        for s in samples {
+            let Some(range) = ranged.iter().filter(|(sel, _)| sel.matches(&s.name, &s.labels)).map(|(_, r)| *r).max() else {
+                continue;
+            };
+            let points = self.history.entry((s.name.clone(), s.labels.clone())).or_default();
+            points.push_back((ts, s.value));
+            while points.front().is_some_and(|(t, _)| *t < ts - range.as_secs_f64()) {
+                points.pop_front();
+            }
+        }
+        self.history.retain(|_, points| points.back().is_some_and(|(t, _)| *t >= ts - 3600.0));
+    }
+}
+
+fn notification(rule: &Rule, alert: &Active, ended: Option<DateTime<Utc>>) -> Notification {
+    let annotations = rule
+        .annotations
+        .iter()
+        .map(|(k, v)| {
+            let mut text = v.replace("{{ $value }}", &alert.value.to_string());
+            for (name, value) in &alert.labels {
+                text = text.replace(&format!("{{{{ $labels.{} }}}}", name), value);
+            }
+            (k.clone(), text)
+        })
+        .collect();
+    Notification {
+        status: if ended.is_some() { "resolved" } else { "firing" },
+        labels: alert.labels.clone(),
+        annotations,
+        starts_at: alert.active_at.to_rfc3339(),
+        ends_at: ended.map(|t| t.to_rfc3339()),
+        value: alert.value,
+    }
+}
+
+/// Exposes active alerts as `ALERTS{alertname, alertstate, ...} 1`.
+struct AlertsCollector {
+    active: ActiveAlerts,
+    desc: Desc,
+}
+
+impl AlertsCollector {
+    fn new(active: ActiveAlerts) -> prometheus::Result<Self> {
+        let desc = Desc::new(
+            "ALERTS".into(),
+            "Pending and firing alerts".into(),
+            vec!["alertname".into(), "alertstate".into()],
+            HashMap::new(),
+        )?;
+        Ok(Self { active, desc })
+    }
+}
+
+impl Collector for AlertsCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let active = self.active.read().unwrap();
+        let metrics: Vec<_> = active
+            .values()
+            .map(|alert| {
+                let state = alert.state.to_string();
+                let mut labels: Vec<(&str, &str)> =
+                    alert.labels.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+                labels.push(("alertstate", &state));
+                sample(MetricType::GAUGE, &labels, 1.0)
+            })
+            .collect();
+        if metrics.is_empty() {
+            return Vec::new();
+        }
+        vec![family("ALERTS", "Pending and firing alerts", MetricType::GAUGE, metrics)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use prometheus::{Gauge, IntCounter};
+
+    use super::*;
+
+    fn engine(name: &str, rules: &str, registry: &Registry, webhook: Option<String>) -> AlertEngine {
+        let path = std::env::temp_dir().join(format!("metrics-exporter-rules-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, rules).unwrap();
+        let engine = AlertEngine::load(path.to_str().unwrap(), registry.clone(), Federation::default(), webhook);
+        fs::remove_file(&path).unwrap();
+        engine.unwrap()
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    /// `(alertname, alertstate)` of every `ALERTS` series.
+    fn alerts(registry: &Registry) -> Vec<(String, String)> {
+        let samples = exposition::flatten(&registry.gather());
+        let label = |s: &FlatSample, name: &str| s.labels.iter().find(|(k, _)| k == name).unwrap().1.clone();
+        samples.iter().filter(|s| s.name == "ALERTS").map(|s| (label(s, "alertname"), label(s, "alertstate"))).collect()
+    }
+
+    #[test]
+    fn parses_parentheses_in_label_regexes() {
+        let expr = Expr::parse(r#"up{job=~"(api|web)"} == 0"#).unwrap();
+        assert!(expr.func.is_none());
+        assert!(expr.selector.matches("up", &vec![("job".into(), "web".into())]));
+
+        let expr = Expr::parse(r#"rate(http_requests_total{code=~"(4|5).."}[5m]) > 1"#).unwrap();
+        assert!(matches!(expr.func, Some(Func::Rate(d)) if d == Duration::from_secs(300)));
+        assert!(expr.selector.matches("http_requests_total", &vec![("code".into(), "503".into())]));
+
+        assert!(Expr::parse("sum(up) > 1").is_err());
+    }
+
+    #[test]
+    fn parses_comparisons_and_functions() {
+        // Whether each comparison holds for 0, 1 and 2 against a threshold of 1.
+        let table = [
+            ("up > 1", [false, false, true]),
+            ("up<1", [true, false, false]),
+            ("up >= 1", [false, true, true]),
+            ("up <= 1", [true, true, false]),
+            ("up == 1", [false, true, false]),
+            ("up != 1", [true, false, true]),
+        ];
+        for (input, expected) in table {
+            let (op, threshold) = Expr::parse(input).unwrap().compare.unwrap();
+            assert_eq!([0.0, 1.0, 2.0].map(|v| op.holds(v, threshold)), expected, "{}", input);
+        }
+        let increase = Expr::parse("increase(jobs_total[1h])").unwrap();
+        assert!(matches!(increase.func, Some(Func::Increase(d)) if d.as_secs() == 3600));
+        assert!(Expr::parse("up").unwrap().compare.is_none());
+        for bad in ["up > high", "up = 1", "rate(jobs_total) > 1", "rate(jobs_total[5m] > 1", "irate(jobs_total[5m])"] {
+            assert!(Expr::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn fires_after_for_and_resolves() {
+        let registry = Registry::new();
+        let depth = Gauge::new("queue_depth", "Jobs waiting").unwrap();
+        registry.register(Box::new(depth.clone())).unwrap();
+        let mut engine = engine(
+            "for",
+            r#"
+            [[rules]]
+            alert = "QueueBacklog"
+            expr = "queue_depth > 10"
+            for = "1m"
+            labels = { severity = "page" }
+            annotations = { summary = "{{ $value }} jobs waiting ({{ $labels.severity }})" }
+            "#,
+            &registry,
+            None,
+        );
+        depth.set(20.0);
+        assert!(engine.evaluate(at(0)).is_empty());
+        assert_eq!(alerts(&registry), [("QueueBacklog".into(), "pending".into())]);
+        assert!(engine.evaluate(at(30)).is_empty());
+
+        depth.set(25.0);
+        let fired = engine.evaluate(at(60));
+        assert_eq!(fired.len(), 1);
+        assert_eq!((fired[0].status, fired[0].value, fired[0].ends_at.as_deref()), ("firing", 25.0, None));
+        assert_eq!(fired[0].starts_at, at(0).to_rfc3339());
+        assert_eq!(fired[0].labels["severity"], "page");
+        assert_eq!(fired[0].annotations["summary"], "25 jobs waiting (page)");
+        assert_eq!(alerts(&registry), [("QueueBacklog".into(), "firing".into())]);
+        assert!(engine.evaluate(at(90)).is_empty());
+
+        depth.set(5.0);
+        let resolved = engine.evaluate(at(120));
+        assert_eq!(resolved.len(), 1);
+        assert_eq!((resolved[0].status, resolved[0].ends_at.clone()), ("resolved", Some(at(120).to_rfc3339())));
+        assert!(alerts(&registry).is_empty());
+
+        // A pending alert that clears never notifies.
+        depth.set(20.0);
+        assert!(engine.evaluate(at(150)).is_empty());
+        depth.set(0.0);
+        assert!(engine.evaluate(at(160)).is_empty());
+    }
+
+    #[test]
+    fn rates_counters_across_resets() {
+        let registry = Registry::new();
+        let jobs = IntCounter::new("jobs_total", "Jobs run").unwrap();
+        registry.register(Box::new(jobs.clone())).unwrap();
+        let mut engine = engine(
+            "rate",
+            "[[rules]]\nalert = \"Busy\"\nexpr = \"rate(jobs_total[2m]) > 0.5\"\n\
+             [[rules]]\nalert = \"Many\"\nexpr = \"increase(jobs_total[2m]) >= 90\"\n",
+            &registry,
+            None,
+        );
+        jobs.inc_by(100);
+        assert!(engine.evaluate(at(0)).is_empty());
+        jobs.inc_by(30);
+        assert!(engine.evaluate(at(60)).is_empty());
+        // The counter restarts: 130 -> 0 -> 60 is an increase of 60.
+        jobs.reset();
+        jobs.inc_by(60);
+        let fired = engine.evaluate(at(120));
+        let names: Vec<_> = fired.iter().map(|n| (n.labels["alertname"].as_str(), n.value)).collect();
+        assert_eq!(names, [("Busy", 0.75), ("Many", 90.0)]);
+        // Flat for the last two minutes.
+        let resolved = engine.evaluate(at(240));
+        assert_eq!(resolved.iter().filter(|n| n.status == "resolved").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn posts_transitions_to_the_webhook() {
+        let received: Arc<Mutex<Vec<serde_json::Value>>> = Arc::default();
+        let sink = received.clone();
+        let app = Router::new().route(
+            "/alerts",
+            post(move |Json(body): Json<serde_json::Value>| async move { sink.lock().unwrap().push(body) }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let registry = Registry::new();
+        let down = Gauge::new("down", "Services down").unwrap();
+        down.set(1.0);
+        registry.register(Box::new(down.clone())).unwrap();
+        let engine = engine("webhook", "[[rules]]\nalert = \"Down\"\nexpr = \"down > 0\"\n", &registry, Some(url));
+        tokio::spawn(engine.run(Duration::from_millis(20)));
+        for _ in 0..200 {
+            if !received.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        down.set(0.0);
+        for _ in 0..200 {
+            if received.lock().unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let received = received.lock().unwrap();
+        let statuses: Vec<_> = received.iter().map(|b| b["alerts"][0]["status"].as_str().unwrap()).collect();
+        assert_eq!(statuses, ["firing", "resolved"]);
+        assert_eq!(received[0]["alerts"][0]["labels"]["alertname"], "Down");
+        assert_eq!(received[0]["alerts"].as_array().unwrap().len(), 1);
+    }
+}
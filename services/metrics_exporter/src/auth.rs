@@ -0,0 +1,220 @@
+//! Access control for every endpoint: a client CIDR allowlist, then bearer
+//! token or basic auth.
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::config::ServerConfig;
+
+/// An IPv4 or IPv6 network such as `10.0.0.0/8`. A bare address is a
+/// single-host network.
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().context("invalid address")?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.trim().parse().context("invalid prefix length")?,
+            None => max,
+        };
+        if prefix > max {
+            bail!("prefix length {} is longer than {}", prefix, max);
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+pub struct Access {
+    allowed: Vec<Cidr>,
+    bearer_token: Option<String>,
+    basic: Option<(String, String)>,
+}
+
+impl Access {
+    /// Build from an already validated server config.
+    pub fn new(config: &ServerConfig) -> anyhow::Result<Self> {
+        let allowed = config.allowed_cidrs.iter().map(|c| c.parse()).collect::<anyhow::Result<_>>()?;
+        let basic = config.auth.username.clone().zip(config.auth.password.clone());
+        Ok(Self { allowed, bearer_token: config.auth.bearer_token.clone(), basic })
+    }
+
+    fn authorized(&self, header: Option<&str>) -> bool {
+        if self.bearer_token.is_none() && self.basic.is_none() {
+            return true;
+        }
+        let Some(header) = header else { return false };
+        if let (Some(token), Some(given)) = (&self.bearer_token, header.strip_prefix("Bearer ")) {
+            return constant_time_eq(token.as_bytes(), given.trim().as_bytes());
+        }
+        if let (Some((user, password)), Some(given)) = (&self.basic, header.strip_prefix("Basic ")) {
+            let Ok(decoded) = STANDARD.decode(given.trim()) else { return false };
+            let expected = format!("{}:{}", user, password);
+            return constant_time_eq(expected.as_bytes(), &decoded);
+        }
+        false
+    }
+}
+
+/// Middleware rejecting clients outside the allowlist (403) and requests
+/// without valid credentials (401).
+pub async fn guard(
+    State(access): State<Arc<Access>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if !access.allowed.is_empty() && !access.allowed.iter().any(|c| c.contains(peer.ip())) {
+        return (StatusCode::FORBIDDEN, "client address not allowed\n").into_response();
+    }
+    let header = req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    if !access.authorized(header) {
+        let challenge = if access.basic.is_some() { r#"Basic realm="metrics_exporter""# } else { "Bearer" };
+        return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, challenge)], "unauthorized\n").into_response();
+    }
+    next.run(req).await
+}
+
+/// Compare secrets without leaking how much of them matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use axum::{middleware, Router};
+
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn access(toml: &str) -> Access {
+        Access::new(&toml::from_str(toml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn matches_ipv4_prefixes() {
+        let net = cidr("10.1.0.0/16");
+        assert!(net.contains("10.1.255.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        // Host bits in the network address are ignored.
+        assert!(cidr("192.168.1.77/24").contains("192.168.1.1".parse().unwrap()));
+        assert!(cidr("172.16.0.0/12").contains("172.31.0.1".parse().unwrap()));
+        assert!(!cidr("172.16.0.0/12").contains("172.32.0.1".parse().unwrap()));
+        assert!(cidr("0.0.0.0/0").contains("203.0.113.9".parse().unwrap()));
+        assert!(cidr("127.0.0.1").contains("127.0.0.1".parse().unwrap()));
+        assert!(!cidr("127.0.0.1").contains("127.0.0.2".parse().unwrap()));
+        // IPv4 clients on a dual-stack socket show up as mapped addresses.
+        assert!(net.contains("::ffff:10.1.0.9".parse().unwrap()));
+        assert!(!net.contains("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn matches_ipv6_prefixes() {
+        let net = cidr("fd00:ab::/32");
+        assert!(net.contains("fd00:ab:1::9".parse().unwrap()));
+        assert!(!net.contains("fd00:ac::1".parse().unwrap()));
+        assert!(cidr("2001:db8::/127").contains("2001:db8::1".parse().unwrap()));
+        assert!(!cidr("2001:db8::/127").contains("2001:db8::2".parse().unwrap()));
+        assert!(cidr("::/0").contains("2001:db8::2".parse().unwrap()));
+        assert!(cidr("::1").contains("::1".parse().unwrap()));
+        assert!(!cidr("::/0").contains("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        for bad in ["10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/x", "example.com/8", ""] {
+            assert!(bad.parse::<Cidr>().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn checks_credentials() {
+        let open = access("");
+        assert!(open.authorized(None));
+
+        let bearer = access("[auth]\nbearer_token = \"t0ken\"");
+        assert!(bearer.authorized(Some("Bearer t0ken")));
+        assert!(!bearer.authorized(Some("Bearer t0ke")));
+        assert!(!bearer.authorized(Some("Bearer ")));
+        assert!(!bearer.authorized(Some("t0ken")));
+        assert!(!bearer.authorized(None));
+
+        let basic = access("[auth]\nusername = \"prometheus\"\npassword = \"s3cret\"");
+        let header = |credentials: &str| format!("Basic {}", STANDARD.encode(credentials));
+        assert!(basic.authorized(Some(&header("prometheus:s3cret"))));
+        assert!(!basic.authorized(Some(&header("prometheus:wrong"))));
+        assert!(!basic.authorized(Some(&header("other:s3cret"))));
+        assert!(!basic.authorized(Some(&header("prometheus:s3cret:"))));
+        assert!(!basic.authorized(Some("Basic not-base64!")));
+        assert!(!basic.authorized(Some("Bearer s3cret")));
+        assert!(!basic.authorized(None));
+    }
+
+    /// Serve a guarded `/` as `server` configures and return its URL.
+    async fn serve(server: &str) -> String {
+        let access = Arc::new(access(server));
+        let app = Router::new().route("/", get(|| async { "ok" })).layer(middleware::from_fn_with_state(access, guard));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let app = app.into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    #[tokio::test]
+    async fn guards_requests() {
+        let client = reqwest::Client::new();
+        let basic = "allowed_cidrs = [\"127.0.0.0/8\"]\n[auth]\nusername = \"prometheus\"\npassword = \"s3cret\"";
+        let url = serve(basic).await;
+        let missing = client.get(&url).send().await.unwrap();
+        assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(missing.headers()[header::WWW_AUTHENTICATE], r#"Basic realm="metrics_exporter""#);
+        let wrong = client.get(&url).basic_auth("prometheus", Some("wrong")).send().await.unwrap();
+        assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+        let right = client.get(&url).basic_auth("prometheus", Some("s3cret")).send().await.unwrap();
+        assert_eq!(right.status(), StatusCode::OK);
+
+        let url = serve("allowed_cidrs = [\"10.0.0.0/8\", \"fd00::/8\"]\n[auth]\nbearer_token = \"t0ken\"").await;
+        let outside = client.get(&url).bearer_auth("t0ken").send().await.unwrap();
+        assert_eq!(outside.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,558 @@
+//! Typed view of `config.toml`, with environment variables as overrides.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::{env, fs, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::auth::Cidr;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub scrape: ScrapeConfig,
+    pub system: SystemConfig,
+    pub docker: DockerConfig,
+    pub push: PushConfig,
+    pub remote_write: RemoteWriteConfig,
+    pub alerts: AlertsConfig,
+    pub probes: ProbeConfig,
+    pub discovery: DiscoveryConfig,
+    pub history: HistoryConfig,
+    pub metrics: MetricsConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub port: u16,
+    /// Client networks allowed to connect, e.g. `10.0.0.0/8`; anyone if empty.
+    pub allowed_cidrs: Vec<String>,
+    pub auth: AuthConfig,
+    /// Serve HTTPS instead of plain HTTP.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self { port: 9300, allowed_cidrs: Vec::new(), auth: AuthConfig::default(), tls: None }
+    }
+}
+
+/// Credentials required on every request. With both a token and a
+/// username/password set, either is accepted; with neither, auth is off.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub bearer_token: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert_file: String,
+    /// PEM private key.
+    pub key_file: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScrapeConfig {
+    pub interval_secs: u64,
+    /// Per-scrape deadline; defaults to the interval, capped at 10 seconds.
+    pub timeout_secs: Option<u64>,
+    /// Upper bound on scrapes in flight at once.
+    pub max_concurrency: usize,
+    pub targets: Vec<TargetConfig>,
+}
+
+impl Default for ScrapeConfig {
+    fn default() -> Self {
+        Self { interval_secs: 15, timeout_secs: None, max_concurrency: 8, targets: Vec::new() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetConfig {
+    pub name: String,
+    pub url: String,
+    #[serde(rename = "type", default)]
+    pub kind: TargetKind,
+    /// Overrides `scrape.interval_secs` for this target.
+    pub interval_secs: Option<u64>,
+    /// Overrides `scrape.timeout_secs` for this target.
+    pub timeout_secs: Option<u64>,
+}
+
+/// How a target's response body is interpreted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetKind {
+    /// Prometheus text exposition format.
+    #[default]
+    Prometheus,
+    /// A flat JSON object; the keys listed in `metrics.custom_gauges` become gauges.
+    Custom,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SystemConfig {
+    pub enabled: bool,
+    pub collect_cpu: bool,
+    pub collect_ram: bool,
+    pub collect_disk: bool,
+    pub collect_network: bool,
+    /// Where the host's procfs is mounted, e.g. `/host/proc` inside a container.
+    pub proc_path: String,
+    /// Where the host's root filesystem is mounted, prefixed to mount points
+    /// before calling `statvfs`.
+    pub rootfs_path: String,
+}
+
+impl Default for SystemConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            collect_cpu: true,
+            collect_ram: true,
+            collect_disk: true,
+            collect_network: true,
+            proc_path: "/proc".into(),
+            rootfs_path: "/".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DockerConfig {
+    pub enabled: bool,
+    /// Path of the Docker Engine API unix socket.
+    pub socket: String,
+    /// Poll interval; defaults to `scrape.interval_secs`.
+    pub interval_secs: Option<u64>,
+}
+
+impl Default for DockerConfig {
+    fn default() -> Self {
+        Self { enabled: false, socket: "/var/run/docker.sock".into(), interval_secs: None }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PushConfig {
+    pub enabled: bool,
+    /// JSON file pushed groups are saved to and restored from.
+    pub persistence_file: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteWriteConfig {
+    pub enabled: bool,
+    pub url: String,
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+    /// Retries per batch before it is spooled to `queue_dir`.
+    pub max_retries: u32,
+    pub min_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Directory for batches that could not be delivered; unset drops them.
+    pub queue_dir: Option<String>,
+    /// Oldest batches are discarded beyond this many.
+    pub max_queue_files: usize,
+}
+
+impl Default for RemoteWriteConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: String::new(),
+            interval_secs: 30,
+            timeout_secs: 10,
+            max_retries: 3,
+            min_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            queue_dir: None,
+            max_queue_files: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertsConfig {
+    pub enabled: bool,
+    /// TOML file with `[[rules]]` entries.
+    pub rules_file: String,
+    /// Receives a JSON payload on every firing or resolved transition.
+    pub webhook: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProbeConfig {
+    pub interval_secs: u64,
+    /// Deadline for a whole probe, from DNS lookup to the last body byte.
+    pub timeout_secs: u64,
+    pub targets: Vec<ProbeTarget>,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self { interval_secs: 30, timeout_secs: 10, targets: Vec::new() }
+    }
+}
+
+/// A blackbox-style HTTP check; every condition set here must hold for success.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProbeTarget {
+    pub name: String,
+    pub url: String,
+    /// Accepted status codes; any 2xx when empty.
+    #[serde(default)]
+    pub valid_status_codes: Vec<u16>,
+    /// Regex the response body must match.
+    pub body_regex: Option<String>,
+    /// Fail the probe if it takes longer than this, even if it completed.
+    pub max_latency_ms: Option<u64>,
+    /// PEM bundle to trust instead of the public web roots.
+    pub ca_file: Option<String>,
+    /// Fail the probe if the body is larger than this; it is read into memory.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+fn default_max_body_bytes() -> usize {
+    1 << 20
+}
+
+/// Targets built from the app registry, plus how often watched files are checked.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    pub enabled: bool,
+    /// Directory of app registry YAML files.
+    pub app_registry_dir: String,
+    /// Which parts of each app to scrape: `backend` and/or `frontend`.
+    pub components: Vec<String>,
+    /// Target URL; `{name}`, `{component}`, `{port}` and `{domain}` are filled in per app.
+    pub url_template: String,
+    /// How often the config file and app registry are checked for changes.
+    pub watch_interval_secs: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            app_registry_dir: "compose/app-registry".into(),
+            components: vec!["backend".into()],
+            url_template: "http://{name}_{component}:{port}/metrics".into(),
+            watch_interval_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    pub enabled: bool,
+    /// How often the registry is sampled into history.
+    pub interval_secs: u64,
+    /// How far back history reaches; older points are dropped.
+    pub retention_secs: u64,
+    /// Series beyond this many are not recorded.
+    pub max_series: usize,
+    /// Append-only file that survives restarts; history is memory-only if unset.
+    pub file: Option<String>,
+    /// The file is compacted down to the retained points once it grows past
+    /// this, so it should leave plenty of room above what retention holds.
+    pub max_file_bytes: u64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 15,
+            retention_secs: 3600,
+            max_series: 10_000,
+            file: None,
+            max_file_bytes: 64 << 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub custom_gauges: Vec<String>,
+}
+
+impl TargetConfig {
+    pub fn interval(&self, scrape: &ScrapeConfig) -> Duration {
+        Duration::from_secs(self.interval_secs.unwrap_or(scrape.interval_secs))
+    }
+
+    pub fn timeout(&self, scrape: &ScrapeConfig) -> Duration {
+        let secs = self.timeout_secs.or(scrape.timeout_secs);
+        secs.map(Duration::from_secs).unwrap_or_else(|| self.interval(scrape).min(Duration::from_secs(10)))
+    }
+}
+
+impl Config {
+    /// Load the file named by `METRICS_CONFIG` (default `config.toml`), apply
+    /// env overrides and validate the result. A missing default file is not an
+    /// error; a missing file that was asked for explicitly is.
+    pub fn load() -> Result<Self> {
+        let mut config = match Self::file_path() {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The file `load` reads, if any.
+    pub fn file_path() -> Option<PathBuf> {
+        match env::var("METRICS_CONFIG") {
+            Ok(path) => Some(path.into()),
+            Err(_) => Some(PathBuf::from("config.toml")).filter(|p| p.exists()),
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        if let Ok(port) = env::var("EXPORTER_PORT") {
+            self.server.port = port.parse().with_context(|| format!("EXPORTER_PORT={:?}", port))?;
+        }
+        if let Ok(token) = env::var("EXPORTER_BEARER_TOKEN") {
+            self.server.auth.bearer_token = Some(token);
+        }
+        if let Ok(password) = env::var("EXPORTER_BASIC_PASSWORD") {
+            self.server.auth.password = Some(password);
+        }
+        if let Ok(secs) = env::var("SCRAPE_INTERVAL_SECS") {
+            self.scrape.interval_secs = secs.parse().with_context(|| format!("SCRAPE_INTERVAL_SECS={:?}", secs))?;
+        }
+        if let Ok(urls) = env::var("SCRAPE_URLS") {
+            self.scrape.targets = urls
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|url| TargetConfig {
+                    name: url.to_string(),
+                    url: url.to_string(),
+                    kind: TargetKind::Prometheus,
+                    interval_secs: None,
+                    timeout_secs: None,
+                })
+                .collect();
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        for cidr in &self.server.allowed_cidrs {
+            cidr.parse::<Cidr>().with_context(|| format!("server.allowed_cidrs: {:?}", cidr))?;
+        }
+        let auth = &self.server.auth;
+        if auth.bearer_token.as_deref() == Some("") {
+            bail!("server.auth.bearer_token must not be empty");
+        }
+        if auth.username.is_some() != auth.password.is_some() {
+            bail!("server.auth: username and password must be set together");
+        }
+        let open = auth.bearer_token.is_none() && auth.username.is_none() && self.server.allowed_cidrs.is_empty();
+        if self.push.enabled && open {
+            bail!("push.enabled needs server.auth or server.allowed_cidrs, or anyone can overwrite pushed series");
+        }
+        if self.scrape.interval_secs == 0 {
+            bail!("scrape.interval_secs must be greater than zero");
+        }
+        if self.scrape.max_concurrency == 0 {
+            bail!("scrape.max_concurrency must be greater than zero");
+        }
+        let mut names = HashSet::new();
+        for (i, target) in self.scrape.targets.iter().enumerate() {
+            let at = format!("scrape.targets[{}]", i);
+            if target.name.trim().is_empty() {
+                bail!("{}: name must not be empty", at);
+            }
+            if !names.insert(target.name.as_str()) {
+                bail!("{}: duplicate target name {:?}", at, target.name);
+            }
+            let url = reqwest::Url::parse(&target.url).with_context(|| format!("{} ({}): invalid url", at, target.name))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                bail!("{} ({}): url scheme must be http or https", at, target.name);
+            }
+            if target.interval_secs == Some(0) {
+                bail!("{} ({}): interval_secs must be greater than zero", at, target.name);
+            }
+            let timeout = target.timeout(&self.scrape);
+            if timeout.is_zero() || timeout > target.interval(&self.scrape) {
+                bail!("{} ({}): timeout must be between 1 second and the scrape interval", at, target.name);
+            }
+            if target.kind == TargetKind::Custom && self.metrics.custom_gauges.is_empty() {
+                bail!("{} ({}): custom targets need metrics.custom_gauges", at, target.name);
+            }
+        }
+        if self.docker.interval_secs == Some(0) {
+            bail!("docker.interval_secs must be greater than zero");
+        }
+        if self.remote_write.enabled {
+            let rw = &self.remote_write;
+            let url = reqwest::Url::parse(&rw.url).context("remote_write.url: invalid url")?;
+            if !matches!(url.scheme(), "http" | "https") {
+                bail!("remote_write.url: scheme must be http or https");
+            }
+            if rw.interval_secs == 0 || rw.timeout_secs == 0 {
+                bail!("remote_write: interval_secs and timeout_secs must be greater than zero");
+            }
+            if rw.min_backoff_ms > rw.max_backoff_ms {
+                bail!("remote_write: min_backoff_ms must not exceed max_backoff_ms");
+            }
+        }
+        if self.alerts.enabled && self.alerts.rules_file.is_empty() {
+            bail!("alerts.rules_file is required when alerts are enabled");
+        }
+        if self.probes.interval_secs == 0 || self.probes.timeout_secs == 0 {
+            bail!("probes: interval_secs and timeout_secs must be greater than zero");
+        }
+        let mut names = HashSet::new();
+        for (i, probe) in self.probes.targets.iter().enumerate() {
+            let at = format!("probes.targets[{}]", i);
+            if probe.name.trim().is_empty() {
+                bail!("{}: name must not be empty", at);
+            }
+            if !names.insert(probe.name.as_str()) {
+                bail!("{}: duplicate probe name {:?}", at, probe.name);
+            }
+            let url = reqwest::Url::parse(&probe.url).with_context(|| format!("{} ({}): invalid url", at, probe.name))?;
+            if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+                bail!("{} ({}): url must be http or https with a host", at, probe.name);
+            }
+            if let Some(re) = &probe.body_regex {
+                regex::Regex::new(re).with_context(|| format!("{} ({}): invalid body_regex", at, probe.name))?;
+            }
+        }
+        if self.discovery.watch_interval_secs == 0 {
+            bail!("discovery.watch_interval_secs must be greater than zero");
+        }
+        if self.discovery.enabled {
+            if let Some(c) = self.discovery.components.iter().find(|c| !matches!(c.as_str(), "backend" | "frontend")) {
+                bail!("discovery.components: unknown component {:?}, expected backend or frontend", c);
+            }
+        }
+        if self.history.enabled {
+            let h = &self.history;
+            if h.interval_secs == 0 || h.retention_secs < h.interval_secs {
+                bail!("history: interval_secs must be greater than zero and no more than retention_secs");
+            }
+        }
+        for gauge in &self.metrics.custom_gauges {
+            let mut chars = gauge.chars();
+            let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                bail!("metrics.custom_gauges: {:?} is not a valid metric name", gauge);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse and validate `text` as `load` does, without the environment.
+    fn parse(text: &str) -> Result<Config> {
+        let config: Config = toml::from_str(text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn error(text: &str) -> String {
+        format!("{:#}", parse(text).expect_err("config should be rejected"))
+    }
+
+    #[test]
+    fn accepts_the_shipped_config() {
+        let config = parse(include_str!("../config.toml")).unwrap();
+        assert!(!config.push.enabled);
+        assert_eq!(config.scrape.targets.len(), 2);
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(error("[server]\nprot = 9300\n").contains("unknown field `prot`"));
+        assert!(error("[scrape]\ntargets = [{ name = \"api\", url = \"http://api/\", intervall_secs = 5 }]\n")
+            .contains("unknown field `intervall_secs`"));
+        assert!(error("[pushh]\nenabled = true\n").contains("unknown field `pushh`"));
+    }
+
+    #[test]
+    fn rejects_duplicate_targets() {
+        let text = r#"
+            [scrape]
+            targets = [
+              { name = "api", url = "http://api:8000/metrics" },
+              { name = "api", url = "http://api:8001/metrics" },
+            ]
+        "#;
+        assert_eq!(error(text), "scrape.targets[1]: duplicate target name \"api\"");
+        let text = r#"
+            [[probes.targets]]
+            name = "web"
+            url = "https://example.com/"
+
+            [[probes.targets]]
+            name = "web"
+            url = "https://example.org/"
+        "#;
+        assert_eq!(error(text), "probes.targets[1]: duplicate probe name \"web\"");
+    }
+
+    #[test]
+    fn rejects_bad_intervals() {
+        assert_eq!(error("[scrape]\ninterval_secs = 0\n"), "scrape.interval_secs must be greater than zero");
+        let target = |fields: &str| {
+            format!("[scrape]\ninterval_secs = 15\ntargets = [{{ name = \"api\", url = \"http://api/\"{} }}]\n", fields)
+        };
+        let interval = "scrape.targets[0] (api): interval_secs must be greater than zero";
+        assert_eq!(error(&target(", interval_secs = 0")), interval);
+        let timeout = "scrape.targets[0] (api): timeout must be between 1 second and the scrape interval";
+        assert_eq!(error(&target(", interval_secs = 5, timeout_secs = 10")), timeout);
+        assert_eq!(error(&target(", timeout_secs = 0")), timeout);
+        assert!(parse(&target(", interval_secs = 5")).is_ok());
+        assert!(error("[history]\nenabled = true\ninterval_secs = 60\nretention_secs = 30\n").starts_with("history:"));
+        assert!(error("[scrape]\ninterval_secs = -1\n").contains("invalid value"));
+    }
+
+    #[test]
+    fn push_needs_auth_or_allowed_networks() {
+        let needs = "push.enabled needs server.auth or server.allowed_cidrs, or anyone can overwrite pushed series";
+        assert_eq!(error("[push]\nenabled = true\n"), needs);
+        assert!(parse("[push]\nenabled = true\n[server.auth]\nbearer_token = \"t0ken\"\n").is_ok());
+        assert!(parse("[push]\nenabled = true\n[server.auth]\nusername = \"p\"\npassword = \"w\"\n").is_ok());
+        assert!(parse("[push]\nenabled = true\n[server]\nallowed_cidrs = [\"10.0.0.0/8\"]\n").is_ok());
+        assert!(parse("").is_ok());
+    }
+}
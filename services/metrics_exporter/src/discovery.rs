@@ -0,0 +1,120 @@
+//! Scrape targets built from the app registry, the same
+//! `compose/app-registry/*.yaml` files nginx is generated from.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::config::{DiscoveryConfig, TargetConfig, TargetKind};
+
+#[derive(Deserialize)]
+struct App {
+    name: String,
+    frontend: Option<Endpoint>,
+    backend: Option<Endpoint>,
+}
+
+#[derive(Deserialize)]
+struct Endpoint {
+    port: u16,
+    #[serde(default)]
+    domain: String,
+}
+
+/// Registry files in name order.
+pub fn registry_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("reading {}", dir.display()))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "yaml" || ext == "yml"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// One target per app and configured component. Files that cannot be parsed
+/// are skipped so one bad entry does not take every app's target with it.
+pub fn discover(config: &DiscoveryConfig) -> Result<Vec<TargetConfig>> {
+    let mut targets = Vec::new();
+    for path in registry_files(Path::new(&config.app_registry_dir))? {
+        let app: App = match fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| Ok(serde_yaml::from_str(&text)?))
+        {
+            Ok(app) => app,
+            Err(e) => {
+                eprintln!("discovery: skipping {}: {:#}", path.display(), e);
+                continue;
+            }
+        };
+        for component in &config.components {
+            let endpoint = match component.as_str() {
+                "frontend" => app.frontend.as_ref(),
+                _ => app.backend.as_ref(),
+            };
+            let Some(endpoint) = endpoint else { continue };
+            let url = config
+                .url_template
+                .replace("{name}", &app.name)
+                .replace("{component}", component)
+                .replace("{port}", &endpoint.port.to_string())
+                .replace("{domain}", &endpoint.domain);
+            if let Err(e) = reqwest::Url::parse(&url) {
+                eprintln!("discovery: skipping {} {}: invalid url {:?}: {}", app.name, component, url, e);
+                continue;
+            }
+            targets.push(TargetConfig {
+                name: format!("{}_{}", app.name, component),
+                url,
+                kind: TargetKind::Prometheus,
+                interval_secs: None,
+                timeout_secs: None,
+            });
+        }
+    }
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_a_target_per_app_and_component() {
+        let dir = std::env::temp_dir().join(format!("metrics-exporter-registry-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let files = [
+            ("shop.yaml", "name: shop\nfrontend: { port: 3000, domain: shop.example.com }\nbackend: { port: 8000 }\n"),
+            ("blog.yml", "name: blog\nbackend: { port: 8001, domain: blog.example.com }\n"),
+            ("broken.yaml", "name: [unclosed\n"),
+            ("README.md", "name: readme\nbackend: { port: 1 }\n"),
+        ];
+        for (name, text) in files {
+            fs::write(dir.join(name), text).unwrap();
+        }
+        let config = DiscoveryConfig {
+            app_registry_dir: dir.display().to_string(),
+            components: vec!["backend".into(), "frontend".into()],
+            url_template: "http://{name}-{component}.internal:{port}/metrics?host={domain}".into(),
+            ..DiscoveryConfig::default()
+        };
+        let targets = discover(&config).unwrap();
+        let names: Vec<_> = registry_files(&dir).unwrap().iter().map(|p| p.file_name().unwrap().to_owned()).collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(names, ["blog.yml", "broken.yaml", "shop.yaml"]);
+        let targets: Vec<_> = targets.iter().map(|t| (t.name.as_str(), t.url.as_str())).collect();
+        assert_eq!(
+            targets,
+            [
+                ("blog_backend", "http://blog-backend.internal:8001/metrics?host=blog.example.com"),
+                ("shop_backend", "http://shop-backend.internal:8000/metrics?host="),
+                ("shop_frontend", "http://shop-frontend.internal:3000/metrics?host=shop.example.com"),
+            ]
+        );
+        assert!(discover(&config).is_err());
+    }
+}
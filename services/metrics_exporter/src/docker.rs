@@ -0,0 +1,341 @@
+//! Per-container metrics from the Docker Engine API, spoken over its unix socket.
+//!
+//! The API is async and a `Collector` is not, so a background task polls the
+//! engine once per interval and the collector serves the latest snapshot.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper::Request;
+use hyper_util::rt::TokioIo;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use prometheus::IntCounter;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tokio::net::UnixStream;
+
+use crate::exposition::{family, sample};
+
+const LABELS: &[&str] = &["container", "service"];
+
+/// `(name, help, kind)` of every family we export, in output order.
+const FAMILIES: &[(&str, &str, MetricType)] = &[
+    ("container_cpu_usage_seconds_total", "Cumulative CPU time consumed by the container", MetricType::COUNTER),
+    ("container_memory_usage_bytes", "Current memory usage of the container", MetricType::GAUGE),
+    ("container_memory_limit_bytes", "Memory limit of the container", MetricType::GAUGE),
+    ("container_network_receive_bytes_total", "Bytes received over all container networks", MetricType::COUNTER),
+    ("container_network_transmit_bytes_total", "Bytes sent over all container networks", MetricType::COUNTER),
+    ("container_restart_count", "Number of times the engine restarted the container", MetricType::GAUGE),
+    ("container_running", "1 if the container is running", MetricType::GAUGE),
+    ("container_health_status", "1 for the container's current health check status", MetricType::GAUGE),
+];
+
+/// Minimal HTTP/1.1 client for the engine's unix socket.
+#[derive(Clone)]
+pub struct DockerClient {
+    socket: PathBuf,
+    /// Limit on each request, so a hung engine cannot stall the poll loop.
+    timeout: Duration,
+}
+
+impl DockerClient {
+    pub fn new(socket: impl Into<PathBuf>, timeout: Duration) -> Self {
+        Self { socket: socket.into(), timeout }
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        match tokio::time::timeout(self.timeout, self.request(path)).await {
+            Ok(result) => result,
+            Err(_) => bail!("GET {}: timed out after {:?}", path, self.timeout),
+        }
+    }
+
+    async fn request<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let stream = UnixStream::connect(&self.socket)
+            .await
+            .with_context(|| format!("connecting to {}", self.socket.display()))?;
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(async move {
+            let _ = conn.await;
+        });
+        let req = Request::get(path).header(hyper::header::HOST, "docker").body(Empty::<Bytes>::new())?;
+        let resp = sender.send_request(req).await.with_context(|| format!("GET {}", path))?;
+        let status = resp.status();
+        let body = resp.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            bail!("GET {}: {} {}", path, status, String::from_utf8_lossy(&body).trim());
+        }
+        serde_json::from_slice(&body).with_context(|| format!("decoding GET {}", path))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerSummary {
+    id: String,
+    names: Vec<String>,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    state: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerInspect {
+    restart_count: u64,
+    state: InspectState,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InspectState {
+    health: Option<Health>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Health {
+    status: String,
+}
+
+#[derive(Deserialize, Default)]
+struct Stats {
+    #[serde(default)]
+    cpu_stats: CpuStats,
+    #[serde(default)]
+    memory_stats: MemoryStats,
+    #[serde(default)]
+    networks: HashMap<String, NetworkStats>,
+}
+
+#[derive(Deserialize, Default)]
+struct CpuStats {
+    #[serde(default)]
+    cpu_usage: CpuUsage,
+}
+
+#[derive(Deserialize, Default)]
+struct CpuUsage {
+    #[serde(default)]
+    total_usage: u64,
+}
+
+#[derive(Deserialize, Default)]
+struct MemoryStats {
+    #[serde(default)]
+    usage: u64,
+    #[serde(default)]
+    limit: u64,
+}
+
+#[derive(Deserialize)]
+struct NetworkStats {
+    rx_bytes: u64,
+    tx_bytes: u64,
+}
+
+#[derive(Clone)]
+pub struct DockerCollector {
+    snapshot: Arc<RwLock<Vec<MetricFamily>>>,
+    descs: Arc<Vec<Desc>>,
+    failures: IntCounter,
+}
+
+impl DockerCollector {
+    pub fn new() -> prometheus::Result<Self> {
+        let descs = FAMILIES
+            .iter()
+            .map(|(name, help, _)| {
+                let mut labels: Vec<String> = LABELS.iter().map(|l| l.to_string()).collect();
+                if *name == "container_health_status" {
+                    labels.push("status".into());
+                }
+                Desc::new(name.to_string(), help.to_string(), labels, HashMap::new())
+            })
+            .collect::<prometheus::Result<_>>()?;
+        let failures = IntCounter::new("docker_poll_failures_total", "Polls of the Docker engine that failed or timed out")?;
+        Ok(Self { snapshot: Arc::default(), descs: Arc::new(descs), failures })
+    }
+
+    /// Refresh the snapshot forever. A failed poll clears it so stale
+    /// containers do not linger.
+    pub async fn run(self, client: DockerClient, interval: Duration) {
+        loop {
+            match poll(&client).await {
+                Ok(families) => *self.snapshot.write().unwrap() = families,
+                Err(e) => {
+                    eprintln!("docker poll failed: {:#}", e);
+                    self.failures.inc();
+                    self.snapshot.write().unwrap().clear();
+                }
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+impl Collector for DockerCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().chain(self.failures.desc()).collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.snapshot.read().unwrap().clone();
+        families.extend(self.failures.collect());
+        families
+    }
+}
+
+async fn poll(client: &DockerClient) -> Result<Vec<MetricFamily>> {
+    let containers: Vec<ContainerSummary> = client.get("/containers/json?all=true").await?;
+    let details = futures::future::join_all(containers.iter().map(|c| container_metrics(client, c))).await;
+
+    let mut by_family: Vec<Vec<Metric>> = vec![Vec::new(); FAMILIES.len()];
+    for (c, metrics) in containers.iter().zip(details) {
+        // A container can vanish between the list and the detail calls.
+        let metrics = match metrics {
+            Ok(metrics) => metrics,
+            Err(e) => {
+                eprintln!("skipping container {}: {:#}", c.id, e);
+                continue;
+            }
+        };
+        for (name, metric) in metrics {
+            let i = FAMILIES.iter().position(|(n, _, _)| *n == name).expect("family listed in FAMILIES");
+            by_family[i].push(metric);
+        }
+    }
+    Ok(FAMILIES
+        .iter()
+        .zip(by_family)
+        .filter(|(_, metrics)| !metrics.is_empty())
+        .map(|((name, help, kind), metrics)| family(name, help, *kind, metrics))
+        .collect())
+}
+
+/// Samples for one container, tagged with their family name.
+async fn container_metrics(client: &DockerClient, c: &ContainerSummary) -> Result<Vec<(&'static str, Metric)>> {
+    let name = c.names.first().map(|n| n.trim_start_matches('/')).unwrap_or(&c.id);
+    let service = c.labels.get("com.docker.compose.service").map(String::as_str).unwrap_or("");
+    let labels = [("container", name), ("service", service)];
+    let running = c.state == "running";
+
+    let inspect: ContainerInspect = client.get(&format!("/containers/{}/json", c.id)).await?;
+    let health = inspect.state.health.map(|h| h.status).unwrap_or_else(|| "none".into());
+
+    let mut out = vec![
+        ("container_restart_count", sample(MetricType::GAUGE, &labels, inspect.restart_count as f64)),
+        ("container_running", sample(MetricType::GAUGE, &labels, if running { 1.0 } else { 0.0 })),
+        ("container_health_status", sample(MetricType::GAUGE, &[labels[0], labels[1], ("status", &health)], 1.0)),
+    ];
+    if running {
+        let stats: Stats = client
+            .get(&format!("/containers/{}/stats?stream=false&one-shot=true", c.id))
+            .await?;
+        let (rx, tx) = stats.networks.values().fold((0, 0), |(rx, tx), n| (rx + n.rx_bytes, tx + n.tx_bytes));
+        out.extend([
+            ("container_cpu_usage_seconds_total", sample(MetricType::COUNTER, &labels, stats.cpu_stats.cpu_usage.total_usage as f64 / 1e9)),
+            ("container_memory_usage_bytes", sample(MetricType::GAUGE, &labels, stats.memory_stats.usage as f64)),
+            ("container_memory_limit_bytes", sample(MetricType::GAUGE, &labels, stats.memory_stats.limit as f64)),
+            ("container_network_receive_bytes_total", sample(MetricType::COUNTER, &labels, rx as f64)),
+            ("container_network_transmit_bytes_total", sample(MetricType::COUNTER, &labels, tx as f64)),
+        ]);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http_body_util::Full;
+    use hyper::body::Incoming;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use tokio::net::UnixListener;
+
+    use super::*;
+
+    /// Serve `respond(path and query)` on a fresh unix socket, like the
+    /// engine would. `None` never answers.
+    fn mock_engine(name: &str, respond: fn(&str) -> Option<&'static str>) -> PathBuf {
+        let socket = std::env::temp_dir().join(format!("metrics_exporter_{}_{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let service = service_fn(move |req: Request<Incoming>| async move {
+                    let path = req.uri().path_and_query().map_or("", |p| p.as_str()).to_string();
+                    match respond(&path) {
+                        Some(body) => Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(body)))),
+                        None => std::future::pending().await,
+                    }
+                });
+                tokio::spawn(hyper::server::conn::http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        socket
+    }
+
+    fn value(families: &[MetricFamily], name: &str) -> f64 {
+        let family = families.iter().find(|f| f.get_name() == name).unwrap_or_else(|| panic!("no {}", name));
+        let metric = &family.get_metric()[0];
+        match family.get_field_type() {
+            MetricType::COUNTER => metric.get_counter().get_value(),
+            _ => metric.get_gauge().get_value(),
+        }
+    }
+
+    #[tokio::test]
+    async fn polls_containers_from_the_engine() {
+        let socket = mock_engine("poll", |path| match path {
+            "/containers/json?all=true" => Some(
+                r#"[{"Id":"abc","Names":["/stack-api-1"],"Labels":{"com.docker.compose.service":"api"},"State":"running"}]"#,
+            ),
+            "/containers/abc/json" => Some(r#"{"RestartCount":2,"State":{"Health":{"Status":"healthy"}}}"#),
+            "/containers/abc/stats?stream=false&one-shot=true" => Some(
+                r#"{"cpu_stats":{"cpu_usage":{"total_usage":2500000000}},
+                    "memory_stats":{"usage":100,"limit":200},
+                    "networks":{"eth0":{"rx_bytes":10,"tx_bytes":20},"eth1":{"rx_bytes":1,"tx_bytes":2}}}"#,
+            ),
+            _ => None,
+        });
+        let client = DockerClient::new(&socket, Duration::from_secs(5));
+        let families = poll(&client).await.unwrap();
+
+        assert_eq!(value(&families, "container_cpu_usage_seconds_total"), 2.5);
+        assert_eq!(value(&families, "container_memory_usage_bytes"), 100.0);
+        assert_eq!(value(&families, "container_memory_limit_bytes"), 200.0);
+        assert_eq!(value(&families, "container_network_receive_bytes_total"), 11.0);
+        assert_eq!(value(&families, "container_network_transmit_bytes_total"), 22.0);
+        assert_eq!(value(&families, "container_restart_count"), 2.0);
+        assert_eq!(value(&families, "container_running"), 1.0);
+        let health = families.iter().find(|f| f.get_name() == "container_health_status").unwrap();
+        let labels: Vec<(&str, &str)> =
+            health.get_metric()[0].get_label().iter().map(|l| (l.get_name(), l.get_value())).collect();
+        assert!(labels.contains(&("container", "stack-api-1")));
+        assert!(labels.contains(&("service", "api")));
+        assert!(labels.contains(&("status", "healthy")));
+    }
+
+    #[tokio::test]
+    async fn hung_engine_times_out_and_counts_a_failure() {
+        let socket = mock_engine("hung", |_| None);
+        let client = DockerClient::new(&socket, Duration::from_millis(100));
+        let error = poll(&client).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("timed out"), "{:#}", error);
+
+        let collector = DockerCollector::new().unwrap();
+        tokio::spawn(collector.clone().run(client, Duration::from_millis(10)));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(collector.failures.get() >= 1);
+        let families = collector.collect();
+        assert_eq!(families.len(), 1, "only the failure counter after a failed poll");
+    }
+}
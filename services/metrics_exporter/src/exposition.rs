@@ -0,0 +1,754 @@
+//! Parser for the Prometheus text exposition format (version 0.0.4), plus
+//! the OpenMetrics and JSON encoders the `/metrics` endpoints offer.
+//!
+//! The output is the same `MetricFamily` protobuf model the `prometheus` crate
+//! gathers, so parsed series can be merged straight into our own registry.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use prometheus::proto::{
+    Bucket, Counter, Gauge, Histogram, LabelPair, Metric, MetricFamily, MetricType, Quantile,
+    Summary,
+};
+use serde::Serialize;
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub type Labels = Vec<(String, String)>;
+
+struct Sample {
+    name: String,
+    labels: Labels,
+    value: f64,
+    timestamp: Option<i64>,
+}
+
+struct Family {
+    name: String,
+    help: String,
+    kind: MetricType,
+    samples: Vec<Sample>,
+}
+
+/// Parse a full exposition body into metric families, in order of first appearance.
+///
+/// Untyped series are reported as gauges because the text encoder cannot
+/// write the untyped kind back out.
+pub fn parse(text: &str) -> Result<Vec<MetricFamily>, ParseError> {
+    let mut families: Vec<Family> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+
+    for (n, raw) in text.lines().enumerate() {
+        let line = raw.trim();
+        let err = |msg: &str| ParseError { line: n + 1, msg: msg.to_string() };
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut parts = comment.trim_start().splitn(3, ' ');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("HELP"), Some(name), help) => {
+                    let i = family_index(&mut families, &mut index, name);
+                    families[i].help = unescape(help.unwrap_or(""));
+                }
+                (Some("TYPE"), Some(name), Some(kind)) => {
+                    let kind = match kind.trim() {
+                        "counter" => MetricType::COUNTER,
+                        "gauge" => MetricType::GAUGE,
+                        "histogram" => MetricType::HISTOGRAM,
+                        "summary" => MetricType::SUMMARY,
+                        "untyped" => MetricType::UNTYPED,
+                        other => return Err(err(&format!("unknown metric type {:?}", other))),
+                    };
+                    let i = family_index(&mut families, &mut index, name);
+                    if !families[i].samples.is_empty() {
+                        return Err(err(&format!("TYPE for {} after its samples", name)));
+                    }
+                    families[i].kind = kind;
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        let sample = parse_sample(line).map_err(|msg| err(&msg))?;
+        let i = match index.get(&sample.name) {
+            Some(&i) => i,
+            None => {
+                let parent = ["_bucket", "_sum", "_count"].iter().find_map(|suffix| {
+                    let base = sample.name.strip_suffix(suffix)?;
+                    let &i = index.get(base)?;
+                    match (families[i].kind, *suffix) {
+                        (MetricType::HISTOGRAM, _) => Some(i),
+                        (MetricType::SUMMARY, "_sum" | "_count") => Some(i),
+                        _ => None,
+                    }
+                });
+                match parent {
+                    Some(i) => i,
+                    None => family_index(&mut families, &mut index, &sample.name),
+                }
+            }
+        };
+        families[i].samples.push(sample);
+    }
+
+    Ok(families
+        .into_iter()
+        .filter(|f| !f.samples.is_empty())
+        .map(build_family)
+        .collect())
+}
+
+fn family_index(families: &mut Vec<Family>, index: &mut HashMap<String, usize>, name: &str) -> usize {
+    *index.entry(name.to_string()).or_insert_with(|| {
+        families.push(Family {
+            name: name.to_string(),
+            help: String::new(),
+            kind: MetricType::UNTYPED,
+            samples: Vec::new(),
+        });
+        families.len() - 1
+    })
+}
+
+fn parse_sample(line: &str) -> Result<Sample, String> {
+    let name_end = line
+        .find(|c: char| c == '{' || c.is_whitespace())
+        .ok_or("missing sample value")?;
+    let name = &line[..name_end];
+    if !valid_name(name) {
+        return Err(format!("invalid metric name {:?}", name));
+    }
+    let mut rest = &line[name_end..];
+    let mut labels = Vec::new();
+    if let Some(body) = rest.strip_prefix('{') {
+        let (parsed, after) = parse_labels(body)?;
+        labels = parsed;
+        rest = after;
+    }
+
+    let mut fields = rest.split_whitespace();
+    let value = fields.next().ok_or("missing sample value")?;
+    let value: f64 = value.parse().map_err(|_| format!("invalid sample value {:?}", value))?;
+    let timestamp = match fields.next() {
+        Some(ts) => Some(ts.parse().map_err(|_| format!("invalid timestamp {:?}", ts))?),
+        None => None,
+    };
+    if fields.next().is_some() {
+        return Err("trailing data after timestamp".into());
+    }
+    Ok(Sample { name: name.to_string(), labels, value, timestamp })
+}
+
+/// Parse `name="value",...}` and return the labels plus the remainder after `}`.
+fn parse_labels(mut s: &str) -> Result<(Labels, &str), String> {
+    let mut labels = Vec::new();
+    loop {
+        s = s.trim_start();
+        if let Some(after) = s.strip_prefix('}') {
+            return Ok((labels, after));
+        }
+        let eq = s.find('=').ok_or("label without value")?;
+        let name = s[..eq].trim();
+        if !valid_name(name) || name.contains(':') {
+            return Err(format!("invalid label name {:?}", name));
+        }
+        s = s[eq + 1..].trim_start().strip_prefix('"').ok_or("label value must be quoted")?;
+
+        let mut value = String::new();
+        let mut chars = s.char_indices();
+        let end = loop {
+            match chars.next() {
+                Some((i, '"')) => break i,
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, c)) => value.push(c),
+                    None => return Err("unterminated label value".into()),
+                },
+                Some((_, c)) => value.push(c),
+                None => return Err("unterminated label value".into()),
+            }
+        };
+        labels.push((name.to_string(), value));
+
+        s = s[end + 1..].trim_start();
+        s = s.strip_prefix(',').unwrap_or(s);
+        if s.is_empty() {
+            return Err("unterminated label set".into());
+        }
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                out.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                out.push('\\');
+                chars.next();
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Label pairs sorted by name, the order the `prometheus` crate itself emits.
+pub fn label_pairs<K: AsRef<str>, V: AsRef<str>>(labels: &[(K, V)]) -> Vec<LabelPair> {
+    let mut pairs: Vec<LabelPair> = labels
+        .iter()
+        .map(|(k, v)| {
+            let mut lp = LabelPair::default();
+            lp.set_name(k.as_ref().to_string());
+            lp.set_value(v.as_ref().to_string());
+            lp
+        })
+        .collect();
+    pairs.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+    pairs
+}
+
+/// A single counter or gauge sample, for collectors that build series by hand.
+pub fn sample(kind: MetricType, labels: &[(&str, &str)], value: f64) -> Metric {
+    let mut m = Metric::default();
+    if kind == MetricType::COUNTER {
+        let mut c = Counter::default();
+        c.set_value(value);
+        m.set_counter(c);
+    } else {
+        let mut g = Gauge::default();
+        g.set_value(value);
+        m.set_gauge(g);
+    }
+    m.set_label(label_pairs(labels).into());
+    m
+}
+
+pub fn family(name: &str, help: &str, kind: MetricType, metrics: Vec<Metric>) -> MetricFamily {
+    let mut mf = MetricFamily::default();
+    mf.set_name(name.to_string());
+    mf.set_help(help.to_string());
+    mf.set_field_type(kind);
+    mf.set_metric(metrics.into());
+    mf
+}
+
+fn build_family(family: Family) -> MetricFamily {
+    let mut mf = MetricFamily::default();
+    mf.set_name(family.name.clone());
+    mf.set_help(family.help);
+
+    let metrics = match family.kind {
+        MetricType::HISTOGRAM => grouped(&family.name, family.samples, "le", |group| {
+            let mut h = Histogram::default();
+            let mut inf_count = None;
+            for (suffix, extra, value) in group {
+                match (suffix, extra) {
+                    ("_bucket", Some(le)) if le.parse::<f64>().is_ok_and(f64::is_infinite) => {
+                        inf_count = Some(value as u64)
+                    }
+                    ("_bucket", Some(le)) => {
+                        let mut b = Bucket::default();
+                        b.set_upper_bound(le.parse().unwrap_or(f64::NAN));
+                        b.set_cumulative_count(value as u64);
+                        h.mut_bucket().push(b);
+                    }
+                    ("_sum", _) => h.set_sample_sum(value),
+                    ("_count", _) => h.set_sample_count(value as u64),
+                    _ => {}
+                }
+            }
+            if h.get_sample_count() == 0 {
+                h.set_sample_count(inf_count.unwrap_or(0));
+            }
+            let mut m = Metric::default();
+            m.set_histogram(h);
+            m
+        }),
+        MetricType::SUMMARY => grouped(&family.name, family.samples, "quantile", |group| {
+            let mut s = Summary::default();
+            for (suffix, extra, value) in group {
+                match (suffix, extra) {
+                    ("", Some(q)) => {
+                        let mut quantile = Quantile::default();
+                        quantile.set_quantile(q.parse().unwrap_or(f64::NAN));
+                        quantile.set_value(value);
+                        s.mut_quantile().push(quantile);
+                    }
+                    ("_sum", _) => s.set_sample_sum(value),
+                    ("_count", _) => s.set_sample_count(value as u64),
+                    _ => {}
+                }
+            }
+            let mut m = Metric::default();
+            m.set_summary(s);
+            m
+        }),
+        kind => family
+            .samples
+            .into_iter()
+            .map(|sample| {
+                let mut m = Metric::default();
+                if kind == MetricType::COUNTER {
+                    let mut c = Counter::default();
+                    c.set_value(sample.value);
+                    m.set_counter(c);
+                } else {
+                    let mut g = Gauge::default();
+                    g.set_value(sample.value);
+                    m.set_gauge(g);
+                }
+                m.set_label(label_pairs(&sample.labels).into());
+                if let Some(ts) = sample.timestamp {
+                    m.set_timestamp_ms(ts);
+                }
+                m
+            })
+            .collect(),
+    };
+
+    mf.set_field_type(match family.kind {
+        MetricType::UNTYPED => MetricType::GAUGE,
+        kind => kind,
+    });
+    mf.set_metric(metrics.into());
+    mf
+}
+
+/// One histogram/summary sample: name suffix, `le`/`quantile` value, sample value.
+type Part<'a> = (&'a str, Option<String>, f64);
+
+/// Group histogram or summary samples by their label set, ignoring the
+/// per-sample `le`/`quantile` label, and build one metric per group.
+fn grouped<F>(name: &str, samples: Vec<Sample>, special: &str, build: F) -> Vec<Metric>
+where
+    F: Fn(Vec<Part>) -> Metric,
+{
+    let mut groups: Vec<(Labels, Option<i64>, Vec<Part>)> = Vec::new();
+    for sample in &samples {
+        let mut key = Vec::new();
+        let mut extra = None;
+        for (k, v) in &sample.labels {
+            if k == special {
+                extra = Some(v.clone());
+            } else {
+                key.push((k.clone(), v.clone()));
+            }
+        }
+        key.sort();
+        let suffix = sample.name.strip_prefix(name).unwrap_or("");
+        let part = (suffix, extra, sample.value);
+        match groups.iter_mut().find(|(k, _, _)| *k == key) {
+            Some(group) => group.2.push(part),
+            None => groups.push((key, sample.timestamp, vec![part])),
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(key, timestamp, parts)| {
+            let mut m = build(parts);
+            m.set_label(label_pairs(&key).into());
+            if let Some(ts) = timestamp {
+                m.set_timestamp_ms(ts);
+            }
+            m
+        })
+        .collect()
+}
+
+/// One series value, as it would appear on a line of text output.
+pub struct FlatSample {
+    pub name: String,
+    /// Sorted by name; does not include `__name__`.
+    pub labels: Labels,
+    pub value: f64,
+    pub timestamp_ms: Option<i64>,
+}
+
+/// Flatten families into individual series, expanding histograms and
+/// summaries into `_bucket`/`_sum`/`_count` the way the text format does.
+pub fn flatten(families: &[MetricFamily]) -> Vec<FlatSample> {
+    let mut out = Vec::new();
+    for mf in families {
+        let name = mf.get_name();
+        for m in mf.get_metric() {
+            let labels: Labels = m
+                .get_label()
+                .iter()
+                .map(|lp| (lp.get_name().to_string(), lp.get_value().to_string()))
+                .collect();
+            let timestamp_ms = Some(m.get_timestamp_ms()).filter(|&ts| ts != 0);
+            let mut push = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                let mut labels = labels.clone();
+                if let Some((k, v)) = extra {
+                    labels.push((k.to_string(), v));
+                    labels.sort();
+                }
+                out.push(FlatSample { name: format!("{}{}", name, suffix), labels, value, timestamp_ms });
+            };
+            match mf.get_field_type() {
+                MetricType::COUNTER => push("", None, m.get_counter().get_value()),
+                MetricType::GAUGE => push("", None, m.get_gauge().get_value()),
+                MetricType::UNTYPED => push("", None, m.get_untyped().get_value()),
+                MetricType::HISTOGRAM => {
+                    let h = m.get_histogram();
+                    let mut inf_seen = false;
+                    for b in h.get_bucket() {
+                        let le = b.get_upper_bound();
+                        inf_seen |= le == f64::INFINITY;
+                        let le = if le == f64::INFINITY { "+Inf".to_string() } else { le.to_string() };
+                        push("_bucket", Some(("le", le)), b.get_cumulative_count() as f64);
+                    }
+                    if !inf_seen {
+                        push("_bucket", Some(("le", "+Inf".into())), h.get_sample_count() as f64);
+                    }
+                    push("_sum", None, h.get_sample_sum());
+                    push("_count", None, h.get_sample_count() as f64);
+                }
+                MetricType::SUMMARY => {
+                    let s = m.get_summary();
+                    for q in s.get_quantile() {
+                        push("", Some(("quantile", q.get_quantile().to_string())), q.get_value());
+                    }
+                    push("_sum", None, s.get_sample_sum());
+                    push("_count", None, s.get_sample_count() as f64);
+                }
+            }
+        }
+    }
+    out
+}
+
+pub const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Whether an `Accept` header prefers OpenMetrics over the classic text format.
+pub fn wants_openmetrics(accept: &str) -> bool {
+    let (mut openmetrics, mut text) = (0.0, 0.0);
+    for part in accept.split(',') {
+        let mut params = part.split(';').map(str::trim);
+        let media = params.next().unwrap_or("");
+        let q: f64 = params.find_map(|p| p.strip_prefix("q=")).and_then(|q| q.parse().ok()).unwrap_or(1.0);
+        match media {
+            "application/openmetrics-text" => openmetrics = f64::max(openmetrics, q),
+            "text/plain" | "text/*" | "*/*" => text = f64::max(text, q),
+            _ => {}
+        }
+    }
+    openmetrics > 0.0 && openmetrics >= text
+}
+
+/// Encode families in the OpenMetrics 1.0 text format.
+///
+/// Counter families are announced without their `_total` suffix and every
+/// counter sample carries it, as the spec requires. Timestamps are seconds,
+/// and HELP is escaped like a label value.
+pub fn encode_openmetrics(families: &[MetricFamily]) -> String {
+    let mut out = String::new();
+    for mf in families {
+        let name = mf.get_name();
+        let (kind, base) = match mf.get_field_type() {
+            MetricType::COUNTER => ("counter", name.strip_suffix("_total").unwrap_or(name)),
+            MetricType::GAUGE => ("gauge", name),
+            MetricType::HISTOGRAM => ("histogram", name),
+            MetricType::SUMMARY => ("summary", name),
+            MetricType::UNTYPED => ("unknown", name),
+        };
+        out.push_str(&format!("# TYPE {} {}\n", base, kind));
+        if !mf.get_help().is_empty() {
+            out.push_str(&format!("# HELP {} {}\n", base, escape_label(mf.get_help())));
+        }
+        for s in flatten(std::slice::from_ref(mf)) {
+            if mf.get_field_type() == MetricType::COUNTER {
+                out.push_str(base);
+                out.push_str("_total");
+            } else {
+                out.push_str(&s.name);
+            }
+            if !s.labels.is_empty() {
+                let labels: Vec<String> = s.labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v))).collect();
+                out.push_str(&format!("{{{}}}", labels.join(",")));
+            }
+            out.push_str(&format!(" {}", format_value(s.value)));
+            if let Some(ts) = s.timestamp_ms {
+                out.push_str(&format!(" {}", ts as f64 / 1000.0));
+            }
+            out.push('\n');
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// A sample value as the text formats and the Prometheus HTTP API write it.
+pub fn format_value(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".into()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".into()
+    } else {
+        value.to_string()
+    }
+}
+
+#[derive(Serialize)]
+pub struct JsonFamily {
+    pub name: String,
+    pub help: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub samples: Vec<JsonSample>,
+}
+
+#[derive(Serialize)]
+pub struct JsonSample {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    /// `null` for NaN and infinities, which JSON cannot represent.
+    pub value: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp_ms: Option<i64>,
+}
+
+/// Families as JSON for the platform UI, one flattened sample per series.
+pub fn to_json(families: &[MetricFamily]) -> Vec<JsonFamily> {
+    families
+        .iter()
+        .map(|mf| JsonFamily {
+            name: mf.get_name().to_string(),
+            help: mf.get_help().to_string(),
+            kind: match mf.get_field_type() {
+                MetricType::COUNTER => "counter",
+                MetricType::GAUGE => "gauge",
+                MetricType::HISTOGRAM => "histogram",
+                MetricType::SUMMARY => "summary",
+                MetricType::UNTYPED => "untyped",
+            },
+            samples: flatten(std::slice::from_ref(mf))
+                .into_iter()
+                .map(|s| JsonSample {
+                    name: s.name,
+                    labels: s.labels.into_iter().collect(),
+                    value: Some(s.value).filter(|v| v.is_finite()),
+                    timestamp_ms: s.timestamp_ms,
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(m: &Metric) -> Vec<(&str, &str)> {
+        m.get_label().iter().map(|lp| (lp.get_name(), lp.get_value())).collect()
+    }
+
+    fn error(text: &str) -> String {
+        parse(text).expect_err("parse should fail").to_string()
+    }
+
+    #[test]
+    fn parses_escaped_label_values() {
+        let families =
+            parse("files{path=\"C:\\\\data\",msg=\"say \\\"hi\\\"\\nbye\",empty=\"\"} 3 1700000000000\n").unwrap();
+        let m = &families[0].get_metric()[0];
+        assert_eq!(labels(m), [("empty", ""), ("msg", "say \"hi\"\nbye"), ("path", "C:\\data")]);
+        assert_eq!(m.get_gauge().get_value(), 3.0);
+        assert_eq!(m.get_timestamp_ms(), 1_700_000_000_000);
+    }
+
+    #[test]
+    fn takes_help_and_type_before_samples_only() {
+        let text = "# HELP jobs_total Jobs run,\\nby queue.\n# TYPE jobs_total counter\njobs_total{queue=\"a\"} 7\n\
+                    # HELP temp Temperature\ntemp 21.5\n";
+        let families = parse(text).unwrap();
+        assert_eq!(families[0].get_name(), "jobs_total");
+        assert_eq!(families[0].get_help(), "Jobs run,\nby queue.");
+        assert_eq!(families[0].get_field_type(), MetricType::COUNTER);
+        assert_eq!(families[0].get_metric()[0].get_counter().get_value(), 7.0);
+        // Untyped series come out as gauges.
+        assert_eq!(families[1].get_field_type(), MetricType::GAUGE);
+        assert_eq!(families[1].get_help(), "Temperature");
+
+        // TYPE may follow HELP, but not the family's samples.
+        let families = parse("# TYPE up gauge\n# HELP up Up.\nup 1\n").unwrap();
+        assert_eq!((families[0].get_field_type(), families[0].get_help()), (MetricType::GAUGE, "Up."));
+        assert_eq!(error("up 1\n# TYPE up gauge\n"), "line 2: TYPE for up after its samples");
+        // Comments and families without samples are skipped.
+        assert!(parse("# just a comment\n# TYPE idle gauge\n\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn groups_histograms_and_summaries() {
+        let text = "# TYPE latency histogram\n\
+                    latency_bucket{path=\"/\",le=\"0.1\"} 2\n\
+                    latency_bucket{path=\"/\",le=\"1\"} 5\n\
+                    latency_bucket{path=\"/\",le=\"+Inf\"} 6\n\
+                    latency_sum{path=\"/\"} 3.5\n\
+                    latency_count{path=\"/\"} 6\n\
+                    latency_bucket{path=\"/a\",le=\"+Inf\"} 1\n\
+                    latency_sum{path=\"/a\"} 0.2\n\
+                    # TYPE rpc summary\n\
+                    rpc{quantile=\"0.5\"} 0.01\n\
+                    rpc{quantile=\"0.99\"} 0.3\n\
+                    rpc_sum 12\n\
+                    rpc_count 400\n";
+        let families = parse(text).unwrap();
+        assert_eq!(families.len(), 2);
+        let latency = &families[0];
+        assert_eq!(latency.get_field_type(), MetricType::HISTOGRAM);
+        assert_eq!(latency.get_metric().len(), 2);
+        let root = &latency.get_metric()[0];
+        assert_eq!(labels(root), [("path", "/")]);
+        let h = root.get_histogram();
+        let buckets: Vec<_> = h.get_bucket().iter().map(|b| (b.get_upper_bound(), b.get_cumulative_count())).collect();
+        assert_eq!(buckets, [(0.1, 2), (1.0, 5)]);
+        assert_eq!((h.get_sample_sum(), h.get_sample_count()), (3.5, 6));
+        // Without _count, the +Inf bucket gives the count.
+        assert_eq!(latency.get_metric()[1].get_histogram().get_sample_count(), 1);
+
+        let rpc = families[1].get_metric()[0].get_summary();
+        let quantiles: Vec<_> = rpc.get_quantile().iter().map(|q| (q.get_quantile(), q.get_value())).collect();
+        assert_eq!(quantiles, [(0.5, 0.01), (0.99, 0.3)]);
+        assert_eq!((rpc.get_sample_sum(), rpc.get_sample_count()), (12.0, 400));
+
+        // And back out the way the text format writes them.
+        let flat: Vec<_> = flatten(&families[..1])
+            .into_iter()
+            .filter(|s| s.labels.contains(&("path".into(), "/".into())))
+            .map(|s| (s.name, s.labels.iter().find(|(k, _)| k == "le").map(|(_, v)| v.clone()), s.value))
+            .collect();
+        let le = |v: &str| Some(v.to_string());
+        assert_eq!(
+            flat,
+            [
+                ("latency_bucket".into(), le("0.1"), 2.0),
+                ("latency_bucket".into(), le("1"), 5.0),
+                ("latency_bucket".into(), le("+Inf"), 6.0),
+                ("latency_sum".into(), None, 3.5),
+                ("latency_count".into(), None, 6.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn suffixed_series_of_other_types_are_families_of_their_own() {
+        let families = parse("# TYPE rpc summary\nrpc_bucket 1\n# TYPE hits counter\nhits_count 2\n").unwrap();
+        let names: Vec<_> = families.iter().map(|f| f.get_name()).collect();
+        assert_eq!(names, ["rpc_bucket", "hits_count"]);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(error("ok 1\n1up 1\n"), "line 2: invalid metric name \"1up\"");
+        assert_eq!(error("up\n"), "line 1: missing sample value");
+        assert_eq!(error("up one\n"), "line 1: invalid sample value \"one\"");
+        assert_eq!(error("up 1 soon\n"), "line 1: invalid timestamp \"soon\"");
+        assert_eq!(error("up 1 2 3\n"), "line 1: trailing data after timestamp");
+        assert_eq!(error("up{job=\"a} 1\n"), "line 1: unterminated label value");
+        assert_eq!(error("up{job=a} 1\n"), "line 1: label value must be quoted");
+        assert_eq!(error("up{job} 1\n"), "line 1: label without value");
+        assert_eq!(error("up{a:b=\"x\"} 1\n"), "line 1: invalid label name \"a:b\"");
+        assert_eq!(error("up{job=\"a\",\n"), "line 1: unterminated label set");
+        assert_eq!(error("# TYPE up enum\n"), "line 1: unknown metric type \"enum\"");
+        assert!(parse("up NaN\nup{a=\"b\"} +Inf\n").is_ok());
+    }
+
+    #[test]
+    fn negotiates_openmetrics_by_quality() {
+        assert!(wants_openmetrics("application/openmetrics-text; version=1.0.0; charset=utf-8"));
+        // What Prometheus itself sends.
+        assert!(wants_openmetrics(concat!(
+            "application/openmetrics-text;version=1.0.0;q=0.5,application/openmetrics-text;version=0.0.1;q=0.4,",
+            "text/plain;version=0.0.4;q=0.3,*/*;q=0.1",
+        )));
+        assert!(wants_openmetrics("text/plain;q=0.5, application/openmetrics-text"));
+        assert!(!wants_openmetrics(""));
+        assert!(!wants_openmetrics("*/*"));
+        assert!(!wants_openmetrics("text/plain, application/openmetrics-text;q=0.9"));
+        assert!(!wants_openmetrics("application/openmetrics-text;q=0"));
+        assert!(!wants_openmetrics("application/json"));
+    }
+
+    #[test]
+    fn encodes_openmetrics() {
+        let mut families = parse(
+            "# HELP jobs_total Jobs \"run\".\n# TYPE jobs_total counter\njobs_total{queue=\"a\\\\b\"} 3 1700000000123\n\
+             # TYPE restarts counter\nrestarts 1\n\
+             # TYPE latency histogram\nlatency_bucket{le=\"1\"} 2\nlatency_bucket{le=\"+Inf\"} 3\nlatency_sum 1.5\n\
+             temp -Inf\n",
+        )
+        .unwrap();
+        let mut odd = Metric::default();
+        odd.mut_untyped().set_value(1.0);
+        families.push(family("odd", "", MetricType::UNTYPED, vec![odd]));
+        assert_eq!(
+            encode_openmetrics(&families),
+            "# TYPE jobs counter\n\
+             # HELP jobs Jobs \\\"run\\\".\n\
+             jobs_total{queue=\"a\\\\b\"} 3 1700000000.123\n\
+             # TYPE restarts counter\n\
+             restarts_total 1\n\
+             # TYPE latency histogram\n\
+             latency_bucket{le=\"1\"} 2\n\
+             latency_bucket{le=\"+Inf\"} 3\n\
+             latency_sum 1.5\n\
+             latency_count 3\n\
+             # TYPE temp gauge\n\
+             temp -Inf\n\
+             # TYPE odd unknown\n\
+             odd 1\n\
+             # EOF\n"
+        );
+        assert_eq!(encode_openmetrics(&[]), "# EOF\n");
+    }
+
+    #[test]
+    fn converts_to_json() {
+        let text = "# HELP temp Temperature\ntemp{room=\"a\"} NaN 1700000000000\ntemp{room=\"b\"} 21.5\n";
+        let families = parse(text).unwrap();
+        assert_eq!(
+            serde_json::to_value(to_json(&families)).unwrap(),
+            serde_json::json!([{
+                "name": "temp",
+                "help": "Temperature",
+                "type": "gauge",
+                "samples": [
+                    {"name": "temp", "labels": {"room": "a"}, "value": null, "timestamp_ms": 1700000000000i64},
+                    {"name": "temp", "labels": {"room": "b"}, "value": 21.5},
+                ],
+            }])
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! Re-exposes the series scraped from each target on our own `/metrics`.
+
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use prometheus::proto::{LabelPair, MetricFamily};
+use prometheus::Registry;
+
+/// Latest scrape result per target. Each scrape replaces the target's series
+/// wholesale, so series that vanish from a target vanish from our output too.
+#[derive(Clone, Default)]
+pub struct Federation {
+    targets: Arc<RwLock<BTreeMap<String, Vec<MetricFamily>>>>,
+    /// Names our own collectors have used. Federated families by these names
+    /// are renamed `exported_<name>`, and stay renamed should ours go quiet.
+    reserved: Arc<RwLock<HashSet<String>>>,
+}
+
+impl Federation {
+    /// Store a fresh scrape, adding a `target` label to every series. A
+    /// `target` label already set by the app is kept as `exported_target`.
+    /// Families typed differently by another target are dropped, as one name
+    /// can only have one type.
+    pub fn update(&self, target: &str, mut families: Vec<MetricFamily>) {
+        for mf in &mut families {
+            for m in mf.mut_metric().iter_mut() {
+                let mut labels: Vec<LabelPair> = m.take_label().into();
+                for lp in labels.iter_mut().filter(|lp| lp.get_name() == "target") {
+                    lp.set_name("exported_target".into());
+                }
+                let mut lp = LabelPair::default();
+                lp.set_name("target".into());
+                lp.set_value(target.to_string());
+                labels.push(lp);
+                labels.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+                m.set_label(labels.into());
+            }
+        }
+        let mut targets = self.targets.write().unwrap();
+        families.retain(|mf| {
+            let others = targets.iter().filter(|(name, _)| *name != target);
+            let clash = others.flat_map(|(name, families)| families.iter().map(move |other| (name, other))).find(
+                |(_, other)| other.get_name() == mf.get_name() && other.get_field_type() != mf.get_field_type(),
+            );
+            match clash {
+                Some((other, _)) => {
+                    eprintln!("{}: dropping {}, typed differently by {}", target, mf.get_name(), other);
+                    false
+                }
+                None => true,
+            }
+        });
+        targets.insert(target.to_string(), families);
+    }
+
+    /// Drop everything known about a target, e.g. after a failed scrape.
+    pub fn remove(&self, target: &str) {
+        self.targets.write().unwrap().remove(target);
+    }
+
+    /// Gather our own series from `registry` and add every target's. Families
+    /// several targets expose are merged, and one named like one of ours is
+    /// renamed rather than mixed into it.
+    pub fn gather(&self, registry: &Registry) -> Vec<MetricFamily> {
+        let mut families = registry.gather();
+        let mut reserved = self.reserved.write().unwrap();
+        for mf in &families {
+            if !reserved.contains(mf.get_name()) {
+                reserved.insert(mf.get_name().to_string());
+            }
+        }
+        let mut federated: BTreeMap<String, MetricFamily> = BTreeMap::new();
+        for mf in self.targets.read().unwrap().values().flatten() {
+            let mut mf = mf.clone();
+            if reserved.contains(mf.get_name()) {
+                mf.set_name(format!("exported_{}", mf.get_name()));
+            }
+            match federated.entry(mf.get_name().to_string()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(mf);
+                }
+                Entry::Occupied(mut entry) => entry.get_mut().mut_metric().extend(mf.take_metric()),
+            }
+        }
+        families.extend(federated.into_values());
+        families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        families
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{IntGauge, IntGaugeVec, Opts};
+
+    use super::*;
+    use crate::exposition;
+
+    fn series(families: &[MetricFamily]) -> Vec<String> {
+        exposition::flatten(families)
+            .into_iter()
+            .map(|s| {
+                let labels: Vec<_> = s.labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                format!("{}{{{}}} {}", s.name, labels.join(","), s.value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn labels_series_with_their_target() {
+        let federation = Federation::default();
+        federation.update("api", exposition::parse("requests{target=\"db\",code=\"200\"} 5\n").unwrap());
+        assert_eq!(
+            series(&federation.gather(&Registry::new())),
+            ["requests{code=200,exported_target=db,target=api} 5"]
+        );
+        federation.remove("api");
+        assert!(federation.gather(&Registry::new()).is_empty());
+    }
+
+    #[test]
+    fn merges_targets_and_renames_our_own_names() {
+        let registry = Registry::new();
+        let up = IntGaugeVec::new(Opts::new("up", "Ours"), &["target"]).unwrap();
+        up.with_label_values(&["api"]).set(1);
+        registry.register(Box::new(up.clone())).unwrap();
+        let federation = Federation::default();
+        federation.update("api", exposition::parse("# TYPE up counter\nup 3\nqueue_depth 2\n").unwrap());
+        federation.update("web", exposition::parse("queue_depth 4\n").unwrap());
+
+        let families = federation.gather(&registry);
+        let names: Vec<_> = families.iter().map(|f| f.get_name()).collect();
+        assert_eq!(names, ["exported_up", "queue_depth", "up"]);
+        assert_eq!(
+            series(&families),
+            ["exported_up{target=api} 3", "queue_depth{target=api} 2", "queue_depth{target=web} 4", "up{target=api} 1"]
+        );
+
+        // Once ours, always ours: the rename holds while our series are gone.
+        up.reset();
+        let names: Vec<_> = federation.gather(&registry).iter().map(|f| f.get_name().to_string()).collect();
+        assert_eq!(names, ["exported_up", "queue_depth"]);
+    }
+
+    #[test]
+    fn drops_families_typed_differently_by_another_target() {
+        let registry = Registry::new();
+        registry.register(Box::new(IntGauge::new("own", "Ours").unwrap())).unwrap();
+        let federation = Federation::default();
+        federation.update("api", exposition::parse("# TYPE jobs counter\njobs 1\n").unwrap());
+        federation.update("web", exposition::parse("# TYPE jobs gauge\njobs 2\nother 1\n").unwrap());
+        assert_eq!(series(&federation.gather(&registry)), ["jobs{target=api} 1", "other{target=web} 1", "own{} 0"]);
+    }
+}
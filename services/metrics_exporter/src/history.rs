@@ -0,0 +1,460 @@
+//! Recent history of every series in the registry, so the platform UI can
+//! draw sparklines without a separate TSDB.
+//!
+//! The registry is sampled on a fixed interval into a bounded ring buffer per
+//! series. With `file` set, every sample batch is also appended to a JSON-lines
+//! file that is replayed on startup and compacted once it grows too large;
+//! both happen on a blocking thread, away from the handlers.
+//! `/api/v1/query_range` answers in the shape of the Prometheus HTTP API.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::DateTime;
+use prometheus::Registry;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::config::HistoryConfig;
+use crate::exposition::{self, Labels};
+use crate::federation::Federation;
+use crate::query::{parse_duration, Selector};
+use crate::AppState;
+
+/// How far back a step looks for the latest point, as in Prometheus.
+const LOOKBACK_MS: i64 = 5 * 60 * 1000;
+
+/// Most points a single query may return per series.
+const MAX_POINTS: i64 = 11_000;
+
+/// Query times beyond the year 9999, either way, are refused.
+const MAX_TIME_SECS: f64 = 253_402_300_799.0;
+
+/// Points of one series, oldest first, as `(unix ms, value)`.
+type Points = VecDeque<(i64, f64)>;
+
+/// One line of the history file.
+#[derive(Serialize, Deserialize)]
+struct Batch {
+    t: i64,
+    /// `(name, labels, value)`; non-finite values are written as `null`.
+    samples: Vec<(String, Labels, Option<f64>)>,
+}
+
+#[derive(Clone)]
+pub struct History {
+    /// Series by metric name, then by label set.
+    series: Arc<RwLock<HashMap<String, HashMap<Labels, Points>>>>,
+    config: HistoryConfig,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(config: HistoryConfig) -> Self {
+        let capacity = (config.retention_secs / config.interval_secs.max(1)) as usize + 1;
+        Self { series: Arc::default(), config, capacity }
+    }
+
+    fn retention_ms(&self) -> i64 {
+        self.config.retention_secs as i64 * 1000
+    }
+
+    /// Replay the history file, then rewrite it without expired points.
+    pub fn restore(&self) -> Result<()> {
+        let Some(path) = &self.config.file else { return Ok(()) };
+        if !Path::new(path).exists() {
+            return Ok(());
+        }
+        let file = File::open(path).with_context(|| format!("opening {}", path))?;
+        let cutoff = now_ms() - self.retention_ms();
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.with_context(|| format!("reading {}", path))?;
+            // The last line may be torn if we were killed mid-write.
+            let batch: Batch = match serde_json::from_str(&line) {
+                Ok(batch) => batch,
+                Err(e) => {
+                    eprintln!("history: ignoring {} line {}: {}", path, n + 1, e);
+                    continue;
+                }
+            };
+            if batch.t >= cutoff {
+                let samples = batch.samples.into_iter().map(|(name, labels, v)| (name, labels, v.unwrap_or(f64::NAN)));
+                self.insert(batch.t, samples);
+            }
+        }
+        self.compact()
+    }
+
+    /// Sample the registry and federated series forever.
+    pub async fn run(self, registry: Registry, federation: Federation) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(self.config.interval_secs));
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut warned = false;
+        loop {
+            ticker.tick().await;
+            let t = now_ms();
+            let samples = exposition::flatten(&federation.gather(&registry));
+            self.expire(t);
+            let dropped = self.insert(t, samples.iter().map(|s| (s.name.clone(), s.labels.clone(), s.value)));
+            if dropped > 0 && !warned {
+                eprintln!("history: max_series {} reached, new series are not recorded", self.config.max_series);
+                warned = true;
+            }
+            if self.config.file.is_none() {
+                continue;
+            }
+            // Batches are written off the runtime, and one at a time, so the
+            // file keeps them in order.
+            let history = self.clone();
+            let batch = samples.into_iter().map(|s| (s.name, s.labels, s.value)).collect();
+            match tokio::task::spawn_blocking(move || history.append(t, batch)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => eprintln!("history: failed to write history file: {:#}", e),
+                Err(e) => eprintln!("history: history file writer failed: {}", e),
+            }
+        }
+    }
+
+    /// Record one point per sample. Returns how many new series were refused
+    /// because of `max_series`.
+    fn insert(&self, t: i64, samples: impl Iterator<Item = (String, Labels, f64)>) -> usize {
+        let mut series = self.series.write().unwrap();
+        let mut count: usize = series.values().map(HashMap::len).sum();
+        let mut dropped = 0;
+        for (name, labels, value) in samples {
+            let by_labels = series.entry(name).or_default();
+            if !by_labels.contains_key(&labels) {
+                if count >= self.config.max_series {
+                    dropped += 1;
+                    continue;
+                }
+                count += 1;
+            }
+            let points = by_labels.entry(labels).or_default();
+            points.push_back((t, value));
+            while points.len() > self.capacity {
+                points.pop_front();
+            }
+        }
+        series.retain(|_, by_labels| !by_labels.is_empty());
+        dropped
+    }
+
+    /// Drop points older than the retention, and series left without any.
+    fn expire(&self, now: i64) {
+        let cutoff = now - self.retention_ms();
+        let mut series = self.series.write().unwrap();
+        for by_labels in series.values_mut() {
+            for points in by_labels.values_mut() {
+                while points.front().is_some_and(|(t, _)| *t < cutoff) {
+                    points.pop_front();
+                }
+            }
+            by_labels.retain(|_, points| !points.is_empty());
+        }
+        series.retain(|_, by_labels| !by_labels.is_empty());
+    }
+
+    fn append(&self, t: i64, samples: Vec<(String, Labels, f64)>) -> Result<()> {
+        let Some(path) = &self.config.file else { return Ok(()) };
+        let samples = samples.into_iter().map(|(name, labels, v)| (name, labels, Some(v).filter(|v| v.is_finite())));
+        let mut line = serde_json::to_vec(&Batch { t, samples: samples.collect() })?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(&line)?;
+        if file.metadata()?.len() > self.config.max_file_bytes {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the history file from memory, one line per sample time.
+    fn compact(&self) -> Result<()> {
+        let Some(path) = &self.config.file else { return Ok(()) };
+        let mut batches: BTreeMap<i64, Vec<(String, Labels, Option<f64>)>> = BTreeMap::new();
+        for (name, by_labels) in self.series.read().unwrap().iter() {
+            for (labels, points) in by_labels {
+                for &(t, v) in points {
+                    batches.entry(t).or_default().push((name.clone(), labels.clone(), Some(v).filter(|v| v.is_finite())));
+                }
+            }
+        }
+        let tmp = Path::new(path).with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp).with_context(|| format!("creating {}", tmp.display()))?);
+        for (t, samples) in batches {
+            serde_json::to_writer(&mut out, &Batch { t, samples })?;
+            out.write_all(b"\n")?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp, path).with_context(|| format!("replacing {}", path))?;
+        Ok(())
+    }
+
+    /// Evaluate `selector` at every step between `start` and `end`, in unix ms.
+    fn query_range(&self, selector: &Selector, start: i64, end: i64, step: i64) -> Vec<RangeSeries> {
+        let series = self.series.read().unwrap();
+        let Some(by_labels) = series.get(&selector.name) else { return Vec::new() };
+        let mut result: Vec<RangeSeries> = by_labels
+            .iter()
+            .filter(|(labels, _)| selector.matches(&selector.name, labels))
+            .filter_map(|(labels, points)| {
+                let mut values = Vec::new();
+                let mut next = 0;
+                let mut t = start;
+                while t <= end {
+                    while next < points.len() && points[next].0 <= t {
+                        next += 1;
+                    }
+                    if let Some(&(at, v)) = next.checked_sub(1).map(|i| &points[i]) {
+                        if t - at <= LOOKBACK_MS {
+                            values.push((t as f64 / 1000.0, exposition::format_value(v)));
+                        }
+                    }
+                    match t.checked_add(step) {
+                        Some(later) => t = later,
+                        None => break,
+                    }
+                }
+                if values.is_empty() {
+                    return None;
+                }
+                let mut metric: BTreeMap<String, String> = labels.iter().cloned().collect();
+                metric.insert("__name__".into(), selector.name.clone());
+                Some(RangeSeries { metric, values })
+            })
+            .collect();
+        result.sort_by(|a, b| a.metric.cmp(&b.metric));
+        result
+    }
+}
+
+#[derive(Serialize)]
+struct RangeSeries {
+    metric: BTreeMap<String, String>,
+    /// `[unix seconds, value]` pairs; values are strings as in the Prometheus API.
+    values: Vec<(f64, String)>,
+}
+
+#[derive(Deserialize)]
+pub struct RangeQuery {
+    query: String,
+    start: String,
+    end: String,
+    step: String,
+}
+
+/// `GET /api/v1/query_range?query=<selector>&start=&end=&step=`.
+///
+/// `start` and `end` are unix seconds or RFC 3339; `step` is seconds or a
+/// duration such as `30s`.
+pub async fn query_range(State(state): State<Arc<AppState>>, Query(q): Query<RangeQuery>) -> Response {
+    let parsed = (|| -> Result<_> {
+        let selector = Selector::parse(q.query.trim()).context("invalid query")?;
+        let start = parse_time(&q.start).context("invalid start")?;
+        let end = parse_time(&q.end).context("invalid end")?;
+        let step = parse_step(&q.step).context("invalid step")?;
+        if end < start {
+            bail!("end must not be before start");
+        }
+        let points = end.checked_sub(start).and_then(|span| span.checked_div(step));
+        if !matches!(points, Some(points) if points <= MAX_POINTS) {
+            bail!("more than {} points per series; increase step", MAX_POINTS);
+        }
+        Ok((selector, start, end, step))
+    })();
+    match parsed {
+        Ok((selector, start, end, step)) => {
+            let result = state.history.query_range(&selector, start, end, step);
+            Json(json!({ "status": "success", "data": { "resultType": "matrix", "result": result } })).into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "errorType": "bad_data", "error": format!("{:#}", e) })),
+        )
+            .into_response(),
+    }
+}
+
+/// Unix seconds (possibly fractional) or RFC 3339, as unix ms.
+fn parse_time(s: &str) -> Result<i64> {
+    if let Ok(secs) = s.parse::<f64>() {
+        if !secs.is_finite() || secs.abs() > MAX_TIME_SECS {
+            bail!("time out of range");
+        }
+        return Ok((secs * 1000.0) as i64);
+    }
+    Ok(DateTime::parse_from_rfc3339(s)?.timestamp_millis())
+}
+
+/// Seconds (possibly fractional) or a duration, as ms.
+fn parse_step(s: &str) -> Result<i64> {
+    let ms = match s.parse::<f64>() {
+        Ok(secs) if !secs.is_finite() => bail!("step must be finite"),
+        Ok(secs) => (secs * 1000.0) as i64,
+        Err(_) => i64::try_from(parse_duration(s)?.as_millis()).context("step is too long")?,
+    };
+    if ms <= 0 {
+        bail!("step must be positive");
+    }
+    Ok(ms)
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    /// Unix ms of a point in the tests, well clear of zero.
+    const T: i64 = 1_700_000_000_000;
+
+    fn history(retention_secs: u64, interval_secs: u64, max_series: usize) -> History {
+        History::new(HistoryConfig { retention_secs, interval_secs, max_series, ..HistoryConfig::default() })
+    }
+
+    fn sample(name: &str, job: &str, value: f64) -> (String, Labels, f64) {
+        (name.into(), vec![("job".into(), job.into())], value)
+    }
+
+    fn points(history: &History, name: &str, job: &str) -> Vec<(i64, f64)> {
+        let series = history.series.read().unwrap();
+        let labels: Labels = vec![("job".into(), job.into())];
+        series.get(name).and_then(|s| s.get(&labels)).map(|p| p.iter().copied().collect()).unwrap_or_default()
+    }
+
+    fn range(start: &str, end: &str, step: &str) -> RangeQuery {
+        RangeQuery { query: "up".into(), start: start.into(), end: end.into(), step: step.into() }
+    }
+
+    #[tokio::test]
+    async fn rejects_steps_that_overflow() {
+        assert_eq!(parse_step("15s").unwrap(), 15_000);
+        assert_eq!(parse_step("0.5").unwrap(), 500);
+        assert!(parse_step("999999999999999999d").is_err());
+        assert!(parse_step("999999999999999999s").is_err());
+        assert!(parse_step("0").is_err());
+
+        let state = crate::tests::state(Config::default());
+        for step in ["999999999999999999d", "999999999999999999s", "inf"] {
+            let response = query_range(State(state.clone()), Query(range("0", "60", step))).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{:?}", step);
+        }
+        let response = query_range(State(state), Query(range("0", "60", "15s"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn keeps_a_bounded_ring_per_series() {
+        // Four intervals of retention, so five points.
+        let history = history(60, 15, 2);
+        for i in 0..7 {
+            assert_eq!(history.insert(T + i * 15_000, [sample("up", "a", i as f64)].into_iter()), 0);
+        }
+        let kept: Vec<f64> = points(&history, "up", "a").into_iter().map(|(_, v)| v).collect();
+        assert_eq!(kept, [2.0, 3.0, 4.0, 5.0, 6.0]);
+
+        // New series beyond max_series are refused, known ones still recorded.
+        let batch = [sample("up", "b", 1.0), sample("up", "c", 1.0), sample("up", "a", 7.0)];
+        assert_eq!(history.insert(T + 7 * 15_000, batch.into_iter()), 1);
+        assert_eq!(points(&history, "up", "b").len(), 1);
+        assert!(points(&history, "up", "c").is_empty());
+
+        // a's latest point and b's only one are within a minute of this.
+        history.expire(T + 7 * 15_000 + 60_000);
+        assert_eq!(points(&history, "up", "a"), [(T + 7 * 15_000, 7.0)]);
+        history.expire(T + 7 * 15_000 + 60_001);
+        assert!(history.series.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn queries_a_window_by_step() {
+        let history = history(3600, 15, 100);
+        for (i, value) in [1.0, 2.0, f64::INFINITY].into_iter().enumerate() {
+            let t = T + i as i64 * 15_000;
+            history.insert(t, [sample("up", "a", value), sample("other", "a", 0.0)].into_iter());
+        }
+        history.insert(T + 5_000, [sample("up", "b", 9.0)].into_iter());
+
+        let secs = |ms: i64| ms as f64 / 1000.0;
+        let up = Selector::parse("up").unwrap();
+        let result = history.query_range(&up, T - 15_000, T + 60_000, 15_000);
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].metric.get("__name__").map(String::as_str), Some("up"));
+        assert_eq!(result[0].metric.get("job").map(String::as_str), Some("a"));
+        // Nothing before the first point; then each step takes the latest one.
+        let expected: Vec<(f64, String)> =
+            [(15_000, "1"), (30_000, "2"), (45_000, "+Inf"), (60_000, "+Inf"), (75_000, "+Inf")]
+            .into_iter()
+            .map(|(offset, v)| (secs(T - 15_000 + offset), v.to_string()))
+            .collect();
+        assert_eq!(result[0].values[..], expected[..]);
+        assert_eq!(result[1].values[0], (secs(T + 15_000), "9".to_string()));
+
+        // Points go stale after the lookback.
+        let late = T + 30_000 + LOOKBACK_MS;
+        let result = history.query_range(&Selector::parse("up{job=\"a\"}").unwrap(), late, late + 1, 1);
+        assert_eq!(result[0].values, [(secs(late), "+Inf".to_string())]);
+        assert!(history.query_range(&up, late + 1, late + 1, 1).iter().all(|s| s.metric["job"] == "a"));
+        assert!(history.query_range(&up, late + 2, late + 100, 1).is_empty());
+        assert!(history.query_range(&Selector::parse("missing").unwrap(), T, T + 60_000, 15_000).is_empty());
+    }
+
+    #[tokio::test]
+    async fn restores_from_a_compacted_file() {
+        let path = std::env::temp_dir().join(format!("metrics-exporter-history-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let config = HistoryConfig {
+            retention_secs: 45,
+            file: Some(path.display().to_string()),
+            // Compact on every write.
+            max_file_bytes: 1,
+            ..HistoryConfig::default()
+        };
+        let history = History::new(config.clone());
+        let now = now_ms();
+        for i in (0..4).rev() {
+            let t = now - i * 30_000;
+            history.expire(t);
+            let batch = vec![sample("up", "a", i as f64), sample("nan", "a", f64::NAN)];
+            history.insert(t, batch.clone().into_iter());
+            history.append(t, batch).unwrap();
+        }
+        // Only what retention still holds is left, a line per sample time.
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+        let restored = History::new(config);
+        restored.restore().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(points(&restored, "up", "a"), points(&history, "up", "a"));
+        assert_eq!(points(&restored, "up", "a").len(), 2);
+        assert!(points(&restored, "nan", "a").iter().all(|(_, v)| v.is_nan()));
+    }
+
+    #[tokio::test]
+    async fn validates_range_queries() {
+        let state = crate::tests::state(Config::default());
+        let status = |start: &str, end: &str, step: &str| {
+            let state = state.clone();
+            let q = range(start, end, step);
+            async move { query_range(State(state), Query(q)).await.status() }
+        };
+        assert_eq!(status("2023-11-14T22:13:20Z", "2023-11-14T23:13:20+00:00", "1m").await, StatusCode::OK);
+        assert_eq!(status("60", "0", "15").await, StatusCode::BAD_REQUEST);
+        assert_eq!(status("0", "86400", "1").await, StatusCode::BAD_REQUEST);
+        assert_eq!(status("yesterday", "60", "15").await, StatusCode::BAD_REQUEST);
+        assert_eq!(status("0", "1e300", "15").await, StatusCode::BAD_REQUEST);
+        assert_eq!(status("0", "60", "-15").await, StatusCode::BAD_REQUEST);
+    }
+}
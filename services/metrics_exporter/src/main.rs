@@ -1,68 +1,185 @@
-use axum::{Router, routing::get, response::IntoResponse, extract::State};
-use prometheus::{Encoder, TextEncoder, Registry, IntCounterVec};
-use tokio::{time, sync::Mutex};
-use std::{env, net::SocketAddr, sync::Arc, time::Duration};
-use reqwest::Client;
+use axum::{Json, Router, middleware, routing::{get, put}, response::{IntoResponse, Response}, extract::{Query, State}};
+use axum::http::{header, HeaderMap, StatusCode};
+use prometheus::{proto::MetricFamily, TextEncoder, Registry};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use anyhow::{Context, Result};
 
-#[derive(Clone)]
+mod alerts;
+mod auth;
+mod config;
+mod discovery;
+mod docker;
+mod exposition;
+mod federation;
+mod history;
+mod probe;
+mod push;
+mod query;
+mod remote_write;
+mod scrape;
+mod system;
+mod targets;
+mod tls;
+
+use alerts::AlertEngine;
+use auth::Access;
+use config::Config;
+use docker::{DockerClient, DockerCollector};
+use federation::Federation;
+use history::History;
+use probe::ProbeMetrics;
+use push::PushStore;
+use remote_write::RemoteWriter;
+use scrape::ScrapeMetrics;
+use system::SystemCollector;
+use targets::Scheduler;
+
+/// Shared, read-only handles. Everything mutable lives behind the registry's
+/// own locks, so `/metrics` never waits on a scrape in flight.
 struct AppState {
     registry: Registry,
-    requests: IntCounterVec,
-    targets: Vec<String>,
-    interval: Duration,
+    scrape: ScrapeMetrics,
+    federation: Federation,
+    push: PushStore,
+    history: History,
+    config: Config,
 }
 
-async fn metrics(State(state): axum::extract::State<Arc<Mutex<AppState>>>) -> impl IntoResponse {
-    let state = state.lock().await;
-    let mut buffer = Vec::new();
-    let encoder = TextEncoder::new();
-    let metric_families = state.registry.gather();
-    encoder.encode(&metric_families, &mut buffer).unwrap();
-    String::from_utf8(buffer).unwrap()
+/// Query parameters kept as pairs so `name[]` can repeat.
+type Params = Vec<(String, String)>;
+
+/// Gather our own and federated series, keeping only the families named by
+/// `name[]=` if any are given.
+fn gather(state: &AppState, params: &Params) -> Vec<MetricFamily> {
+    let names: Vec<&str> = params.iter().filter(|(k, _)| k == "name[]").map(|(_, v)| v.as_str()).collect();
+    let mut families = state.federation.gather(&state.registry);
+    if !names.is_empty() {
+        families.retain(|mf| names.contains(&mf.get_name()));
+    }
+    families
 }
 
-async fn scrape_loop(state: Arc<Mutex<AppState>>) {
-    let client = Client::new();
-    loop {
-        {
-            let s = state.lock().await;
-            for target in &s.targets {
-                if let Ok(resp) = client.get(target).send().await {
-                    let label = target.clone();
-                    s.requests.with_label_values(&[&label]).inc_by(1);
-                    drop(resp);
-                }
-            }
+/// `GET /metrics`: the text format, or OpenMetrics when the `Accept` header prefers it.
+async fn metrics(State(state): State<Arc<AppState>>, headers: HeaderMap, Query(params): Query<Params>) -> Response {
+    let families = gather(&state, &params);
+    let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()).unwrap_or("");
+    if exposition::wants_openmetrics(accept) {
+        return ([(header::CONTENT_TYPE, exposition::OPENMETRICS_CONTENT_TYPE)], exposition::encode_openmetrics(&families))
+            .into_response();
+    }
+    match TextEncoder::new().encode_to_string(&families) {
+        Ok(body) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response(),
+        Err(e) => {
+            eprintln!("failed to encode metrics: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("failed to encode metrics: {}\n", e)).into_response()
         }
-        time::sleep(state.lock().await.interval).await;
     }
 }
 
+/// `GET /metrics.json`: the same series as JSON for the platform UI.
+async fn metrics_json(State(state): State<Arc<AppState>>, Query(params): Query<Params>) -> impl IntoResponse {
+    Json(exposition::to_json(&gather(&state, &params)))
+}
+
 #[tokio::main]
-async fn main() {
-    let port = env::var("EXPORTER_PORT").unwrap_or_else(|_| "9300".into()).parse().unwrap();
-    let interval = env::var("SCRAPE_INTERVAL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(15);
-    let targets_env = env::var("SCRAPE_URLS").unwrap_or_default();
-    let targets: Vec<String> = targets_env.split(',').filter(|s| !s.is_empty()).map(|s| s.to_string()).collect();
+async fn main() -> Result<()> {
+    let config = Config::load().context("loading configuration")?;
 
     let registry = Registry::new();
-    let requests = IntCounterVec::new(prometheus::Opts::new("scrape_requests_total", "Number of scrapes"), &["target"]).unwrap();
-    registry.register(Box::new(requests.clone())).unwrap();
+    let scrape = ScrapeMetrics::register(&registry)?;
+    let federation = Federation::default();
+    if !config.probes.targets.is_empty() {
+        let probes = ProbeMetrics::register(&registry)?;
+        probe::spawn(&config.probes, &probes)?;
+    }
+    if config.system.enabled {
+        registry.register(Box::new(SystemCollector::new(config.system.clone())?))?;
+    }
+    if config.docker.enabled {
+        let collector = DockerCollector::new()?;
+        registry.register(Box::new(collector.clone()))?;
+        let interval = Duration::from_secs(config.docker.interval_secs.unwrap_or(config.scrape.interval_secs));
+        tokio::spawn(collector.run(DockerClient::new(&config.docker.socket, interval), interval));
+    }
+
+    let push = PushStore::new(config.push.persistence_file.as_ref().map(Into::into))
+        .context("loading pushed metrics")?;
+    if config.push.enabled {
+        registry.register(Box::new(push.clone()))?;
+    }
+
+    if config.remote_write.enabled {
+        let writer = RemoteWriter::new(config.remote_write.clone(), registry.clone(), federation.clone())?;
+        tokio::spawn(writer.run());
+    }
+
+    if config.alerts.enabled {
+        let engine = AlertEngine::load(&config.alerts.rules_file, registry.clone(), federation.clone(), config.alerts.webhook.clone())
+            .context("loading alert rules")?;
+        tokio::spawn(engine.run(Duration::from_secs(config.scrape.interval_secs)));
+    }
 
-    let state = Arc::new(Mutex::new(AppState {
+    let history = History::new(config.history.clone());
+    if config.history.enabled {
+        history.restore().context("restoring metric history")?;
+        tokio::spawn(history.clone().run(registry.clone(), federation.clone()));
+    }
+
+    let port = config.server.port;
+    let state = Arc::new(AppState {
         registry,
-        requests,
-        targets,
-        interval: Duration::from_secs(interval as u64),
-    }));
+        scrape,
+        federation,
+        push,
+        history,
+        config,
+    });
 
-    let scrape_state = state.clone();
-    tokio::spawn(scrape_loop(scrape_state));
+    let mut scheduler = Scheduler::new(state.clone());
+    let initial = targets::desired(&state.config).context("discovering scrape targets")?;
+    scheduler.apply(&state.config, initial).await;
+    tokio::spawn(scheduler.watch());
 
-    let app = Router::new().route("/metrics", get(metrics)).with_state(state);
+    let mut app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/metrics.json", get(metrics_json));
+    if state.config.push.enabled {
+        app = app.route(
+            "/metrics/job/*group",
+            put(push::put_group).post(push::post_group).delete(push::delete_group),
+        );
+    }
+    if state.config.history.enabled {
+        app = app.route("/api/v1/query_range", get(history::query_range));
+    }
+    let access = Arc::new(Access::new(&state.config.server)?);
+    let tls = state.config.server.tls.as_ref().map(tls::acceptor).transpose()?;
+    let app = app.layer(middleware::from_fn_with_state(access, auth::guard)).with_state(state);
     let addr = SocketAddr::from(([0,0,0,0], port));
-    println!("metrics exporter listening on {}", addr);
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    match tls {
+        Some(acceptor) => {
+            println!("metrics exporter listening on {} (TLS)", addr);
+            tls::serve(listener, acceptor, app).await?;
+        }
+        None => {
+            println!("metrics exporter listening on {}", addr);
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
+        }
+    }
+    Ok(())
 }
 
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Shared state for `config`, with only the scrape metrics registered.
+    pub(crate) fn state(config: Config) -> Arc<AppState> {
+        let registry = Registry::new();
+        let scrape = ScrapeMetrics::register(&registry).unwrap();
+        let push = PushStore::new(config.push.persistence_file.as_ref().map(Into::into)).unwrap();
+        let history = History::new(config.history.clone());
+        Arc::new(AppState { registry, scrape, federation: Federation::default(), push, history, config })
+    }
+}
@@ -0,0 +1,359 @@
+//! Blackbox-style HTTP probes: is the endpoint up, fast enough, serving what
+//! we expect, and when does its certificate expire.
+//!
+//! Probes open their own connection rather than going through reqwest so that
+//! DNS, connect, TLS and time to first byte can be measured separately.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper::body::Bytes;
+use hyper::Request;
+use hyper_util::rt::TokioIo;
+use prometheus::{GaugeVec, IntGaugeVec, Opts, Registry};
+use regex::Regex;
+use reqwest::Url;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use crate::config::{ProbeConfig, ProbeTarget};
+
+/// Phases reported by `probe_duration_seconds`, in the order they happen.
+const PHASES: &[&str] = &["dns", "connect", "tls", "first_byte", "transfer"];
+
+/// Probe result series, all labelled by `target`.
+#[derive(Clone)]
+pub struct ProbeMetrics {
+    success: IntGaugeVec,
+    duration: GaugeVec,
+    status: IntGaugeVec,
+    cert_expiry: GaugeVec,
+}
+
+impl ProbeMetrics {
+    pub fn register(registry: &Registry) -> prometheus::Result<Self> {
+        let metrics = Self {
+            success: IntGaugeVec::new(Opts::new("probe_success", "1 if the last probe passed every check"), &["target"])?,
+            duration: GaugeVec::new(
+                Opts::new("probe_duration_seconds", "Duration of each phase of the last probe"),
+                &["target", "phase"],
+            )?,
+            status: IntGaugeVec::new(
+                Opts::new("probe_http_status_code", "HTTP status code of the last probe, 0 if no response"),
+                &["target"],
+            )?,
+            cert_expiry: GaugeVec::new(
+                Opts::new(
+                    "probe_ssl_earliest_cert_expiry",
+                    "Unix time the first certificate in the served chain expires",
+                ),
+                &["target"],
+            )?,
+        };
+        registry.register(Box::new(metrics.success.clone()))?;
+        registry.register(Box::new(metrics.duration.clone()))?;
+        registry.register(Box::new(metrics.status.clone()))?;
+        registry.register(Box::new(metrics.cert_expiry.clone()))?;
+        Ok(metrics)
+    }
+
+    fn record(&self, target: &str, success: bool, outcome: &Outcome) {
+        self.success.with_label_values(&[target]).set(success as i64);
+        for phase in PHASES {
+            let took = outcome.phases.iter().find(|(p, _)| p == phase).map_or(0.0, |(_, d)| d.as_secs_f64());
+            self.duration.with_label_values(&[target, phase]).set(took);
+        }
+        self.status.with_label_values(&[target]).set(outcome.status.map_or(0, i64::from));
+        match outcome.cert_expiry {
+            Some(expiry) => self.cert_expiry.with_label_values(&[target]).set(expiry as f64),
+            None => {
+                let _ = self.cert_expiry.remove_label_values(&[target]);
+            }
+        }
+    }
+}
+
+/// What a probe observed before it finished or failed.
+#[derive(Default)]
+struct Outcome {
+    phases: Vec<(&'static str, Duration)>,
+    status: Option<u16>,
+    cert_expiry: Option<i64>,
+}
+
+struct Probe {
+    target: ProbeTarget,
+    url: Url,
+    body_regex: Option<Regex>,
+    tls: TlsConnector,
+}
+
+/// Start one task per configured probe.
+pub fn spawn(config: &ProbeConfig, metrics: &ProbeMetrics) -> Result<()> {
+    let interval = Duration::from_secs(config.interval_secs);
+    let timeout = Duration::from_secs(config.timeout_secs);
+    for target in &config.targets {
+        let probe = Probe::new(target.clone()).with_context(|| format!("probe {}", target.name))?;
+        tokio::spawn(probe.run(interval, timeout, metrics.clone()));
+    }
+    Ok(())
+}
+
+impl Probe {
+    fn new(target: ProbeTarget) -> Result<Self> {
+        let url = Url::parse(&target.url)?;
+        let body_regex = target.body_regex.as_deref().map(Regex::new).transpose()?;
+        let mut roots = RootCertStore::empty();
+        match &target.ca_file {
+            Some(path) => {
+                for cert in CertificateDer::pem_file_iter(path).with_context(|| format!("reading {}", path))? {
+                    roots.add(cert.with_context(|| format!("parsing {}", path))?)?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let mut tls = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Self { target, url, body_regex, tls: TlsConnector::from(Arc::new(tls)) })
+    }
+
+    async fn run(self, interval: Duration, timeout: Duration, metrics: ProbeMetrics) {
+        let mut ticker = time::interval(interval);
+        ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let start = Instant::now();
+            let mut outcome = Outcome::default();
+            let result = match time::timeout(timeout, self.check(&mut outcome)).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow!("timed out after {:?}", timeout)),
+            };
+            let elapsed = start.elapsed();
+            let result = result.and_then(|()| match self.target.max_latency_ms {
+                Some(max) if elapsed > Duration::from_millis(max) => {
+                    Err(anyhow!("took {}ms, more than max_latency_ms {}", elapsed.as_millis(), max))
+                }
+                _ => Ok(()),
+            });
+            if let Err(e) = &result {
+                eprintln!("probe {} failed: {:#}", self.target.name, e);
+            }
+            metrics.record(&self.target.name, result.is_ok(), &outcome);
+        }
+    }
+
+    /// Run the probe once, recording phases into `out` as they complete.
+    async fn check(&self, out: &mut Outcome) -> Result<()> {
+        let host = self.url.host_str().context("url has no host")?.trim_start_matches('[').trim_end_matches(']');
+        let port = self.url.port_or_known_default().unwrap_or(80);
+
+        let start = Instant::now();
+        let addrs: Vec<SocketAddr> =
+            lookup_host((host, port)).await.with_context(|| format!("resolving {}", host))?.collect();
+        if addrs.is_empty() {
+            bail!("{} has no addresses", host);
+        }
+        out.phases.push(("dns", start.elapsed()));
+
+        let start = Instant::now();
+        let tcp = connect(&addrs).await?;
+        out.phases.push(("connect", start.elapsed()));
+
+        let (status, body) = if self.url.scheme() == "https" {
+            let start = Instant::now();
+            let name = ServerName::try_from(host.to_string())?;
+            let stream = self.tls.connect(name, tcp).await.context("TLS handshake")?;
+            out.phases.push(("tls", start.elapsed()));
+            out.cert_expiry = stream.get_ref().1.peer_certificates().and_then(earliest_expiry);
+            self.exchange(stream, out).await?
+        } else {
+            self.exchange(tcp, out).await?
+        };
+
+        let status_ok = if self.target.valid_status_codes.is_empty() {
+            (200..300).contains(&status)
+        } else {
+            self.target.valid_status_codes.contains(&status)
+        };
+        if !status_ok {
+            bail!("unexpected status {}", status);
+        }
+        if let Some(re) = &self.body_regex {
+            if !re.is_match(&String::from_utf8_lossy(&body)) {
+                bail!("body does not match {:?}", re.as_str());
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the GET over an established connection and read the whole
+    /// response, up to `max_body_bytes`.
+    async fn exchange<S>(&self, stream: S, out: &mut Outcome) -> Result<(u16, Bytes)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let start = Instant::now();
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(async move {
+            let _ = conn.await;
+        });
+        let mut path = self.url.path().to_string();
+        if let Some(query) = self.url.query() {
+            path = format!("{}?{}", path, query);
+        }
+        let host = self.url.host_str().unwrap_or_default();
+        let authority = match self.url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        let req = Request::get(path)
+            .header(hyper::header::HOST, authority)
+            .header(hyper::header::USER_AGENT, "metrics_exporter-probe")
+            .body(Empty::<Bytes>::new())?;
+        let resp = sender.send_request(req).await.context("sending request")?;
+        out.phases.push(("first_byte", start.elapsed()));
+        let status = resp.status().as_u16();
+        out.status = Some(status);
+
+        let start = Instant::now();
+        let limit = self.target.max_body_bytes;
+        let body = Limited::new(resp.into_body(), limit)
+            .collect()
+            .await
+            .map_err(|e| anyhow!("reading body: {} (max_body_bytes is {})", e, limit))?
+            .to_bytes();
+        out.phases.push(("transfer", start.elapsed()));
+        Ok((status, body))
+    }
+}
+
+/// Connect to the first of `addrs` that accepts, e.g. to 127.0.0.1 when
+/// `localhost` also resolves to an IPv6 address nothing listens on.
+async fn connect(addrs: &[SocketAddr]) -> Result<TcpStream> {
+    let mut last = None;
+    for &addr in addrs {
+        match TcpStream::connect(addr).await {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => last = Some(anyhow!(e).context(format!("connecting to {}", addr))),
+        }
+    }
+    Err(last.unwrap_or_else(|| anyhow!("no addresses to connect to")))
+}
+
+/// Unix time of the earliest `notAfter` in the chain.
+fn earliest_expiry(certs: &[CertificateDer<'_>]) -> Option<i64> {
+    certs
+        .iter()
+        .filter_map(|cert| x509_parser::parse_x509_certificate(cert).ok())
+        .map(|(_, cert)| cert.validity().not_after.timestamp())
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use axum::routing::get;
+    use axum::Router;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::config::TlsConfig;
+    use crate::tls;
+
+    /// Serve a small page and an oversized one over HTTPS on 127.0.0.1 with a
+    /// fresh self-signed certificate. Returns the port and the certificate,
+    /// for use as `ca_file`.
+    async fn https_server(name: &str) -> (u16, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("metrics_exporter_probe_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".into()]).unwrap();
+        let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert_file, certified.cert.pem()).unwrap();
+        std::fs::write(&key_file, certified.key_pair.serialize_pem()).unwrap();
+        let acceptor = tls::acceptor(&TlsConfig {
+            cert_file: cert_file.to_string_lossy().into_owned(),
+            key_file: key_file.to_string_lossy().into_owned(),
+        })
+        .unwrap();
+
+        let app = Router::new()
+            .route("/", get(|| async { "<title>ok</title>" }))
+            .route("/big", get(|| async { "x".repeat(64 * 1024) }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(tls::serve(listener, acceptor, app));
+        (port, cert_file)
+    }
+
+    fn probe(url: String, ca_file: &std::path::Path, max_body_bytes: usize) -> Probe {
+        Probe::new(ProbeTarget {
+            name: "local".into(),
+            url,
+            valid_status_codes: Vec::new(),
+            body_regex: Some("<title>".into()),
+            max_latency_ms: None,
+            ca_file: Some(ca_file.to_string_lossy().into_owned()),
+            max_body_bytes,
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn probes_https_with_phases_and_cert_expiry() {
+        let (port, ca_file) = https_server("ok").await;
+        let probe = probe(format!("https://127.0.0.1:{}/", port), &ca_file, 1 << 20);
+        let mut outcome = Outcome::default();
+        probe.check(&mut outcome).await.unwrap();
+
+        assert_eq!(outcome.status, Some(200));
+        let phases: Vec<&str> = outcome.phases.iter().map(|(phase, _)| *phase).collect();
+        assert_eq!(phases, PHASES);
+        let cert = std::fs::read(&ca_file).unwrap();
+        let der = CertificateDer::from_pem_slice(&cert).unwrap();
+        assert_eq!(outcome.cert_expiry, earliest_expiry(&[der]));
+        assert!(outcome.cert_expiry.is_some());
+    }
+
+    #[tokio::test]
+    async fn fails_on_an_untrusted_certificate() {
+        let (port, _) = https_server("untrusted").await;
+        let (_, other_ca) = https_server("other").await;
+        let probe = probe(format!("https://127.0.0.1:{}/", port), &other_ca, 1 << 20);
+        let error = probe.check(&mut Outcome::default()).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("TLS handshake"), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_body_over_max_body_bytes() {
+        let (port, ca_file) = https_server("big").await;
+        let probe = probe(format!("https://127.0.0.1:{}/big", port), &ca_file, 1024);
+        let mut outcome = Outcome::default();
+        let error = probe.check(&mut outcome).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("max_body_bytes"), "{:#}", error);
+        assert_eq!(outcome.status, Some(200));
+    }
+
+    #[tokio::test]
+    async fn tries_each_resolved_address() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let open = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp = connect(&[closed, open.local_addr().unwrap()]).await.unwrap();
+        assert_eq!(tcp.peer_addr().unwrap(), open.local_addr().unwrap());
+
+        let error = connect(&[closed]).await.unwrap_err();
+        assert!(format!("{:#}", error).starts_with(&format!("connecting to {}", closed)), "{:#}", error);
+    }
+}
@@ -0,0 +1,344 @@
+//! Pushgateway-style ingestion for short-lived jobs that cannot be scraped.
+//!
+//! Groups are addressed as `/metrics/job/<job>{/<label>/<value>}`. `PUT`
+//! replaces a group, `POST` replaces only the families present in the body and
+//! `DELETE` drops the group. Groups can be persisted to a JSON file so they
+//! survive a restart; the file is written by a background task, so pushes
+//! never wait on the disk.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use prometheus::TextEncoder;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::exposition::{self, family, sample};
+use crate::AppState;
+
+/// Grouping labels, `job` first, then the rest in path order.
+type GroupKey = Vec<(String, String)>;
+
+struct Group {
+    /// Families as pushed, without the grouping labels.
+    families: Vec<MetricFamily>,
+    push_time: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedGroup {
+    labels: GroupKey,
+    push_time: f64,
+    /// The group's families in text exposition format.
+    metrics: String,
+}
+
+#[derive(Clone)]
+pub struct PushStore {
+    groups: Arc<RwLock<BTreeMap<GroupKey, Group>>>,
+    /// Snapshots for the persistence file's writer, `None` without one.
+    tx: Option<mpsc::UnboundedSender<Vec<PersistedGroup>>>,
+    desc: Arc<Desc>,
+}
+
+impl PushStore {
+    /// Create the store, restoring groups from `persistence_file` if it exists.
+    /// The file's writer is a blocking task, so this must be called within the
+    /// runtime when a file is given.
+    pub fn new(persistence_file: Option<PathBuf>) -> Result<Self> {
+        let desc = Desc::new(
+            "push_time_seconds".into(),
+            "Last Unix time a group was pushed".into(),
+            vec!["job".into()],
+            HashMap::new(),
+        )?;
+        let store = Self { groups: Arc::default(), tx: None, desc: Arc::new(desc) };
+        let Some(path) = persistence_file else { return Ok(store) };
+        if path.exists() {
+            let text = fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+            let persisted: Vec<PersistedGroup> =
+                serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))?;
+            let mut groups = store.groups.write().unwrap();
+            for group in persisted {
+                let families = exposition::parse(&group.metrics)
+                    .with_context(|| format!("restoring group {:?}", group.labels))?;
+                groups.insert(group.labels, Group { families, push_time: group.push_time });
+            }
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::task::spawn_blocking(move || write_file(&path, rx));
+        Ok(Self { tx: Some(tx), ..store })
+    }
+
+    fn push(&self, key: GroupKey, families: Vec<MetricFamily>, replace: bool) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let mut groups = self.groups.write().unwrap();
+        let group = groups.entry(key).or_insert_with(|| Group { families: Vec::new(), push_time: now });
+        if replace {
+            group.families = families;
+        } else {
+            group.families.retain(|old| families.iter().all(|new| new.get_name() != old.get_name()));
+            group.families.extend(families);
+        }
+        group.push_time = now;
+        self.persist(&groups);
+    }
+
+    fn delete(&self, key: &GroupKey) {
+        let mut groups = self.groups.write().unwrap();
+        groups.remove(key);
+        self.persist(&groups);
+    }
+
+    /// Queue every group for the persistence file, if one is configured.
+    /// Called with the groups still locked, so snapshots queue in order.
+    fn persist(&self, groups: &BTreeMap<GroupKey, Group>) {
+        let Some(tx) = &self.tx else { return };
+        let persisted = groups
+            .iter()
+            .map(|(labels, group)| PersistedGroup {
+                labels: labels.clone(),
+                push_time: group.push_time,
+                metrics: TextEncoder::new().encode_to_string(&group.families).unwrap_or_default(),
+            })
+            .collect();
+        let _ = tx.send(persisted);
+    }
+}
+
+/// Write snapshots to `path` until the store is dropped. Only the latest of
+/// those queued meanwhile is written.
+fn write_file(path: &FsPath, mut rx: mpsc::UnboundedReceiver<Vec<PersistedGroup>>) {
+    while let Some(mut persisted) = rx.blocking_recv() {
+        while let Ok(newer) = rx.try_recv() {
+            persisted = newer;
+        }
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_vec(&persisted)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(fs::write(&tmp, bytes)?))
+            .and_then(|_| Ok(fs::rename(&tmp, path)?));
+        if let Err(e) = result {
+            eprintln!("failed to persist pushed metrics to {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl Collector for PushStore {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let groups = self.groups.read().unwrap();
+        let mut out = Vec::new();
+        let mut push_times = Vec::new();
+        for (key, group) in groups.iter() {
+            let grouping: Vec<(&str, &str)> = key.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+            for mf in &group.families {
+                let mut mf = mf.clone();
+                for m in mf.mut_metric().iter_mut() {
+                    let mut labels: Vec<LabelPair> = m.take_label().into();
+                    labels.retain(|lp| key.iter().all(|(k, _)| k != lp.get_name()));
+                    labels.extend(exposition::label_pairs(&grouping));
+                    labels.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+                    m.set_label(labels.into());
+                }
+                out.push(mf);
+            }
+            push_times.push(sample(MetricType::GAUGE, &grouping, group.push_time));
+        }
+        if !push_times.is_empty() {
+            out.push(family("push_time_seconds", "Last Unix time a group was pushed", MetricType::GAUGE, push_times));
+        }
+        out
+    }
+}
+
+/// Turn `<job>{/<label>/<value>}` into a grouping key.
+fn group_key(path: &str) -> Result<GroupKey, String> {
+    let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let Some((job, rest)) = parts.split_first() else {
+        return Err("job name must not be empty".into());
+    };
+    if rest.len() % 2 != 0 {
+        return Err("grouping labels must come in name/value pairs".into());
+    }
+    let mut key = vec![("job".to_string(), job.to_string())];
+    for pair in rest.chunks(2) {
+        let (name, value) = (pair[0], pair[1]);
+        let mut chars = name.chars();
+        let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid || name.starts_with("__") {
+            return Err(format!("invalid label name {:?}", name));
+        }
+        if key.iter().any(|(k, _)| k == name) {
+            return Err(format!("duplicate grouping label {:?}", name));
+        }
+        key.push((name.to_string(), value.to_string()));
+    }
+    Ok(key)
+}
+
+/// Parse a pushed body and make sure no series contradicts the grouping key.
+fn parse_push(key: &GroupKey, body: &str) -> Result<Vec<MetricFamily>, String> {
+    let families = exposition::parse(body).map_err(|e| e.to_string())?;
+    for mf in &families {
+        for m in mf.get_metric() {
+            for lp in m.get_label() {
+                if let Some((k, v)) = key.iter().find(|(k, _)| k == lp.get_name()) {
+                    if v != lp.get_value() {
+                        return Err(format!(
+                            "{} has label {}={:?} which conflicts with the grouping key value {:?}",
+                            mf.get_name(),
+                            k,
+                            lp.get_value(),
+                            v
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    Ok(families)
+}
+
+fn ingest(state: &AppState, path: &str, body: &str, replace: bool) -> (StatusCode, String) {
+    let result = group_key(path).and_then(|key| Ok((parse_push(&key, body)?, key)));
+    match result {
+        Ok((families, key)) => {
+            state.push.push(key, families, replace);
+            (StatusCode::OK, String::new())
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, format!("{}\n", e)),
+    }
+}
+
+/// `PUT /metrics/job/*path`: replace the whole group.
+pub async fn put_group(State(state): State<Arc<AppState>>, Path(path): Path<String>, body: String) -> impl IntoResponse {
+    ingest(&state, &path, &body, true)
+}
+
+/// `POST /metrics/job/*path`: replace only the pushed families.
+pub async fn post_group(State(state): State<Arc<AppState>>, Path(path): Path<String>, body: String) -> impl IntoResponse {
+    ingest(&state, &path, &body, false)
+}
+
+/// `DELETE /metrics/job/*path`: drop the group.
+pub async fn delete_group(State(state): State<Arc<AppState>>, Path(path): Path<String>) -> impl IntoResponse {
+    match group_key(&path) {
+        Ok(key) => {
+            state.push.delete(&key);
+            (StatusCode::ACCEPTED, String::new())
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, format!("{}\n", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::config::Config;
+
+    /// Every pushed series as `name{labels} value`, without push times.
+    fn series(store: &PushStore) -> Vec<String> {
+        exposition::flatten(&store.collect())
+            .into_iter()
+            .filter(|s| s.name != "push_time_seconds")
+            .map(|s| {
+                let labels: Vec<_> = s.labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                format!("{}{{{}}} {}", s.name, labels.join(","), s.value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_grouping_keys() {
+        let key = |path: &str| group_key(path).map(|key| format!("{:?}", key));
+        assert_eq!(key("batch").unwrap(), r#"[("job", "batch")]"#);
+        assert_eq!(key("batch/instance/a/zone/b/").unwrap(), r#"[("job", "batch"), ("instance", "a"), ("zone", "b")]"#);
+        assert_eq!(key(""), Err("job name must not be empty".into()));
+        assert_eq!(key("batch/instance"), Err("grouping labels must come in name/value pairs".into()));
+        assert_eq!(key("batch/1x/a"), Err("invalid label name \"1x\"".into()));
+        assert_eq!(key("batch/__name__/a"), Err("invalid label name \"__name__\"".into()));
+        assert_eq!(key("batch/job/other"), Err("duplicate grouping label \"job\"".into()));
+    }
+
+    #[tokio::test]
+    async fn put_replaces_groups_and_post_replaces_families() {
+        let state = crate::tests::state(Config::default());
+        let send = |method: &str, path: &str, body: &str| {
+            let (state, path, body) = (state.clone(), Path(path.to_string()), body.to_string());
+            let method = method.to_string();
+            async move {
+                match method.as_str() {
+                    "PUT" => put_group(State(state), path, body).await.into_response().status(),
+                    "POST" => post_group(State(state), path, body).await.into_response().status(),
+                    _ => delete_group(State(state), path).await.into_response().status(),
+                }
+            }
+        };
+        assert_eq!(send("PUT", "batch/instance/x", "a 1\nb{instance=\"x\"} 2\n").await, StatusCode::OK);
+        assert_eq!(send("PUT", "other", "a 9\n").await, StatusCode::OK);
+        assert_eq!(
+            series(&state.push),
+            ["a{instance=x,job=batch} 1", "b{instance=x,job=batch} 2", "a{job=other} 9"]
+        );
+
+        assert_eq!(send("POST", "batch/instance/x", "b 3\nc 4\n").await, StatusCode::OK);
+        assert_eq!(
+            series(&state.push),
+            ["a{instance=x,job=batch} 1", "b{instance=x,job=batch} 3", "c{instance=x,job=batch} 4", "a{job=other} 9"]
+        );
+        assert_eq!(send("PUT", "batch/instance/x", "c 5\n").await, StatusCode::OK);
+        assert_eq!(series(&state.push), ["c{instance=x,job=batch} 5", "a{job=other} 9"]);
+
+        // Rejected pushes leave the group as it was.
+        assert_eq!(send("PUT", "batch/instance/x", "c{instance=\"y\"} 6\n").await, StatusCode::BAD_REQUEST);
+        assert_eq!(send("POST", "batch/instance/x", "c{\n").await, StatusCode::BAD_REQUEST);
+        assert_eq!(series(&state.push), ["c{instance=x,job=batch} 5", "a{job=other} 9"]);
+
+        assert_eq!(send("DELETE", "batch/instance/x", "").await, StatusCode::ACCEPTED);
+        assert_eq!(send("DELETE", "batch/instance/missing", "").await, StatusCode::ACCEPTED);
+        assert_eq!(series(&state.push), ["a{job=other} 9"]);
+        let push_times = exposition::flatten(&state.push.collect());
+        assert_eq!(push_times.iter().filter(|s| s.name == "push_time_seconds").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn persists_groups_across_restarts() {
+        let path = std::env::temp_dir().join(format!("metrics-exporter-push-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let store = PushStore::new(Some(path.clone())).unwrap();
+        let done = exposition::parse("# TYPE done counter\ndone 3\n").unwrap();
+        store.push(group_key("batch/instance/x").unwrap(), done, true);
+        store.push(group_key("gone").unwrap(), exposition::parse("a 1\n").unwrap(), true);
+        store.delete(&group_key("gone").unwrap());
+
+        let mut restored = None;
+        for _ in 0..500 {
+            let text = fs::read_to_string(&path).unwrap_or_default();
+            if serde_json::from_str::<Vec<PersistedGroup>>(&text).is_ok_and(|groups| groups.len() == 1) {
+                restored = Some(PushStore::new(Some(path.clone())).unwrap());
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let restored = restored.expect("the store is never written");
+        fs::remove_file(&path).unwrap();
+        assert_eq!(series(&restored), ["done{instance=x,job=batch} 3"]);
+        assert_eq!(restored.collect()[0].get_field_type(), MetricType::COUNTER);
+    }
+}
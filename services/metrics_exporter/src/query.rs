@@ -0,0 +1,113 @@
+//! The small PromQL subset shared by alert rules and the history API:
+//! instant vector selectors and durations.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+
+use crate::exposition::Labels;
+
+/// Parse durations like `30s`, `5m`, `1h30m` or `500ms`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        bail!("empty duration");
+    }
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let n: u64 = rest[..digits].parse().with_context(|| format!("invalid duration {:?}", s))?;
+        rest = &rest[digits..];
+        let unit_len = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let part = match &rest[..unit_len] {
+            "ms" => Some(Duration::from_millis(n)),
+            "s" => Some(Duration::from_secs(n)),
+            "m" => n.checked_mul(60).map(Duration::from_secs),
+            "h" => n.checked_mul(3600).map(Duration::from_secs),
+            "d" => n.checked_mul(86400).map(Duration::from_secs),
+            unit => bail!("invalid duration unit {:?} in {:?}", unit, s),
+        };
+        total = part.and_then(|part| total.checked_add(part)).with_context(|| format!("duration {:?} is too long", s))?;
+        rest = &rest[unit_len..];
+    }
+    Ok(total)
+}
+
+/// An instant vector selector such as `http_requests_total{code=~"5.."}`.
+pub struct Selector {
+    pub name: String,
+    matchers: Vec<Matcher>,
+}
+
+enum Matcher {
+    Eq(String, String),
+    Ne(String, String),
+    Re(String, Regex),
+    NotRe(String, Regex),
+}
+
+impl Selector {
+    pub fn parse(input: &str) -> Result<Self> {
+        let (name, body) = match input.split_once('{') {
+            Some((name, body)) => (name.trim(), Some(body.strip_suffix('}').context("missing closing brace")?)),
+            None => (input.trim(), None),
+        };
+        let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+        if !valid {
+            bail!("invalid metric name {:?}", name);
+        }
+        let mut matchers = Vec::new();
+        let mut rest = body.unwrap_or("").trim();
+        while !rest.is_empty() {
+            let op_at = rest.find(['=', '!']).context("label matcher without operator")?;
+            let label = rest[..op_at].trim().to_string();
+            let after = &rest[op_at..];
+            let (op, after) = ["=~", "!~", "!=", "="]
+                .iter()
+                .find_map(|op| after.strip_prefix(op).map(|a| (*op, a)))
+                .context("invalid label matcher operator")?;
+            let after = after.trim_start().strip_prefix('"').context("label matcher value must be quoted")?;
+            let end = after.find('"').context("unterminated label matcher value")?;
+            let value = after[..end].to_string();
+            let anchored = || Regex::new(&format!("^(?:{})$", value)).with_context(|| format!("invalid regex {:?}", value));
+            matchers.push(match op {
+                "=" => Matcher::Eq(label, value.clone()),
+                "!=" => Matcher::Ne(label, value.clone()),
+                "=~" => Matcher::Re(label, anchored()?),
+                _ => Matcher::NotRe(label, anchored()?),
+            });
+            rest = after[end + 1..].trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+        }
+        Ok(Self { name: name.to_string(), matchers })
+    }
+
+    /// Whether the series `name{labels}` is selected. Missing labels match as empty.
+    pub fn matches(&self, name: &str, labels: &Labels) -> bool {
+        let get = |label: &str| labels.iter().find(|(k, _)| k == label).map_or("", |(_, v)| v.as_str());
+        name == self.name
+            && self.matchers.iter().all(|m| match m {
+                Matcher::Eq(l, v) => get(l) == v,
+                Matcher::Ne(l, v) => get(l) != v,
+                Matcher::Re(l, re) => re.is_match(get(l)),
+                Matcher::NotRe(l, re) => !re.is_match(get(l)),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("1s500ms").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration("2d").unwrap(), Duration::from_secs(2 * 86400));
+        for bad in ["", "5", "m", "5w", "-5s", "999999999999999999d", "18446744073709551615s1s"] {
+            assert!(parse_duration(bad).is_err(), "{:?}", bad);
+        }
+    }
+}
@@ -0,0 +1,331 @@
+//! Prometheus remote_write sender for shipping the registry to long-term storage.
+//!
+//! Every interval the whole registry is gathered into one snappy-compressed
+//! `WriteRequest`. Batches that cannot be delivered after retrying are spooled
+//! to `queue_dir` and replayed, oldest first, once the receiver is back.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use prometheus::{IntCounter, IntGauge, Registry};
+use prost::Message;
+use reqwest::{Client, StatusCode};
+
+use crate::config::RemoteWriteConfig;
+use crate::exposition;
+use crate::federation::Federation;
+
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+/// Why a send failed, and whether trying again could help.
+enum SendError {
+    Retryable(String),
+    Fatal(String),
+}
+
+pub struct RemoteWriter {
+    config: RemoteWriteConfig,
+    registry: Registry,
+    federation: Federation,
+    client: Client,
+    sent: IntCounter,
+    failed: IntCounter,
+    queued: IntGauge,
+}
+
+impl RemoteWriter {
+    pub fn new(config: RemoteWriteConfig, registry: Registry, federation: Federation) -> Result<Self> {
+        let sent = IntCounter::new("remote_write_sent_batches_total", "Batches accepted by the remote_write receiver")?;
+        let failed = IntCounter::new("remote_write_failed_batches_total", "Batches that could not be delivered this cycle")?;
+        let queued = IntGauge::new("remote_write_queued_batches", "Batches waiting in the on-disk queue")?;
+        registry.register(Box::new(sent.clone()))?;
+        registry.register(Box::new(failed.clone()))?;
+        registry.register(Box::new(queued.clone()))?;
+        if let Some(dir) = &config.queue_dir {
+            fs::create_dir_all(dir).with_context(|| format!("creating {}", dir))?;
+        }
+        let client = Client::builder().timeout(Duration::from_secs(config.timeout_secs)).build()?;
+        Ok(Self { config, registry, federation, client, sent, failed, queued })
+    }
+
+    pub async fn run(self) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(self.config.interval_secs));
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let batch = self.encode_registry();
+            let delivered = self.drain_queue().await && self.send_with_retry(&batch).await;
+            if !delivered {
+                self.failed.inc();
+                self.enqueue(&batch);
+            }
+            self.queued.set(self.queue_files().len() as i64);
+        }
+    }
+
+    /// Gather the registry into a snappy-compressed `WriteRequest`.
+    fn encode_registry(&self) -> Vec<u8> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+        let timeseries = exposition::flatten(&self.federation.gather(&self.registry))
+            .into_iter()
+            .map(|s| {
+                let mut labels = vec![Label { name: "__name__".into(), value: s.name }];
+                labels.extend(s.labels.into_iter().map(|(name, value)| Label { name, value }));
+                labels.sort_by(|a, b| a.name.cmp(&b.name));
+                TimeSeries {
+                    labels,
+                    samples: vec![Sample { value: s.value, timestamp: s.timestamp_ms.unwrap_or(now) }],
+                }
+            })
+            .collect();
+        let raw = WriteRequest { timeseries }.encode_to_vec();
+        snap::raw::Encoder::new().compress_vec(&raw).expect("snappy can compress any input")
+    }
+
+    async fn send(&self, body: &[u8]) -> Result<(), SendError> {
+        let resp = self
+            .client
+            .post(&self.config.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+            .header(reqwest::header::CONTENT_ENCODING, "snappy")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body.to_vec())
+            .send()
+            .await
+            .map_err(|e| SendError::Retryable(e.to_string()))?;
+        let status = resp.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(SendError::Retryable(format!("receiver returned {}", status)))
+        } else {
+            let text = resp.text().await.unwrap_or_default();
+            Err(SendError::Fatal(format!("receiver returned {}: {}", status, text.trim())))
+        }
+    }
+
+    /// Send with exponential backoff. Returns false if the batch should be
+    /// kept for later; batches the receiver rejects outright are dropped.
+    async fn send_with_retry(&self, body: &[u8]) -> bool {
+        let mut backoff = Duration::from_millis(self.config.min_backoff_ms);
+        for attempt in 0..=self.config.max_retries {
+            match self.send(body).await {
+                Ok(()) => {
+                    self.sent.inc();
+                    return true;
+                }
+                Err(SendError::Fatal(e)) => {
+                    eprintln!("remote_write: dropping batch: {}", e);
+                    return true;
+                }
+                Err(SendError::Retryable(e)) => {
+                    eprintln!("remote_write: attempt {} failed: {}", attempt + 1, e);
+                    if attempt < self.config.max_retries {
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(Duration::from_millis(self.config.max_backoff_ms));
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Replay spooled batches oldest first. Stops at the first failure so
+    /// ordering is preserved; returns whether the queue is now empty.
+    async fn drain_queue(&self) -> bool {
+        for path in self.queue_files() {
+            let body = match fs::read(&path) {
+                Ok(body) => body,
+                Err(e) => {
+                    eprintln!("remote_write: unreadable queue file {}: {}", path.display(), e);
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+            };
+            if !self.send_with_retry(&body).await {
+                return false;
+            }
+            let _ = fs::remove_file(&path);
+        }
+        true
+    }
+
+    fn enqueue(&self, body: &[u8]) {
+        let Some(dir) = &self.config.queue_dir else { return };
+        if let Err(e) = write_queue_file(Path::new(dir), body) {
+            eprintln!("remote_write: failed to spool batch: {:#}", e);
+            return;
+        }
+        let files = self.queue_files();
+        let excess = files.len().saturating_sub(self.config.max_queue_files);
+        for old in &files[..excess] {
+            let _ = fs::remove_file(old);
+        }
+    }
+
+    /// Spooled batches, oldest first.
+    fn queue_files(&self) -> Vec<PathBuf> {
+        let Some(dir) = &self.config.queue_dir else { return Vec::new() };
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok().map(|e| e.path()))
+                    .filter(|p| p.extension().is_some_and(|ext| ext == "snappy"))
+                    .collect()
+            })
+            .unwrap_or_default();
+        files.sort();
+        files
+    }
+}
+
+fn write_queue_file(dir: &Path, body: &[u8]) -> Result<()> {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    let path = dir.join(format!("{:020}.snappy", nanos));
+    if path.exists() {
+        bail!("queue file {} already exists", path.display());
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, body)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode as Status};
+    use axum::routing::post;
+    use axum::Router;
+    use prometheus::{GaugeVec, Opts};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Stub {
+        /// Decoded bodies received, in order.
+        received: Arc<Mutex<Vec<WriteRequest>>>,
+        /// Statuses to answer with, in order; 204 once they run out.
+        answers: Arc<Mutex<VecDeque<u16>>>,
+    }
+
+    /// A remote_write receiver on a local port. Returns its URL.
+    async fn receiver(stub: Stub) -> String {
+        let write = |State(stub): State<Stub>, headers: HeaderMap, body: Bytes| async move {
+            assert_eq!(headers["content-encoding"], "snappy");
+            assert_eq!(headers["content-type"], "application/x-protobuf");
+            let raw = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+            stub.received.lock().unwrap().push(WriteRequest::decode(raw.as_slice()).unwrap());
+            Status::from_u16(stub.answers.lock().unwrap().pop_front().unwrap_or(204)).unwrap()
+        };
+        let app = Router::new().route("/write", post(write)).with_state(stub);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/write", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    fn writer(url: String, queue_dir: Option<String>) -> RemoteWriter {
+        let config = RemoteWriteConfig {
+            url,
+            max_retries: 2,
+            min_backoff_ms: 1,
+            max_backoff_ms: 1,
+            queue_dir,
+            ..RemoteWriteConfig::default()
+        };
+        RemoteWriter::new(config, Registry::new(), Federation::default()).unwrap()
+    }
+
+    fn label_pairs(series: &TimeSeries) -> Vec<(&str, &str)> {
+        series.labels.iter().map(|l| (l.name.as_str(), l.value.as_str())).collect()
+    }
+
+    #[tokio::test]
+    async fn sends_the_registry_with_sorted_labels() {
+        let stub = Stub::default();
+        let writer = writer(receiver(stub.clone()).await, None);
+        let temps = GaugeVec::new(Opts::new("room_temp", "Temperature"), &["site", "floor"]).unwrap();
+        temps.with_label_values(&["ams", "2"]).set(21.5);
+        writer.registry.register(Box::new(temps)).unwrap();
+
+        assert!(writer.send_with_retry(&writer.encode_registry()).await);
+        assert_eq!(writer.sent.get(), 1);
+        let received = stub.received.lock().unwrap();
+        let request = &received[0];
+        let temp = request.timeseries.iter().find(|s| s.labels.iter().any(|l| l.value == "room_temp")).unwrap();
+        assert_eq!(label_pairs(temp), [("__name__", "room_temp"), ("floor", "2"), ("site", "ams")]);
+        assert_eq!(temp.samples.len(), 1);
+        assert_eq!(temp.samples[0].value, 21.5);
+        assert!(temp.samples[0].timestamp > 0);
+        let names: Vec<_> = request.timeseries.iter().map(|s| s.labels[0].value.as_str()).collect();
+        assert!(names.contains(&"remote_write_sent_batches_total"), "{:?}", names);
+    }
+
+    #[tokio::test]
+    async fn drops_on_4xx_and_retries_on_5xx() {
+        let stub = Stub::default();
+        let dir = std::env::temp_dir().join(format!("remote-write-queue-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let writer = writer(receiver(stub.clone()).await, Some(dir.to_string_lossy().into_owned()));
+        let batch = writer.encode_registry();
+        let attempts = || stub.received.lock().unwrap().len();
+
+        // A 4xx will not get better: one attempt, then the batch is dropped.
+        stub.answers.lock().unwrap().extend([400]);
+        assert!(writer.send_with_retry(&batch).await);
+        assert_eq!((attempts(), writer.sent.get()), (1, 0));
+
+        // 5xx and 429 are retried until one succeeds.
+        stub.answers.lock().unwrap().extend([503, 429]);
+        assert!(writer.send_with_retry(&batch).await);
+        assert_eq!((attempts(), writer.sent.get()), (4, 1));
+
+        // Still failing after max_retries: the batch is kept and spooled.
+        stub.answers.lock().unwrap().extend([500, 502, 503]);
+        assert!(!writer.send_with_retry(&batch).await);
+        assert_eq!(attempts(), 7);
+        writer.enqueue(&batch);
+        assert_eq!(writer.queue_files().len(), 1);
+
+        // Once the receiver is back the spooled batch is replayed and removed.
+        assert!(writer.drain_queue().await);
+        assert_eq!((attempts(), writer.sent.get()), (8, 2));
+        assert!(writer.queue_files().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+//! Scraping of a single target and the bookkeeping series describing how it went.
+
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::{GaugeVec, IntCounterVec, IntGaugeVec, Opts, Registry};
+use reqwest::{Client, StatusCode};
+
+use crate::config::{TargetConfig, TargetKind};
+use crate::exposition;
+use crate::federation::Federation;
+
+/// Per-target health series, all labelled by `target`.
+#[derive(Clone)]
+pub struct ScrapeMetrics {
+    requests: IntCounterVec,
+    up: IntGaugeVec,
+    duration: GaugeVec,
+    last_success: GaugeVec,
+    http_status: IntGaugeVec,
+    errors: IntCounterVec,
+}
+
+impl ScrapeMetrics {
+    pub fn register(registry: &Registry) -> prometheus::Result<Self> {
+        let metrics = Self {
+            requests: IntCounterVec::new(Opts::new("scrape_requests_total", "Number of scrapes"), &["target"])?,
+            up: IntGaugeVec::new(Opts::new("up", "1 if the last scrape of the target succeeded, 0 otherwise"), &["target"])?,
+            duration: GaugeVec::new(
+                Opts::new("scrape_duration_seconds", "Duration of the last scrape of the target"),
+                &["target"],
+            )?,
+            last_success: GaugeVec::new(
+                Opts::new("scrape_last_success_timestamp_seconds", "Unix time of the last successful scrape"),
+                &["target"],
+            )?,
+            http_status: IntGaugeVec::new(
+                Opts::new("scrape_http_status", "HTTP status code of the last scrape, 0 if no response"),
+                &["target"],
+            )?,
+            errors: IntCounterVec::new(
+                Opts::new("scrape_errors_total", "Failed scrapes by failure kind"),
+                &["target", "kind"],
+            )?,
+        };
+        registry.register(Box::new(metrics.requests.clone()))?;
+        registry.register(Box::new(metrics.up.clone()))?;
+        registry.register(Box::new(metrics.duration.clone()))?;
+        registry.register(Box::new(metrics.last_success.clone()))?;
+        registry.register(Box::new(metrics.http_status.clone()))?;
+        registry.register(Box::new(metrics.errors.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Drop every series of a target that is no longer scraped.
+    pub fn forget(&self, target: &str) {
+        let _ = self.requests.remove_label_values(&[target]);
+        let _ = self.up.remove_label_values(&[target]);
+        let _ = self.duration.remove_label_values(&[target]);
+        let _ = self.last_success.remove_label_values(&[target]);
+        let _ = self.http_status.remove_label_values(&[target]);
+        for kind in ["connect", "timeout", "non_2xx", "parse"] {
+            let _ = self.errors.remove_label_values(&[target, kind]);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ScrapeError {
+    Connect(reqwest::Error),
+    Timeout,
+    Status(StatusCode),
+    Parse(String),
+}
+
+impl ScrapeError {
+    fn from_reqwest(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            ScrapeError::Timeout
+        } else {
+            ScrapeError::Connect(e)
+        }
+    }
+
+    /// Value of the `kind` label on `scrape_errors_total`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ScrapeError::Connect(_) => "connect",
+            ScrapeError::Timeout => "timeout",
+            ScrapeError::Status(_) => "non_2xx",
+            ScrapeError::Parse(_) => "parse",
+        }
+    }
+}
+
+impl fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScrapeError::Connect(e) => write!(f, "request failed: {}", e),
+            ScrapeError::Timeout => write!(f, "request timed out"),
+            ScrapeError::Status(status) => write!(f, "unexpected status {}", status),
+            ScrapeError::Parse(e) => write!(f, "invalid response body: {}", e),
+        }
+    }
+}
+
+/// Scrape one target, update its federated series and record how the scrape went.
+pub async fn scrape(
+    client: &Client,
+    target: &TargetConfig,
+    timeout: Duration,
+    custom_gauges: &[String],
+    metrics: &ScrapeMetrics,
+    federation: &Federation,
+) {
+    let start = Instant::now();
+    let name = target.name.as_str();
+    metrics.requests.with_label_values(&[name]).inc();
+    let result = fetch(client, target, timeout, custom_gauges, metrics).await;
+    metrics.duration.with_label_values(&[name]).set(start.elapsed().as_secs_f64());
+
+    match result {
+        Ok(families) => {
+            federation.update(name, families);
+            metrics.up.with_label_values(&[name]).set(1);
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            metrics.last_success.with_label_values(&[name]).set(now.as_secs_f64());
+        }
+        Err(e) => {
+            eprintln!("failed to scrape {}: {}", name, e);
+            federation.remove(name);
+            metrics.up.with_label_values(&[name]).set(0);
+            metrics.errors.with_label_values(&[name, e.kind()]).inc();
+        }
+    }
+}
+
+async fn fetch(
+    client: &Client,
+    target: &TargetConfig,
+    timeout: Duration,
+    custom_gauges: &[String],
+    metrics: &ScrapeMetrics,
+) -> Result<Vec<MetricFamily>, ScrapeError> {
+    let status = metrics.http_status.with_label_values(&[&target.name]);
+    status.set(0);
+    let accept = match target.kind {
+        TargetKind::Prometheus => "text/plain;version=0.0.4",
+        TargetKind::Custom => "application/json",
+    };
+    let resp = client
+        .get(&target.url)
+        .header(reqwest::header::ACCEPT, accept)
+        .timeout(timeout)
+        .send()
+        .await
+        .map_err(ScrapeError::from_reqwest)?;
+    status.set(resp.status().as_u16() as i64);
+    if !resp.status().is_success() {
+        return Err(ScrapeError::Status(resp.status()));
+    }
+    let body = resp.text().await.map_err(ScrapeError::from_reqwest)?;
+    match target.kind {
+        TargetKind::Prometheus => exposition::parse(&body).map_err(|e| ScrapeError::Parse(e.to_string())),
+        TargetKind::Custom => custom_families(&body, custom_gauges),
+    }
+}
+
+/// Turn a flat JSON object into one gauge per configured key. Keys that are
+/// missing or not numeric are skipped.
+fn custom_families(body: &str, gauges: &[String]) -> Result<Vec<MetricFamily>, ScrapeError> {
+    let value: serde_json::Value = serde_json::from_str(body).map_err(|e| ScrapeError::Parse(e.to_string()))?;
+    let object = value
+        .as_object()
+        .ok_or_else(|| ScrapeError::Parse("expected a JSON object".into()))?;
+    Ok(gauges
+        .iter()
+        .filter_map(|name| {
+            let v = object.get(name)?.as_f64()?;
+            let m = exposition::sample(MetricType::GAUGE, &[], v);
+            Some(exposition::family(name, "", MetricType::GAUGE, vec![m]))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode as Code;
+    use axum::routing::get;
+    use axum::Router;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Serve a target that answers in every way a scrape can go.
+    async fn app() -> String {
+        let app = Router::new()
+            .route("/metrics", get(|| async { "# TYPE jobs counter\njobs 3\n" }))
+            .route("/json", get(|| async { r#"{"queue": 4, "label": "x", "users": 1.5}"# }))
+            .route("/down", get(|| async { (Code::SERVICE_UNAVAILABLE, "") }))
+            .route("/garbage", get(|| async { "<html>" }))
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    ""
+                }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    fn target(name: &str, url: String, kind: TargetKind) -> TargetConfig {
+        TargetConfig { name: name.into(), url, kind, interval_secs: None, timeout_secs: None }
+    }
+
+    /// The value of the series `name` with these label values, in label order.
+    fn value(registry: &Registry, name: &str, labels: &[&str]) -> Option<f64> {
+        exposition::flatten(&registry.gather())
+            .into_iter()
+            .find(|s| s.name == name && s.labels.iter().map(|(_, v)| v.as_str()).eq(labels.iter().copied()))
+            .map(|s| s.value)
+    }
+
+    #[tokio::test]
+    async fn records_how_each_scrape_went() {
+        let url = app().await;
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let registry = Registry::new();
+        let metrics = ScrapeMetrics::register(&registry).unwrap();
+        let federation = Federation::default();
+        let client = Client::new();
+        let gauges = ["queue".to_string(), "label".into(), "users".into(), "missing".into()];
+        let timeout = Duration::from_millis(300);
+        let targets = [
+            target("ok", format!("{}/metrics", url), TargetKind::Prometheus),
+            target("json", format!("{}/json", url), TargetKind::Custom),
+            target("down", format!("{}/down", url), TargetKind::Prometheus),
+            target("garbage", format!("{}/garbage", url), TargetKind::Prometheus),
+            target("slow", format!("{}/slow", url), TargetKind::Prometheus),
+            target("closed", format!("http://{}/metrics", closed), TargetKind::Prometheus),
+        ];
+        for target in &targets {
+            scrape(&client, target, timeout, &gauges, &metrics, &federation).await;
+        }
+        scrape(&client, &targets[0], timeout, &gauges, &metrics, &federation).await;
+
+        let up: Vec<_> = targets.iter().map(|t| value(&registry, "up", &[&t.name])).collect();
+        assert_eq!(up, [Some(1.0), Some(1.0), Some(0.0), Some(0.0), Some(0.0), Some(0.0)]);
+        let status: Vec<_> = targets.iter().map(|t| value(&registry, "scrape_http_status", &[&t.name])).collect();
+        assert_eq!(status, [Some(200.0), Some(200.0), Some(503.0), Some(200.0), Some(0.0), Some(0.0)]);
+        for (target, kind) in [("down", "non_2xx"), ("garbage", "parse"), ("slow", "timeout"), ("closed", "connect")] {
+            assert_eq!(value(&registry, "scrape_errors_total", &[kind, target]), Some(1.0), "{}", target);
+        }
+        assert_eq!(value(&registry, "scrape_requests_total", &["ok"]), Some(2.0));
+        assert!(value(&registry, "scrape_last_success_timestamp_seconds", &["ok"]).is_some());
+        assert!(value(&registry, "scrape_last_success_timestamp_seconds", &["down"]).is_none());
+        assert!(value(&registry, "scrape_duration_seconds", &["slow"]).is_some_and(|d| d >= 0.3));
+
+        // Only what the healthy targets served is federated.
+        let federated = exposition::flatten(&federation.gather(&Registry::new()));
+        let federated: Vec<_> = federated.iter().map(|s| (s.name.as_str(), s.labels[0].1.as_str(), s.value)).collect();
+        assert_eq!(federated, [("jobs", "ok", 3.0), ("queue", "json", 4.0), ("users", "json", 1.5)]);
+
+        metrics.forget("down");
+        assert!(value(&registry, "up", &["down"]).is_none());
+        assert!(value(&registry, "scrape_errors_total", &["non_2xx", "down"]).is_none());
+    }
+}
@@ -0,0 +1,262 @@
+//! Host metrics read from `/proc` and `statvfs`, named after node_exporter's.
+//!
+//! Everything is read fresh on each gather; a file that cannot be read simply
+//! yields no series, so the collector is harmless on non-Linux hosts.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{MetricFamily, MetricType};
+
+use crate::config::SystemConfig;
+use crate::exposition::{family, sample};
+
+/// Filesystem types that never hold user data.
+const IGNORED_FS_TYPES: &[&str] = &[
+    "autofs", "binfmt_misc", "bpf", "cgroup", "cgroup2", "configfs", "debugfs", "devpts", "devtmpfs",
+    "fusectl", "hugetlbfs", "mqueue", "nsfs", "proc", "pstore", "rpc_pipefs", "securityfs", "selinuxfs",
+    "squashfs", "sysfs", "tracefs",
+];
+
+pub struct SystemCollector {
+    config: SystemConfig,
+    proc_path: PathBuf,
+    rootfs_path: PathBuf,
+    descs: Vec<Desc>,
+}
+
+impl SystemCollector {
+    pub fn new(config: SystemConfig) -> prometheus::Result<Self> {
+        let mut descs = Vec::new();
+        let mut desc = |name: &str, help: &str, labels: &[&str]| -> prometheus::Result<()> {
+            let labels = labels.iter().map(|l| l.to_string()).collect();
+            descs.push(Desc::new(name.into(), help.into(), labels, HashMap::new())?);
+            Ok(())
+        };
+        if config.collect_cpu {
+            desc("node_cpu_seconds_total", "Seconds the CPUs spent in each mode", &["cpu", "mode"])?;
+        }
+        if config.collect_ram {
+            desc("node_memory_MemTotal_bytes", "Total usable memory", &[])?;
+            desc("node_memory_MemAvailable_bytes", "Memory available for new workloads", &[])?;
+        }
+        if config.collect_disk {
+            let labels = &["device", "fstype", "mountpoint"];
+            desc("node_filesystem_size_bytes", "Filesystem size", labels)?;
+            desc("node_filesystem_free_bytes", "Free filesystem space", labels)?;
+            desc("node_filesystem_avail_bytes", "Filesystem space available to non-root users", labels)?;
+        }
+        if config.collect_network {
+            desc("node_network_receive_bytes_total", "Bytes received per interface", &["device"])?;
+            desc("node_network_transmit_bytes_total", "Bytes transmitted per interface", &["device"])?;
+        }
+        let proc_path = PathBuf::from(&config.proc_path);
+        let rootfs_path = PathBuf::from(&config.rootfs_path);
+        Ok(Self { config, proc_path, rootfs_path, descs })
+    }
+
+    fn read_proc(&self, file: &str) -> Option<String> {
+        fs::read_to_string(self.proc_path.join(file)).ok()
+    }
+
+    fn cpu(&self, out: &mut Vec<MetricFamily>) {
+        const MODES: &[&str] = &["user", "nice", "system", "idle", "iowait", "irq", "softirq", "steal"];
+        let Some(stat) = self.read_proc("stat") else { return };
+        // SAFETY: sysconf has no preconditions.
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64;
+        let mut metrics = Vec::new();
+        for line in stat.lines() {
+            let mut fields = line.split_whitespace();
+            let Some(cpu) = fields.next().and_then(|f| f.strip_prefix("cpu")) else { continue };
+            if cpu.is_empty() {
+                continue; // the aggregate line
+            }
+            for (mode, value) in MODES.iter().zip(fields) {
+                let Ok(value) = value.parse::<f64>() else { continue };
+                metrics.push(sample(MetricType::COUNTER, &[("cpu", cpu), ("mode", mode)], value / ticks));
+            }
+        }
+        push(out, "node_cpu_seconds_total", "Seconds the CPUs spent in each mode", MetricType::COUNTER, metrics);
+    }
+
+    fn memory(&self, out: &mut Vec<MetricFamily>) {
+        let Some(meminfo) = self.read_proc("meminfo") else { return };
+        for line in meminfo.lines() {
+            let (key, help) = match line.split(':').next() {
+                Some("MemTotal") => ("MemTotal", "Total usable memory"),
+                Some("MemAvailable") => ("MemAvailable", "Memory available for new workloads"),
+                _ => continue,
+            };
+            let Some(kb) = line.split_whitespace().nth(1).and_then(|v| v.parse::<f64>().ok()) else { continue };
+            let name = format!("node_memory_{}_bytes", key);
+            push(out, &name, help, MetricType::GAUGE, vec![sample(MetricType::GAUGE, &[], kb * 1024.0)]);
+        }
+    }
+
+    fn filesystems(&self, out: &mut Vec<MetricFamily>) {
+        // Init's mount table is the host's when `/proc` is the host's, whereas
+        // `/proc/mounts` is our own process's, i.e. the container's.
+        let Some(mounts) = self.read_proc("1/mounts") else { return };
+        let (mut size, mut free, mut avail) = (Vec::new(), Vec::new(), Vec::new());
+        for line in mounts.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [device, mountpoint, fstype, ..] = fields[..] else { continue };
+            if IGNORED_FS_TYPES.contains(&fstype) {
+                continue;
+            }
+            let mountpoint = unescape_mount(mountpoint);
+            let Some(st) = statvfs(&self.rootfs_path.join(mountpoint.trim_start_matches('/'))) else { continue };
+            let labels = [("device", device), ("fstype", fstype), ("mountpoint", mountpoint.as_str())];
+            size.push(sample(MetricType::GAUGE, &labels, st.0));
+            free.push(sample(MetricType::GAUGE, &labels, st.1));
+            avail.push(sample(MetricType::GAUGE, &labels, st.2));
+        }
+        push(out, "node_filesystem_size_bytes", "Filesystem size", MetricType::GAUGE, size);
+        push(out, "node_filesystem_free_bytes", "Free filesystem space", MetricType::GAUGE, free);
+        push(
+            out,
+            "node_filesystem_avail_bytes",
+            "Filesystem space available to non-root users",
+            MetricType::GAUGE,
+            avail,
+        );
+    }
+
+    fn network(&self, out: &mut Vec<MetricFamily>) {
+        let Some(dev) = self.read_proc("net/dev") else { return };
+        let (mut rx, mut tx) = (Vec::new(), Vec::new());
+        for line in dev.lines().skip(2) {
+            let Some((device, counters)) = line.split_once(':') else { continue };
+            let counters: Vec<f64> = counters.split_whitespace().filter_map(|v| v.parse().ok()).collect();
+            if counters.len() < 9 {
+                continue;
+            }
+            let labels = [("device", device.trim())];
+            rx.push(sample(MetricType::COUNTER, &labels, counters[0]));
+            tx.push(sample(MetricType::COUNTER, &labels, counters[8]));
+        }
+        push(out, "node_network_receive_bytes_total", "Bytes received per interface", MetricType::COUNTER, rx);
+        push(out, "node_network_transmit_bytes_total", "Bytes transmitted per interface", MetricType::COUNTER, tx);
+    }
+}
+
+impl Collector for SystemCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut out = Vec::new();
+        if self.config.collect_cpu {
+            self.cpu(&mut out);
+        }
+        if self.config.collect_ram {
+            self.memory(&mut out);
+        }
+        if self.config.collect_disk {
+            self.filesystems(&mut out);
+        }
+        if self.config.collect_network {
+            self.network(&mut out);
+        }
+        out
+    }
+}
+
+fn push(out: &mut Vec<MetricFamily>, name: &str, help: &str, kind: MetricType, metrics: Vec<prometheus::proto::Metric>) {
+    if !metrics.is_empty() {
+        out.push(family(name, help, kind, metrics));
+    }
+}
+
+/// `/proc/mounts` escapes spaces, tabs, newlines and backslashes as `\ooo`.
+fn unescape_mount(path: &str) -> String {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let octal = tail.get(..3).filter(|d| d.iter().all(|c| (b'0'..=b'7').contains(c)));
+        match octal {
+            Some(d) if b == b'\\' && d[0] <= b'3' => {
+                bytes.push((d[0] - b'0') << 6 | (d[1] - b'0') << 3 | (d[2] - b'0'));
+                rest = &tail[3..];
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Size, free and available bytes of the filesystem holding `path`.
+fn statvfs(path: &Path) -> Option<(f64, f64, f64)> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: `c_path` is a valid NUL-terminated string and `st` is a plain
+    // C struct that statvfs fully initialises on success.
+    let st = unsafe {
+        let mut st: libc::statvfs = std::mem::zeroed();
+        if libc::statvfs(c_path.as_ptr(), &mut st) != 0 {
+            return None;
+        }
+        st
+    };
+    let block = st.f_frsize as f64;
+    Some((st.f_blocks as f64 * block, st.f_bfree as f64 * block, st.f_bavail as f64 * block))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exposition::flatten;
+
+    #[test]
+    fn unescapes_mountpoints() {
+        assert_eq!(unescape_mount("/mnt/my\\040disk"), "/mnt/my disk");
+        assert_eq!(unescape_mount("/a\\011b\\012c"), "/a\tb\nc");
+        // An escaped backslash is not the start of another escape.
+        assert_eq!(unescape_mount("/a\\134040"), "/a\\040");
+        assert_eq!(unescape_mount("/caf\\303\\251"), "/caf\u{e9}");
+        assert_eq!(unescape_mount("/odd\\0"), "/odd\\0");
+        assert_eq!(unescape_mount("/odd\\999"), "/odd\\999");
+    }
+
+    #[test]
+    fn reads_the_host_mount_table() {
+        let dir = std::env::temp_dir().join(format!("metrics-exporter-mounts-{}", std::process::id()));
+        let (proc_path, rootfs_path) = (dir.join("proc"), dir.join("rootfs"));
+        fs::create_dir_all(proc_path.join("1")).unwrap();
+        fs::create_dir_all(rootfs_path.join("mnt/my disk")).unwrap();
+        fs::write(proc_path.join("mounts"), "overlay / overlay rw 0 0\n").unwrap();
+        fs::write(
+            proc_path.join("1/mounts"),
+            "/dev/sda1 / ext4 rw 0 0\n\
+             proc /proc proc rw 0 0\n\
+             /dev/sdb1 /mnt/my\\040disk xfs rw 0 0\n\
+             /dev/sdc1 /gone ext4 rw 0 0\n",
+        )
+        .unwrap();
+        let config = SystemConfig {
+            collect_cpu: false,
+            collect_ram: false,
+            collect_network: false,
+            proc_path: proc_path.display().to_string(),
+            rootfs_path: rootfs_path.display().to_string(),
+            ..SystemConfig::default()
+        };
+        let families = SystemCollector::new(config).unwrap().collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let size: Vec<_> = flatten(&families)
+            .into_iter()
+            .filter(|s| s.name == "node_filesystem_size_bytes")
+            .map(|s| s.labels.into_iter().map(|(_, v)| v).collect::<Vec<_>>())
+            .collect();
+        assert_eq!(size, [["/dev/sda1", "ext4", "/"], ["/dev/sdb1", "xfs", "/mnt/my disk"]]);
+        assert_eq!(families.len(), 3);
+    }
+}
@@ -0,0 +1,304 @@
+//! The live set of scrape targets: `[scrape]` targets plus, when enabled,
+//! those discovered from the app registry.
+//!
+//! Both sources are re-read on SIGHUP or when a watched file changes. Only
+//! targets that were added, removed or changed are restarted; everything else
+//! keeps its schedule. Settings outside `[scrape]`, `[discovery]` and
+//! `metrics.custom_gauges` still need a restart.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use reqwest::Client;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::config::{Config, DiscoveryConfig, TargetConfig, TargetKind};
+use crate::{discovery, scrape, AppState};
+
+/// A target together with its resolved schedule and, for custom targets, the
+/// gauges read from it, so a change to any of those also counts as a change.
+#[derive(Clone, PartialEq)]
+struct Job {
+    target: TargetConfig,
+    interval: Duration,
+    timeout: Duration,
+    custom_gauges: Vec<String>,
+}
+
+pub struct Scheduler {
+    state: Arc<AppState>,
+    client: Client,
+    permits: Arc<Semaphore>,
+    discovery: DiscoveryConfig,
+    running: HashMap<String, (Job, JoinHandle<()>)>,
+}
+
+/// Static targets followed by discovered ones. A discovered target whose name
+/// is already taken is skipped.
+pub fn desired(config: &Config) -> Result<Vec<TargetConfig>> {
+    let mut targets = config.scrape.targets.clone();
+    if config.discovery.enabled {
+        for target in discovery::discover(&config.discovery)? {
+            if targets.iter().any(|t| t.name == target.name) {
+                eprintln!("discovery: target {} is already configured, skipping", target.name);
+                continue;
+            }
+            targets.push(target);
+        }
+    }
+    Ok(targets)
+}
+
+impl Scheduler {
+    /// The semaphore bounding concurrent scrapes is sized once, at startup.
+    pub fn new(state: Arc<AppState>) -> Self {
+        let permits = Arc::new(Semaphore::new(state.config.scrape.max_concurrency));
+        let discovery = state.config.discovery.clone();
+        Self { state, client: Client::new(), permits, discovery, running: HashMap::new() }
+    }
+
+    /// Bring the running tasks in line with `targets`, as configured by
+    /// `config`.
+    pub async fn apply(&mut self, config: &Config, targets: Vec<TargetConfig>) {
+        let scrape = &config.scrape;
+        let wanted: HashMap<String, Job> = targets
+            .into_iter()
+            .map(|target| {
+                let custom_gauges = match target.kind {
+                    TargetKind::Custom => config.metrics.custom_gauges.clone(),
+                    _ => Vec::new(),
+                };
+                let job = Job { interval: target.interval(scrape), timeout: target.timeout(scrape), custom_gauges, target };
+                (job.target.name.clone(), job)
+            })
+            .collect();
+
+        let stale: Vec<String> = self
+            .running
+            .iter()
+            .filter(|(name, (job, _))| wanted.get(*name) != Some(job))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &stale {
+            let (_, handle) = self.running.remove(name).expect("name taken from running");
+            handle.abort();
+            // Wait for the abort so a scrape in flight cannot re-add the target.
+            let _ = handle.await;
+            if !wanted.contains_key(name) {
+                self.state.federation.remove(name);
+                self.state.scrape.forget(name);
+                println!("stopped scraping {}", name);
+            }
+        }
+
+        for (name, job) in wanted {
+            if self.running.contains_key(&name) {
+                continue;
+            }
+            println!("scraping {} every {:?} from {}", name, job.interval, job.target.url);
+            let task = scrape_target(self.state.clone(), self.client.clone(), self.permits.clone(), job.clone());
+            self.running.insert(name, (job, tokio::spawn(task)));
+        }
+    }
+
+    /// Reload on SIGHUP or when the config file or app registry changes.
+    pub async fn watch(mut self) {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => Some(hangup),
+            Err(e) => {
+                eprintln!("cannot listen for SIGHUP, relying on file changes only: {}", e);
+                None
+            }
+        };
+        let mut ticker = time::interval(Duration::from_secs(self.discovery.watch_interval_secs));
+        ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        let mut seen = self.fingerprint();
+        loop {
+            tokio::select! {
+                Some(()) = async { hangup.as_mut()?.recv().await } => println!("SIGHUP received, reloading targets"),
+                _ = ticker.tick() => {
+                    let current = self.fingerprint();
+                    if current == seen {
+                        continue;
+                    }
+                    seen = current;
+                    println!("configuration changed, reloading targets");
+                }
+            }
+            match Config::load().and_then(|config| Ok((desired(&config)?, config))) {
+                Ok((targets, config)) => {
+                    self.apply(&config, targets).await;
+                    self.discovery = config.discovery;
+                    seen = self.fingerprint();
+                }
+                Err(e) => eprintln!("reload failed, keeping current targets: {:#}", e),
+            }
+        }
+    }
+
+    /// Modification time and size of every watched file.
+    fn fingerprint(&self) -> Vec<(PathBuf, Option<SystemTime>, u64)> {
+        let mut files: Vec<PathBuf> = Config::file_path().into_iter().collect();
+        if self.discovery.enabled {
+            files.extend(discovery::registry_files(Path::new(&self.discovery.app_registry_dir)).unwrap_or_default());
+        }
+        files
+            .into_iter()
+            .map(|path| {
+                let meta = fs::metadata(&path).ok();
+                let modified = meta.as_ref().and_then(|m| m.modified().ok());
+                let len = meta.map_or(0, |m| m.len());
+                (path, modified, len)
+            })
+            .collect()
+    }
+}
+
+async fn scrape_target(state: Arc<AppState>, client: Client, permits: Arc<Semaphore>, job: Job) {
+    let mut ticker = time::interval(job.interval);
+    ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let _permit = permits.acquire().await.expect("semaphore is never closed");
+        scrape::scrape(&client, &job.target, job.timeout, &job.custom_gauges, &state.scrape, &state.federation).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use axum::extract::{Path as UrlPath, State};
+    use axum::routing::get;
+    use axum::Router;
+    use prometheus::Registry;
+
+    use super::*;
+    use crate::exposition;
+
+    /// Scrapes served per path, and the most ever in flight at once.
+    #[derive(Default)]
+    struct Hits {
+        by_path: Mutex<HashMap<String, usize>>,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    impl Hits {
+        fn get(&self, path: &str) -> usize {
+            self.by_path.lock().unwrap().get(path).copied().unwrap_or(0)
+        }
+    }
+
+    async fn app(hits: Arc<Hits>) -> String {
+        async fn serve(State(hits): State<Arc<Hits>>, UrlPath(path): UrlPath<String>) -> String {
+            let now = hits.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            hits.max_in_flight.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            hits.in_flight.fetch_sub(1, Ordering::SeqCst);
+            *hits.by_path.lock().unwrap().entry(path.clone()).or_default() += 1;
+            format!("served{{path=\"{}\"}} 1\n", path)
+        }
+        let app = Router::new().route("/:path", get(serve)).with_state(hits);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    fn target(name: &str, url: String) -> TargetConfig {
+        TargetConfig { name: name.into(), url, kind: TargetKind::Prometheus, interval_secs: None, timeout_secs: None }
+    }
+
+    fn config(max_concurrency: usize) -> Config {
+        let mut config = Config::default();
+        config.scrape.interval_secs = 60;
+        config.scrape.max_concurrency = max_concurrency;
+        config
+    }
+
+    async fn settle(hits: &Hits, done: impl Fn(&Hits) -> bool) {
+        for _ in 0..200 {
+            if done(hits) {
+                // Let the scrape's bookkeeping land too.
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("targets were not scraped: {:?}", hits.by_path.lock().unwrap());
+    }
+
+    fn up(state: &AppState) -> Vec<(String, f64)> {
+        let families = state.federation.gather(&state.registry);
+        let series = exposition::flatten(&families).into_iter().filter(|s| s.name == "up");
+        series.map(|s| (s.labels[0].1.clone(), s.value)).collect()
+    }
+
+    #[test]
+    fn discovered_targets_do_not_replace_configured_ones() {
+        let dir = std::env::temp_dir().join(format!("metrics-exporter-desired-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("api.yaml"), "name: api\nbackend: { port: 8000 }\n").unwrap();
+        fs::write(dir.join("web.yaml"), "name: web\nbackend: { port: 3000 }\n").unwrap();
+        let mut config = Config::default();
+        config.scrape.targets = vec![target("api_backend", "http://api:9999/metrics".into())];
+        assert_eq!(desired(&config).unwrap().len(), 1);
+
+        config.discovery.enabled = true;
+        config.discovery.app_registry_dir = dir.display().to_string();
+        let targets = desired(&config).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let targets: Vec<_> = targets.iter().map(|t| (t.name.as_str(), t.url.as_str())).collect();
+        assert_eq!(
+            targets,
+            [("api_backend", "http://api:9999/metrics"), ("web_backend", "http://web_backend:3000/metrics")]
+        );
+    }
+
+    #[tokio::test]
+    async fn restarts_only_changed_targets() {
+        let hits = Arc::new(Hits::default());
+        let url = app(hits.clone()).await;
+        let config = config(8);
+        let state = crate::tests::state(config.clone());
+        let mut scheduler = Scheduler::new(state.clone());
+
+        scheduler.apply(&config, vec![target("a", format!("{}/a", url)), target("b", format!("{}/b", url))]).await;
+        settle(&hits, |h| h.get("a") == 1 && h.get("b") == 1).await;
+        assert_eq!(up(&state), [("a".to_string(), 1.0), ("b".to_string(), 1.0)]);
+
+        // A target starts with a scrape, so only the changed one is scraped again.
+        scheduler.apply(&config, vec![target("a", format!("{}/a", url)), target("b", format!("{}/b2", url))]).await;
+        settle(&hits, |h| h.get("b2") == 1).await;
+        assert_eq!((hits.get("a"), hits.get("b")), (1, 1));
+
+        scheduler.apply(&config, vec![target("a", format!("{}/a", url))]).await;
+        assert_eq!(up(&state), [("a".to_string(), 1.0)]);
+        let federated = exposition::flatten(&state.federation.gather(&Registry::new()));
+        assert!(federated.iter().all(|s| s.labels.contains(&("target".into(), "a".into()))));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!((hits.get("a"), hits.get("b2")), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn bounds_scrapes_in_flight() {
+        let hits = Arc::new(Hits::default());
+        let url = app(hits.clone()).await;
+        let config = config(2);
+        let mut scheduler = Scheduler::new(crate::tests::state(config.clone()));
+        let targets = (0..5).map(|i| target(&format!("t{}", i), format!("{}/t{}", url, i))).collect();
+        scheduler.apply(&config, targets).await;
+        settle(&hits, |h| (0..5).all(|i| h.get(&format!("t{}", i)) == 1)).await;
+        assert_eq!(hits.max_in_flight.load(Ordering::SeqCst), 2);
+    }
+}
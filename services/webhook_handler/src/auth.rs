@@ -0,0 +1,22 @@
+//! The bearer token guarding the jobs and deliveries APIs. They hand out
+//! deploy output and payloads and can cancel or rerun deploys, while the
+//! port has to stay reachable for the forges.
+
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+
+use crate::config::ServerConfig;
+use crate::providers::constant_time_eq;
+
+/// The response refusing a request without `Authorization: Bearer <token>`,
+/// if it is refused. Without a token configured every request is.
+pub fn unauthorized(server: &ServerConfig, headers: &HeaderMap) -> Option<Response> {
+    let Some(token) = &server.api_token else {
+        return Some((StatusCode::FORBIDDEN, "API is disabled, set server.api_token\n").into_response());
+    };
+    let given = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()).and_then(|v| v.strip_prefix("Bearer "));
+    match given {
+        Some(given) if constant_time_eq(given.trim().as_bytes(), token.as_bytes()) => None,
+        _ => Some((StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")], "unauthorized\n").into_response()),
+    }
+}
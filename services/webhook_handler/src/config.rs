@@ -0,0 +1,580 @@
+//! Typed view of `config.toml`, with environment variables as overrides.
+
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    /// Forges accepted, each on its own path. Empty means GitHub on `/`.
+    pub providers: Vec<ProviderConfig>,
+    pub actions: ActionsConfig,
+    pub deploy: DeployConfig,
+    pub jobs: JobsConfig,
+    pub notify: NotifyConfig,
+    pub deliveries: DeliveriesConfig,
+    /// How actions are confined, unless their route sets its own.
+    pub sandbox: SandboxConfig,
+    /// Checked in order; the first matching route handles the event.
+    pub routes: Vec<RouteConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub port: u16,
+    /// Shared secret for providers that do not set their own.
+    pub secret: String,
+    /// Accept deliveries signed with the built-in or a placeholder secret.
+    /// Anyone can sign those, so it is only meant for local testing.
+    pub allow_default_secret: bool,
+    /// How long delivery IDs are remembered to reject replays; 0 disables.
+    pub replay_ttl_secs: u64,
+    /// How far a signed timestamp may be from our clock, either way.
+    pub max_skew_secs: u64,
+    /// Bearer token for the `/jobs` and `/deliveries` APIs, which are off
+    /// without one. Overridden by `API_TOKEN`.
+    pub api_token: Option<String>,
+}
+
+/// The secret used when none is configured.
+pub const DEFAULT_SECRET: &str = "secret";
+
+/// Secrets found in examples, and so no secret at all.
+const PLACEHOLDER_SECRETS: &[&str] = &[DEFAULT_SECRET, "change_me"];
+
+/// Whether `secret` is empty, the built-in one or a known placeholder.
+pub fn is_placeholder_secret(secret: &str) -> bool {
+    secret.is_empty() || PLACEHOLDER_SECRETS.contains(&secret)
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            port: 9000,
+            secret: DEFAULT_SECRET.into(),
+            allow_default_secret: false,
+            replay_ttl_secs: 24 * 60 * 60,
+            max_skew_secs: 300,
+            api_token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    /// `X-Hub-Signature-256`.
+    Github,
+    /// `X-Gitlab-Token`, the secret itself.
+    Gitlab,
+    /// `X-Gitea-Signature`.
+    Gitea,
+    /// `X-Forgejo-Signature`.
+    Forgejo,
+    /// `X-Hub-Signature`, as sent by Bitbucket Cloud and Data Center.
+    Bitbucket,
+    /// Hex HMAC of the body in a configurable header.
+    Generic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderConfig {
+    pub kind: ProviderKind,
+    /// Where deliveries from this provider are posted, e.g. `/gitlab`.
+    pub path: String,
+    /// Defaults to `server.secret`.
+    pub secret: Option<String>,
+    /// Generic only: the header carrying the signature.
+    pub header: Option<String>,
+    /// Generic only.
+    #[serde(default = "default_algorithm")]
+    pub algorithm: Algorithm,
+    /// Generic only: stripped from the header value before comparing, e.g. `sha256=`.
+    #[serde(default)]
+    pub prefix: String,
+    /// Generic only: the header naming the event. Without one every delivery
+    /// is a `push`.
+    pub event_header: Option<String>,
+    /// Generic only: the header carrying a unique ID per delivery, used to
+    /// reject replays.
+    pub delivery_header: Option<String>,
+    /// Generic only: the header carrying the Unix time the delivery was
+    /// signed. When set, the signature covers `<timestamp>.<body>` and
+    /// deliveries outside `server.max_skew_secs` are rejected.
+    pub timestamp_header: Option<String>,
+}
+
+fn default_algorithm() -> Algorithm {
+    Algorithm::Sha256
+}
+
+impl ProviderConfig {
+    /// What runs when `[[providers]]` is not configured.
+    fn github() -> Self {
+        Self {
+            kind: ProviderKind::Github,
+            path: "/".into(),
+            secret: None,
+            header: None,
+            algorithm: default_algorithm(),
+            prefix: String::new(),
+            event_header: None,
+            delivery_header: None,
+            timestamp_header: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ActionsConfig {
+    /// Run for every event when no routes are configured.
+    pub script: Option<String>,
+}
+
+/// Limits on the commands a job runs: its script, or its deploy steps'
+/// git and docker commands, and a rollback's.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SandboxConfig {
+    /// The time a job gets for all its commands, health check and rollback.
+    /// Past it the running command is killed, with everything it started.
+    pub timeout_secs: u64,
+    /// Variables passed on from our own environment; the rest, notably
+    /// `WEBHOOK_SECRET`, are withheld. The event's variables are always set.
+    pub env: Vec<String>,
+    /// Working directory of scripts. Deploy steps run in `project_dir`.
+    pub cwd: Option<String>,
+    /// User and group to run as. Changing them needs us to run as root.
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// `RLIMIT_CPU`, in seconds of CPU time.
+    pub cpu_secs: Option<u64>,
+    /// `RLIMIT_AS`, the address space each process may map.
+    pub memory_mb: Option<u64>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 3600,
+            env: ["PATH", "HOME", "LANG", "TZ"].map(String::from).to_vec(),
+            cwd: None,
+            uid: None,
+            gid: None,
+            cpu_secs: None,
+            memory_mb: None,
+        }
+    }
+}
+
+/// Where the built-in deploy steps find the stack.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeployConfig {
+    /// A git checkout of this repository. Steps run here and the paths below
+    /// are relative to it.
+    pub project_dir: String,
+    pub registry_dir: String,
+    /// What `compose_file` entries in the registry are relative to.
+    pub compose_dir: String,
+    /// Command writing a fresh `nginx.conf` from the registry.
+    pub nginx_generate: Vec<String>,
+    /// Container reloaded after the config is regenerated.
+    pub nginx_container: String,
+}
+
+impl Default for DeployConfig {
+    fn default() -> Self {
+        Self {
+            project_dir: ".".into(),
+            registry_dir: "compose/app-registry".into(),
+            compose_dir: "compose".into(),
+            nginx_generate: vec!["python3".into(), "scripts/generate-nginx.py".into()],
+            nginx_container: "nginx".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Step {
+    /// `git fetch` and check out the pushed commit.
+    Checkout,
+    /// `docker compose pull` for the app.
+    Pull,
+    /// `docker compose build` for the app.
+    Build,
+    /// `docker compose up -d` for the app.
+    Up,
+    /// Regenerate `nginx.conf`, check it and reload nginx.
+    Nginx,
+}
+
+impl Step {
+    pub fn name(self) -> &'static str {
+        match self {
+            Step::Checkout => "checkout",
+            Step::Pull => "pull",
+            Step::Build => "build",
+            Step::Up => "up",
+            Step::Nginx => "nginx",
+        }
+    }
+}
+
+/// A built-in deploy of one registry app, run instead of a script.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeployRoute {
+    /// `name` of an app in the registry. Only the nginx step can do without.
+    pub app: Option<String>,
+    #[serde(default = "default_steps")]
+    pub steps: Vec<Step>,
+}
+
+fn default_steps() -> Vec<Step> {
+    vec![Step::Checkout, Step::Pull, Step::Build, Step::Up, Step::Nginx]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// Finished jobs beyond this many are forgotten, oldest first.
+    pub max_jobs: usize,
+    /// Output kept per job; the rest is dropped.
+    pub max_log_bytes: usize,
+    /// Runs of the same action allowed at once; further jobs wait in its queue.
+    pub concurrency: usize,
+    /// How long a queued job waits for a newer event before it may start.
+    pub debounce_ms: u64,
+    /// Also kill running jobs of an action when a newer event arrives for it,
+    /// not just the ones still queued.
+    pub cancel_running: bool,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self { max_jobs: 100, max_log_bytes: 1 << 20, concurrency: 1, debounce_ms: 0, cancel_running: false }
+    }
+}
+
+/// The log of received deliveries behind `/deliveries`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeliveriesConfig {
+    /// JSON-lines file the log is kept in across restarts. Unset keeps it in
+    /// memory only.
+    pub file: Option<String>,
+    /// Deliveries beyond this many are forgotten, oldest first.
+    pub max_deliveries: usize,
+    /// Deliveries that failed verification beyond this many are forgotten.
+    /// They do not count towards `max_deliveries`.
+    pub max_rejected: usize,
+    /// Deliveries older than this are forgotten.
+    pub max_age_days: u32,
+}
+
+impl Default for DeliveriesConfig {
+    fn default() -> Self {
+        Self { file: None, max_deliveries: 1000, max_rejected: 100, max_age_days: 30 }
+    }
+}
+
+/// Where job progress is reported.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// Our own address as seen by developers, e.g. `https://deploy.example.com`.
+    /// Statuses and messages link to the job's page under it.
+    pub public_url: Option<String>,
+    pub status: Option<StatusConfig>,
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForgeApi {
+    Github,
+    /// Gitea, and Forgejo which serves the same API.
+    Gitea,
+}
+
+/// Commit or deployment statuses on the pushed commit.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatusConfig {
+    pub kind: ForgeApi,
+    /// e.g. `https://api.github.com` or `https://gitea.example.com/api/v1`.
+    pub api_url: String,
+    /// Overridden by `NOTIFY_TOKEN`.
+    #[serde(default)]
+    pub token: String,
+    /// Names the status among the commit's checks.
+    #[serde(default = "default_context")]
+    pub context: String,
+    /// Report through GitHub deployments instead of commit statuses.
+    #[serde(default)]
+    pub deployments: bool,
+    /// The deployment environment, with `deployments`.
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    /// Glob on the repository's full name. Jobs for other repositories, e.g.
+    /// mirrors delivered by another forge, are not reported.
+    pub repository: Option<String>,
+}
+
+fn default_context() -> String {
+    "deploy".into()
+}
+
+fn default_environment() -> String {
+    "production".into()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    /// `{"text": ...}`, also understood by Mattermost and Rocket.Chat.
+    Slack,
+    /// `{"content": ...}`.
+    Discord,
+    /// The job record as served by `/jobs/{id}`, without its output.
+    Json,
+}
+
+/// A chat or generic JSON webhook told about job status changes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub format: MessageFormat,
+    /// Job statuses worth a message.
+    #[serde(default = "default_notify_on")]
+    pub on: Vec<String>,
+}
+
+fn default_notify_on() -> Vec<String> {
+    vec!["succeeded".into(), "failed".into(), "rolled_back".into()]
+}
+
+/// Values `on` accepts.
+const JOB_STATUSES: &[&str] = &["queued", "running", "succeeded", "failed", "cancelled", "rolled_back"];
+
+/// Matches an event to an action. Unset fields match anything; globs accept
+/// `*` (any run of characters) and `?` (one character).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub name: String,
+    /// Event names, e.g. `push` or `release`. GitLab and Bitbucket events
+    /// are renamed to match: `Push Hook` and `repo:push` are both `push`.
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Glob on the repository's full name, e.g. `yourorg/*`.
+    pub repository: Option<String>,
+    /// Glob on the branch of a `refs/heads/...` ref, e.g. `release/*`.
+    pub branch: Option<String>,
+    /// Glob on the full ref, e.g. `refs/tags/v*`.
+    #[serde(rename = "ref")]
+    pub ref_: Option<String>,
+    /// Script to run for matching events.
+    pub action: Option<String>,
+    /// Built-in deploy steps to run instead of a script.
+    pub deploy: Option<DeployRoute>,
+    /// Polled once the action succeeds, to confirm the app came up.
+    pub health_check: Option<HealthCheck>,
+    /// Script run if the health check never passes, with `SHA` set to the
+    /// last commit this route deployed successfully. Deploy routes without one
+    /// redeploy that commit with their own steps.
+    pub rollback: Option<String>,
+    /// Replaces the top-level `[sandbox]` for this route's jobs.
+    pub sandbox: Option<SandboxConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheck {
+    /// Healthy once a GET answers with a 2xx status.
+    pub url: String,
+    /// How long the app gets to become healthy.
+    #[serde(default = "default_health_timeout")]
+    pub timeout_secs: u64,
+    #[serde(default = "default_health_interval")]
+    pub interval_secs: u64,
+}
+
+fn default_health_timeout() -> u64 {
+    60
+}
+
+fn default_health_interval() -> u64 {
+    2
+}
+
+impl RouteConfig {
+    /// The route's script, or `deploy <app>` for built-in deploys.
+    pub fn action_name(&self) -> String {
+        match (&self.action, &self.deploy) {
+            (Some(script), _) => script.clone(),
+            (None, Some(deploy)) => format!("deploy {}", deploy.app.as_deref().unwrap_or("nginx")),
+            (None, None) => unreachable!("validated: routes have an action or a deploy"),
+        }
+    }
+}
+
+impl Config {
+    /// Load the file named by `CONFIG_PATH` (default `config.toml`), apply
+    /// env overrides and validate the result. A missing default file is not an
+    /// error; a missing file that was asked for explicitly is.
+    pub fn load() -> Result<Self> {
+        let path = match env::var("CONFIG_PATH") {
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => Some(PathBuf::from("config.toml")).filter(|p| p.exists()),
+        };
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        if config.providers.is_empty() {
+            config.providers.push(ProviderConfig::github());
+        }
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        if let Ok(port) = env::var("WEBHOOK_PORT") {
+            self.server.port = port.parse().with_context(|| format!("WEBHOOK_PORT={:?}", port))?;
+        }
+        if let Ok(secret) = env::var("WEBHOOK_SECRET") {
+            self.server.secret = secret;
+        }
+        if let Ok(script) = env::var("ACTION_SCRIPT") {
+            self.actions.script = Some(script);
+        }
+        if let (Ok(token), Some(status)) = (env::var("NOTIFY_TOKEN"), &mut self.notify.status) {
+            status.token = token;
+        }
+        if let Ok(token) = env::var("API_TOKEN") {
+            self.server.api_token = Some(token);
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.jobs.concurrency == 0 {
+            bail!("jobs: concurrency must be at least 1");
+        }
+        if self.deploy.nginx_generate.is_empty() {
+            bail!("deploy: nginx_generate must not be empty");
+        }
+        validate_sandbox("sandbox", &self.sandbox)?;
+        if self.server.api_token.as_deref() == Some("") {
+            bail!("server: api_token must not be empty");
+        }
+        if let Some(status) = &self.notify.status {
+            reqwest::Url::parse(&status.api_url)
+                .with_context(|| format!("notify.status: invalid api_url {:?}", status.api_url))?;
+            if status.token.is_empty() {
+                bail!("notify.status: token must be set, in the file or NOTIFY_TOKEN");
+            }
+            if status.deployments && status.kind != ForgeApi::Github {
+                bail!("notify.status: deployments are only supported on GitHub");
+            }
+        }
+        for (i, webhook) in self.notify.webhooks.iter().enumerate() {
+            let at = format!("notify.webhooks[{}]", i);
+            reqwest::Url::parse(&webhook.url).with_context(|| format!("{}: invalid url {:?}", at, webhook.url))?;
+            if let Some(status) = webhook.on.iter().find(|s| !JOB_STATUSES.contains(&s.as_str())) {
+                bail!("{}: unknown job status {:?}, expected one of {}", at, status, JOB_STATUSES.join(", "));
+            }
+        }
+        for (i, provider) in self.providers.iter().enumerate() {
+            let at = format!("providers[{}] ({})", i, provider.path);
+            if !provider.path.starts_with('/') {
+                bail!("{}: path must start with /", at);
+            }
+            if provider.path == "/jobs" || provider.path.starts_with("/jobs/") {
+                bail!("{}: path is reserved for the jobs API", at);
+            }
+            if provider.path == "/deliveries" || provider.path.starts_with("/deliveries/") {
+                bail!("{}: path is reserved for the deliveries API", at);
+            }
+            if self.providers[..i].iter().any(|p| p.path == provider.path) {
+                bail!("{}: duplicate path", at);
+            }
+            if provider.kind == ProviderKind::Generic && provider.header.is_none() {
+                bail!("{}: generic providers need a header", at);
+            }
+        }
+        for (i, route) in self.routes.iter().enumerate() {
+            let at = format!("routes[{}]", i);
+            if route.name.trim().is_empty() {
+                bail!("{}: name must not be empty", at);
+            }
+            if self.routes[..i].iter().any(|r| r.name == route.name) {
+                bail!("{}: duplicate route name {:?}", at, route.name);
+            }
+            match (&route.action, &route.deploy) {
+                (Some(action), None) if action.trim().is_empty() => {
+                    bail!("{} ({}): action must not be empty", at, route.name)
+                }
+                (Some(_), None) => {}
+                (None, Some(deploy)) => {
+                    if deploy.steps.is_empty() {
+                        bail!("{} ({}): deploy needs at least one step", at, route.name);
+                    }
+                    let needs_app = deploy.steps.iter().any(|s| matches!(s, Step::Pull | Step::Build | Step::Up));
+                    if needs_app && deploy.app.is_none() {
+                        bail!("{} ({}): deploy needs an app for its compose steps", at, route.name);
+                    }
+                }
+                _ => bail!("{} ({}): set exactly one of action and deploy", at, route.name),
+            }
+            if let Some(check) = &route.health_check {
+                reqwest::Url::parse(&check.url)
+                    .with_context(|| format!("{} ({}): invalid health_check url {:?}", at, route.name, check.url))?;
+                if check.interval_secs == 0 {
+                    bail!("{} ({}): health_check interval_secs must be at least 1", at, route.name);
+                }
+            } else if route.rollback.is_some() {
+                bail!("{} ({}): rollback needs a health_check", at, route.name);
+            }
+            if let Some(sandbox) = &route.sandbox {
+                validate_sandbox(&format!("{} ({}): sandbox", at, route.name), sandbox)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn validate_sandbox(at: &str, sandbox: &SandboxConfig) -> Result<()> {
+    if sandbox.timeout_secs == 0 {
+        bail!("{}: timeout_secs must be at least 1", at);
+    }
+    if sandbox.cpu_secs == Some(0) || sandbox.memory_mb == Some(0) {
+        bail!("{}: cpu_secs and memory_mb must be at least 1", at);
+    }
+    Ok(())
+}
@@ -0,0 +1,552 @@
+//! Every delivery received, kept with its headers, body and what became of
+//! it, so a deploy can be rerun later with the original payload.
+//!
+//! Deliveries are held in memory, newest last, and with `file` set are also
+//! appended to a JSON-lines file that is replayed on startup. Past
+//! `max_deliveries` or `max_age_days` the oldest are forgotten, and the file
+//! is rewritten once it holds more forgotten ones than retained. The file is
+//! written by a background task, in the order deliveries were recorded, so
+//! handlers never wait on the disk.
+//!
+//! Anyone can post to the webhook paths, so deliveries that fail verification
+//! keep only a prefix of their headers and body, and are capped separately by
+//! `max_rejected` so they cannot push verified ones out.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use axum::extract::{Path as UrlPath, State};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::mpsc;
+
+use crate::auth::unauthorized;
+use crate::config::{DeliveriesConfig, ProviderConfig};
+use crate::providers;
+use crate::{dispatch, AppState};
+
+/// What is kept of a rejected delivery's body and of each header value.
+const REJECTED_BODY_BYTES: usize = 1024;
+const REJECTED_HEADER_BYTES: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verification {
+    Verified,
+    MissingSignature,
+    BadSignature,
+    /// The provider's delivery ID was seen before.
+    Duplicate,
+    /// Refused because the provider's secret is still the built-in one.
+    NoSecret,
+    /// Resubmitted through `/deliveries/{id}/replay`; not signed again.
+    ManualReplay,
+}
+
+impl Verification {
+    fn accepted(self) -> bool {
+        matches!(self, Verification::Verified | Verification::ManualReplay)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    pub id: u64,
+    pub received_at: DateTime<Utc>,
+    /// The provider path it was posted to.
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    /// The body as text, or base64 if it is not UTF-8.
+    pub body: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub body_base64: bool,
+    /// The headers and body were cut short, as for every rejected delivery.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    pub verification: Verification,
+    pub event: Option<String>,
+    pub route: Option<String>,
+    pub action: Option<String>,
+    pub job_id: Option<u64>,
+    /// The delivery this one replayed.
+    pub replay_of: Option<u64>,
+}
+
+impl Delivery {
+    /// A record of a delivery to `provider`, to be filled in as it is
+    /// handled. The provider's signature header, which for some carries the
+    /// secret itself, and `Authorization` are not stored.
+    pub fn new(provider: &ProviderConfig, headers: &HeaderMap, body: &[u8], verification: Verification) -> Self {
+        let (header_limit, body_limit) =
+            if verification.accepted() { (usize::MAX, usize::MAX) } else { (REJECTED_HEADER_BYTES, REJECTED_BODY_BYTES) };
+        let mut truncated = false;
+        // How many of `len` bytes to keep.
+        let mut keep = |len: usize, limit: usize| {
+            truncated |= len > limit;
+            len.min(limit)
+        };
+        let headers = headers
+            .iter()
+            .filter(|(name, _)| {
+                *name != header::AUTHORIZATION && !name.as_str().eq_ignore_ascii_case(providers::signature_header(provider))
+            })
+            .map(|(name, value)| {
+                let value = &value.as_bytes()[..keep(value.len(), header_limit)];
+                (name.to_string(), String::from_utf8_lossy(value).into_owned())
+            })
+            .collect();
+        let body = &body[..keep(body.len(), body_limit)];
+        let (body, body_base64) = match std::str::from_utf8(body) {
+            Ok(text) => (text.to_string(), false),
+            Err(_) => (BASE64_STANDARD.encode(body), true),
+        };
+        Self {
+            id: 0,
+            received_at: Utc::now(),
+            path: provider.path.clone(),
+            headers,
+            body,
+            body_base64,
+            truncated,
+            verification,
+            event: None,
+            route: None,
+            action: None,
+            job_id: None,
+            replay_of: None,
+        }
+    }
+
+    /// The body exactly as received.
+    fn body_bytes(&self) -> Result<Vec<u8>> {
+        if self.body_base64 {
+            Ok(BASE64_STANDARD.decode(&self.body)?)
+        } else {
+            Ok(self.body.clone().into_bytes())
+        }
+    }
+
+    fn header_map(&self) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::try_from(name.as_str()), HeaderValue::try_from(value.as_str())) {
+                map.append(name, value);
+            }
+        }
+        map
+    }
+}
+
+/// A delivery without its headers and body, for listings.
+#[derive(Serialize)]
+struct Summary<'a> {
+    id: u64,
+    received_at: DateTime<Utc>,
+    path: &'a str,
+    verification: Verification,
+    event: &'a Option<String>,
+    route: &'a Option<String>,
+    action: &'a Option<String>,
+    job_id: Option<u64>,
+    replay_of: Option<u64>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Verified deliveries and replays.
+    accepted: VecDeque<Delivery>,
+    rejected: VecDeque<Delivery>,
+    next_id: u64,
+    /// Lines in the file for deliveries no longer retained.
+    stale_lines: usize,
+}
+
+/// A change to the file, queued for the writer.
+enum FileOp {
+    Append(Delivery),
+    /// Replace the file with these deliveries.
+    Rewrite(Vec<Delivery>),
+}
+
+#[derive(Clone)]
+pub struct DeliveryLog {
+    inner: Arc<Mutex<Inner>>,
+    config: DeliveriesConfig,
+    /// `None` without a file.
+    tx: Option<mpsc::UnboundedSender<FileOp>>,
+}
+
+impl DeliveryLog {
+    /// A log, with a writer for its file if one is configured. The writer
+    /// is a blocking task, so this must be called within the runtime.
+    pub fn new(config: DeliveriesConfig) -> Self {
+        let tx = config.file.clone().map(|path| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::task::spawn_blocking(move || write_file(&path, rx));
+            tx
+        });
+        Self { inner: Arc::default(), config, tx }
+    }
+
+    /// Replay the file, then have it rewritten without expired deliveries.
+    pub fn restore(&self) -> Result<()> {
+        let Some(path) = &self.config.file else { return Ok(()) };
+        if !Path::new(path).exists() {
+            return Ok(());
+        }
+        let file = File::open(path).with_context(|| format!("opening {}", path))?;
+        let mut inner = self.inner.lock().unwrap();
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.with_context(|| format!("reading {}", path))?;
+            // The last line may be torn if we were killed mid-write.
+            match serde_json::from_str::<Delivery>(&line) {
+                Ok(delivery) => {
+                    inner.next_id = inner.next_id.max(delivery.id);
+                    inner.push(delivery);
+                }
+                Err(e) => eprintln!("deliveries: ignoring {} line {}: {}", path, n + 1, e),
+            }
+        }
+        self.expire(&mut inner);
+        self.compact(&mut inner);
+        Ok(())
+    }
+
+    /// Store `delivery` under a new ID, which is returned.
+    pub fn record(&self, mut delivery: Delivery) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        delivery.id = inner.next_id;
+        self.write(FileOp::Append(delivery.clone()));
+        inner.push(delivery);
+        self.expire(&mut inner);
+        if inner.stale_lines > inner.accepted.len() + inner.rejected.len() {
+            self.compact(&mut inner);
+        }
+        inner.next_id
+    }
+
+    /// Forget deliveries beyond `max_deliveries`, or `max_rejected` of the
+    /// rejected ones, or older than `max_age_days`.
+    fn expire(&self, inner: &mut Inner) {
+        let cutoff = Utc::now() - Duration::days(self.config.max_age_days as i64);
+        let mut stale = 0;
+        for (deliveries, max) in
+            [(&mut inner.accepted, self.config.max_deliveries), (&mut inner.rejected, self.config.max_rejected)]
+        {
+            while let Some(oldest) = deliveries.front() {
+                if deliveries.len() <= max && oldest.received_at >= cutoff {
+                    break;
+                }
+                deliveries.pop_front();
+                stale += 1;
+            }
+        }
+        inner.stale_lines += stale;
+    }
+
+    /// Have the file rewritten with just the retained deliveries.
+    fn compact(&self, inner: &mut Inner) {
+        self.write(FileOp::Rewrite(inner.by_id().cloned().collect()));
+        inner.stale_lines = 0;
+    }
+
+    fn write(&self, op: FileOp) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(op);
+        }
+    }
+
+    fn get(&self, id: u64) -> Option<Delivery> {
+        self.inner.lock().unwrap().by_id().find(|d| d.id == id).cloned()
+    }
+}
+
+/// Apply queued changes to the file at `path` until the log is dropped.
+fn write_file(path: &str, mut rx: mpsc::UnboundedReceiver<FileOp>) {
+    while let Some(op) = rx.blocking_recv() {
+        let (what, written) = match op {
+            FileOp::Append(delivery) => ("write delivery", append(path, &delivery)),
+            FileOp::Rewrite(deliveries) => ("compact", rewrite(path, &deliveries)),
+        };
+        if let Err(e) = written {
+            eprintln!("deliveries: failed to {}: {:#}", what, e);
+        }
+    }
+}
+
+fn append(path: &str, delivery: &Delivery) -> Result<()> {
+    let mut line = serde_json::to_vec(delivery)?;
+    line.push(b'\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&line)?;
+    Ok(())
+}
+
+fn rewrite(path: &str, deliveries: &[Delivery]) -> Result<()> {
+    let tmp = Path::new(path).with_extension("tmp");
+    let mut out = BufWriter::new(File::create(&tmp).with_context(|| format!("creating {}", tmp.display()))?);
+    for delivery in deliveries {
+        serde_json::to_writer(&mut out, delivery)?;
+        out.write_all(b"\n")?;
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("replacing {}", path))?;
+    Ok(())
+}
+
+impl Inner {
+    fn push(&mut self, delivery: Delivery) {
+        if delivery.verification.accepted() {
+            self.accepted.push_back(delivery);
+        } else {
+            self.rejected.push_back(delivery);
+        }
+    }
+
+    /// Every retained delivery, oldest first.
+    fn by_id(&self) -> impl Iterator<Item = &Delivery> {
+        let mut all: Vec<&Delivery> = self.accepted.iter().chain(&self.rejected).collect();
+        all.sort_by_key(|d| d.id);
+        all.into_iter()
+    }
+}
+
+/// `GET /deliveries`: every retained delivery without headers and body,
+/// newest first.
+pub async fn list_deliveries(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Some(response) = unauthorized(&state.config.server, &headers) {
+        return response;
+    }
+    let inner = state.deliveries.inner.lock().unwrap();
+    let mut summaries: Vec<Summary> = inner
+        .by_id()
+        .map(|d| Summary {
+            id: d.id,
+            received_at: d.received_at,
+            path: &d.path,
+            verification: d.verification,
+            event: &d.event,
+            route: &d.route,
+            action: &d.action,
+            job_id: d.job_id,
+            replay_of: d.replay_of,
+        })
+        .collect();
+    summaries.reverse();
+    Json(summaries).into_response()
+}
+
+/// `GET /deliveries/:id`: one delivery as received.
+pub async fn get_delivery(State(state): State<Arc<AppState>>, UrlPath(id): UrlPath<u64>, headers: HeaderMap) -> Response {
+    if let Some(response) = unauthorized(&state.config.server, &headers) {
+        return response;
+    }
+    match state.deliveries.get(id) {
+        Some(delivery) => Json(delivery).into_response(),
+        None => (StatusCode::NOT_FOUND, "no such delivery\n").into_response(),
+    }
+}
+
+/// `POST /deliveries/:id/replay`: route a verified delivery's original
+/// headers and body again, as a new delivery.
+pub async fn replay_delivery(
+    State(state): State<Arc<AppState>>,
+    UrlPath(id): UrlPath<u64>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(response) = unauthorized(&state.config.server, &headers) {
+        return response;
+    }
+    let Some(original) = state.deliveries.get(id) else {
+        return (StatusCode::NOT_FOUND, "no such delivery\n").into_response();
+    };
+    if !original.verification.accepted() {
+        return (StatusCode::CONFLICT, "only verified deliveries can be replayed\n").into_response();
+    }
+    let Some(provider) = state.config.providers.iter().find(|p| p.path == original.path) else {
+        return (StatusCode::CONFLICT, format!("no provider is configured on {} any more\n", original.path))
+            .into_response();
+    };
+    let original_headers = original.header_map();
+    let body = match original.body_bytes() {
+        Ok(body) => bytes::Bytes::from(body),
+        Err(e) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("stored body is corrupt: {}\n", e)).into_response()
+        }
+    };
+    let mut delivery = Delivery::new(provider, &original_headers, &body, Verification::ManualReplay);
+    delivery.replay_of = Some(id);
+    println!("replaying delivery {}", id);
+    let (job_id, delivery_id) = dispatch(&state, provider, &original_headers, body, delivery);
+    match job_id {
+        Some(job_id) => {
+            (StatusCode::ACCEPTED, Json(json!({ "job_id": job_id, "delivery_id": delivery_id }))).into_response()
+        }
+        None => (StatusCode::OK, Json(json!({ "delivery_id": delivery_id }))).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration as StdDuration;
+
+    use super::*;
+    use crate::tests::{push_body, serve};
+
+    fn github() -> ProviderConfig {
+        toml::from_str("kind = \"github\"\npath = \"/github\"").unwrap()
+    }
+
+    fn delivery(verification: Verification, body: &[u8]) -> Delivery {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-github-event", HeaderValue::from_static("push"));
+        headers.insert("x-hub-signature-256", HeaderValue::from_static("sha256=abc"));
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer t0ken"));
+        Delivery::new(&github(), &headers, body, verification)
+    }
+
+    fn file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("webhook-deliveries-{}-{}.jsonl", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn ids(log: &DeliveryLog) -> Vec<u64> {
+        log.inner.lock().unwrap().by_id().map(|d| d.id).collect()
+    }
+
+    /// Wait for the writer to leave just the deliveries `ids` in `path`.
+    async fn wait_for_file(path: &str, ids: &[u64]) {
+        let mut found = Vec::new();
+        for _ in 0..500 {
+            let text = fs::read_to_string(path).unwrap_or_default();
+            found = text.lines().map(|l| serde_json::from_str::<Delivery>(l).unwrap().id).collect();
+            if found == ids {
+                return;
+            }
+            tokio::time::sleep(StdDuration::from_millis(10)).await;
+        }
+        panic!("{} holds deliveries {:?}, not {:?}", path, found, ids);
+    }
+
+    #[test]
+    fn keeps_bodies_byte_for_byte() {
+        let text = delivery(Verification::Verified, b"{\"ref\":\"refs/heads/main\"}");
+        assert_eq!((text.body.as_str(), text.body_base64), ("{\"ref\":\"refs/heads/main\"}", false));
+        let headers: Vec<&str> = text.headers.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(headers, ["x-github-event"]);
+
+        let binary = delivery(Verification::Verified, b"\xff\xfe{}\x00");
+        assert!(binary.body_base64);
+        let json = serde_json::to_string(&binary).unwrap();
+        let restored: Delivery = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.body_bytes().unwrap(), b"\xff\xfe{}\x00");
+
+        let rejected = delivery(Verification::BadSignature, &[b'a'; 2000]);
+        assert!(rejected.truncated);
+        assert_eq!(rejected.body.len(), REJECTED_BODY_BYTES);
+    }
+
+    #[tokio::test]
+    async fn caps_expires_and_compacts() {
+        let path = file("caps");
+        let config = DeliveriesConfig { file: Some(path.clone()), max_deliveries: 2, max_rejected: 1, max_age_days: 7 };
+        let log = DeliveryLog::new(config.clone());
+        let mut old = delivery(Verification::Verified, b"old");
+        old.received_at = Utc::now() - Duration::days(8);
+        assert_eq!(log.record(old), 1);
+        for verification in [Verification::Verified, Verification::BadSignature, Verification::MissingSignature] {
+            log.record(delivery(verification, b"{}"));
+        }
+        // The old one is gone, and so is the first rejection, which would
+        // have pushed out a verified delivery had they shared a cap.
+        assert_eq!(ids(&log), [2, 4]);
+        // Forgetting the old one left the file with more forgotten than
+        // retained, so it was rewritten before the others were appended.
+        wait_for_file(&path, &[2, 3, 4]).await;
+        for _ in 5..=7 {
+            log.record(delivery(Verification::Verified, b"{}"));
+        }
+        wait_for_file(&path, &[2, 3, 4, 5, 6, 7]).await;
+        log.record(delivery(Verification::Verified, b"{}"));
+        assert_eq!(ids(&log), [4, 7, 8]);
+        wait_for_file(&path, &[4, 7, 8]).await;
+
+        let restored = DeliveryLog::new(config);
+        restored.restore().unwrap();
+        assert_eq!(ids(&restored), [4, 7, 8]);
+        assert_eq!(restored.record(delivery(Verification::Verified, b"{}")), 9);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn replays_the_original_bytes() {
+        let dir = std::env::temp_dir().join(format!("webhook-deliveries-replay-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("payloads");
+        let script = dir.join("action.sh");
+        fs::write(&script, format!("#!/bin/sh\n/bin/cat >> {}\n", out.display())).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let url = serve(&format!(
+            r#"
+            [server]
+            api_token = "t0ken"
+
+            [[providers]]
+            kind = "github"
+            path = "/github"
+            secret = "s3cr3t-value"
+
+            [[routes]]
+            name = "all"
+            action = "{}"
+            "#,
+            script.display()
+        ))
+        .await;
+        let body = b"{\"ref\":\"refs/heads/main\",\"note\":\"\xff\"}";
+        assert_eq!(push_body(&format!("{}/github", url), "s3cr3t-value", body).await, reqwest::StatusCode::ACCEPTED);
+        assert_eq!(push_body(&format!("{}/github", url), "wrong", body).await, reqwest::StatusCode::UNAUTHORIZED);
+
+        let client = reqwest::Client::new();
+        let replay = |id: u64, token: &'static str| {
+            client.post(format!("{}/deliveries/{}/replay", url, id)).bearer_auth(token).send()
+        };
+        assert_eq!(replay(1, "wrong").await.unwrap().status(), reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(replay(9, "t0ken").await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(replay(2, "t0ken").await.unwrap().status(), reqwest::StatusCode::CONFLICT);
+        let replayed = replay(1, "t0ken").await.unwrap();
+        assert_eq!(replayed.status(), reqwest::StatusCode::ACCEPTED);
+        let replayed: serde_json::Value = replayed.json().await.unwrap();
+        assert_eq!(replayed["delivery_id"], 3);
+
+        let stored = client.get(format!("{}/deliveries/3", url)).bearer_auth("t0ken").send().await.unwrap();
+        let stored: serde_json::Value = stored.json().await.unwrap();
+        assert_eq!(stored["replay_of"], 1);
+        assert_eq!(stored["verification"], "manual_replay");
+        assert_eq!(stored["body_base64"], true);
+
+        let mut twice = body.to_vec();
+        twice.extend_from_slice(body);
+        for _ in 0..500 {
+            if fs::read(&out).unwrap_or_default() == twice {
+                fs::remove_dir_all(&dir).unwrap();
+                return;
+            }
+            tokio::time::sleep(StdDuration::from_millis(10)).await;
+        }
+        panic!("the action got {:?}", String::from_utf8_lossy(&fs::read(&out).unwrap_or_default()));
+    }
+}
@@ -0,0 +1,200 @@
+//! Built-in deploy steps, so a route can deploy a registry app without a
+//! hand-written script. Each step comes down to a few commands the job runs
+//! in `project_dir`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::config::{DeployConfig, SandboxConfig, Step};
+use crate::sandbox;
+
+/// How long `head` may take.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The parts of a `compose/app-registry/*.yaml` file deploys need.
+#[derive(Deserialize)]
+struct App {
+    name: String,
+    compose_file: Option<String>,
+}
+
+/// The commands for `step`. Worked out just before the step runs, so the
+/// compose steps see the registry as checked out by an earlier step.
+pub fn commands(config: &DeployConfig, step: Step, app: Option<&str>, sha: Option<&str>) -> Result<Vec<Command>> {
+    let command = |program: &str, args: &[&str]| {
+        let mut command = Command::new(program);
+        command.args(args).current_dir(&config.project_dir);
+        command
+    };
+    match step {
+        Step::Checkout => {
+            let Some(sha) = sha else { bail!("the event names no commit to check out") };
+            // Forges report a deleted branch or tag as a push to all zeros.
+            if sha.bytes().all(|b| b == b'0') {
+                bail!("the event deletes its ref, there is no commit to check out");
+            }
+            // The sha comes from the payload; anything but a hex commit ID
+            // could be taken by git as an option or a ref.
+            if !is_commit_id(sha) {
+                bail!("{:?} is not a commit ID", sha);
+            }
+            Ok(vec![
+                command("git", &["fetch", "--quiet", "origin"]),
+                command("git", &["checkout", "--quiet", "--force", "--detach", sha, "--"]),
+            ])
+        }
+        Step::Pull | Step::Build | Step::Up => {
+            let app = app.context("no app to deploy")?;
+            let mut compose = command("docker", &["compose", "-f"]);
+            compose.arg(compose_file(config, app)?);
+            match step {
+                Step::Pull => compose.arg("pull"),
+                Step::Build => compose.arg("build"),
+                _ => compose.args(["up", "-d"]),
+            };
+            Ok(vec![compose])
+        }
+        Step::Nginx => {
+            let (program, args) = config.nginx_generate.split_first().expect("validated: nginx_generate is not empty");
+            let mut generate = Command::new(program);
+            generate.args(args).current_dir(&config.project_dir);
+            let container = config.nginx_container.as_str();
+            Ok(vec![
+                generate,
+                command("docker", &["exec", container, "nginx", "-t"]),
+                command("docker", &["exec", container, "nginx", "-s", "reload"]),
+            ])
+        }
+    }
+}
+
+/// An abbreviated or full commit ID: 7 to 40 lowercase hex digits.
+fn is_commit_id(sha: &str) -> bool {
+    (7..=40).contains(&sha.len()) && sha.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// `app`'s compose file, relative to `project_dir`.
+fn compose_file(config: &DeployConfig, app: &str) -> Result<PathBuf> {
+    let dir = Path::new(&config.project_dir).join(&config.registry_dir);
+    let mut files: Vec<PathBuf> = fs::read_dir(&dir)
+        .with_context(|| format!("reading {}", dir.display()))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "yaml" || ext == "yml"))
+        .collect();
+    files.sort();
+    for path in files {
+        // Another app's broken file is not this deploy's problem.
+        let Ok(entry) = fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| Ok(serde_yaml::from_str::<App>(&text)?))
+        else {
+            continue;
+        };
+        if entry.name != app {
+            continue;
+        }
+        let file = entry.compose_file.with_context(|| format!("{} has no compose_file", path.display()))?;
+        return Ok(Path::new(&config.compose_dir).join(file));
+    }
+    bail!("no app named {:?} in {}", app, dir.display())
+}
+
+/// The commit currently checked out in `project_dir`, if it can be told.
+/// Runs confined by `sandbox` like the steps, and is given up on after
+/// `HEAD_TIMEOUT` as the caller holds the checkout.
+pub async fn head(config: &DeployConfig, sandbox: &SandboxConfig) -> Option<String> {
+    let mut command = Command::new("git");
+    command.args(["rev-parse", "HEAD"]).current_dir(&config.project_dir);
+    sandbox::apply(&mut command, sandbox);
+    command.stdin(Stdio::null()).kill_on_drop(true);
+    let output = match tokio::time::timeout(HEAD_TIMEOUT, command.output()).await {
+        Ok(output) => output.ok()?,
+        Err(_) => {
+            eprintln!("git rev-parse HEAD in {}: timed out after {:?}", config.project_dir, HEAD_TIMEOUT);
+            return None;
+        }
+    };
+    let sha = String::from_utf8(output.stdout).ok()?;
+    Some(sha.trim().to_string()).filter(|sha| output.status.success() && !sha.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    /// A project with one registry app and a `docker` on PATH that logs its
+    /// arguments instead of running anything.
+    fn project(name: &str) -> (DeployConfig, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("webhook-deploy-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("compose/app-registry")).unwrap();
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        fs::write(dir.join("compose/app-registry/broken.yaml"), "name: [").unwrap();
+        fs::write(dir.join("compose/app-registry/blog.yaml"), "name: blog\ncompose_file: blog/compose.yaml\n").unwrap();
+        let docker = dir.join("bin/docker");
+        fs::write(&docker, "#!/bin/sh\necho \"$@\" >> \"${0%/*}/docker.log\"\n").unwrap();
+        fs::set_permissions(&docker, fs::Permissions::from_mode(0o755)).unwrap();
+        let config = DeployConfig { project_dir: dir.to_string_lossy().into_owned(), ..DeployConfig::default() };
+        (config, dir)
+    }
+
+    #[tokio::test]
+    async fn runs_compose_steps_through_docker() {
+        let (config, dir) = project("compose");
+        for step in [Step::Pull, Step::Build, Step::Up] {
+            for mut command in commands(&config, step, Some("blog"), None).unwrap() {
+                let status = command.env("PATH", dir.join("bin")).status().await.unwrap();
+                assert!(status.success());
+            }
+        }
+        let log = fs::read_to_string(dir.join("bin/docker.log")).unwrap();
+        assert_eq!(
+            log,
+            "compose -f compose/blog/compose.yaml pull\n\
+             compose -f compose/blog/compose.yaml build\n\
+             compose -f compose/blog/compose.yaml up -d\n"
+        );
+        assert!(commands(&config, Step::Up, Some("shop"), None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checks_out_only_commit_ids() {
+        let config = DeployConfig::default();
+        let sha = "0123456789abcdef0123456789abcdef01234567";
+        let checkout = commands(&config, Step::Checkout, None, Some(sha)).unwrap().pop().unwrap();
+        let args: Vec<_> = checkout.as_std().get_args().collect();
+        assert_eq!(args, ["checkout", "--quiet", "--force", "--detach", sha, "--"]);
+        assert!(commands(&config, Step::Checkout, None, Some("0123abc")).is_ok());
+        let deleted = commands(&config, Step::Checkout, None, Some(&"0".repeat(40))).err().unwrap();
+        assert_eq!(deleted.to_string(), "the event deletes its ref, there is no commit to check out");
+        assert!(commands(&config, Step::Checkout, None, Some("0000000")).is_err());
+        for bad in ["--upload-pack=touch /tmp/x", "main", "0123AB", "0123ab", &format!("{}0", sha), "0123abc\n"] {
+            assert!(commands(&config, Step::Checkout, None, Some(bad)).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_head_of_the_checkout() {
+        let (config, dir) = project("head");
+        let sandbox = SandboxConfig::default();
+        assert_eq!(head(&config, &sandbox).await, None, "not a checkout yet");
+        let git = |args: &[&str]| {
+            let output = std::process::Command::new("git").args(args).current_dir(&dir).output().unwrap();
+            assert!(output.status.success(), "git {:?}", args);
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+        git(&["init", "--quiet"]);
+        git(&["-c", "user.name=t", "-c", "user.email=t@example.com", "commit", "--quiet", "--allow-empty", "-m", "x"]);
+        assert_eq!(head(&config, &sandbox).await, Some(git(&["rev-parse", "HEAD"])));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,31 @@
+//! Post-action health checks: poll an app's health URL until it answers or
+//! its deadline passes.
+
+use std::time::Duration;
+
+use reqwest::Client;
+use tokio::time::Instant;
+
+use crate::config::HealthCheck;
+
+/// No single attempt waits longer than this, however long the deadline.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wait until `check.url` answers with a 2xx status. The error describes the
+/// last failed attempt.
+pub async fn wait_healthy(client: &Client, check: &HealthCheck) -> Result<(), String> {
+    let deadline = Instant::now() + Duration::from_secs(check.timeout_secs);
+    let interval = Duration::from_secs(check.interval_secs);
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let last = match client.get(&check.url).timeout(remaining.min(ATTEMPT_TIMEOUT)).send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => format!("{} answered {}", check.url, response.status()),
+            Err(e) => format!("{}: {}", check.url, e),
+        };
+        if Instant::now() + interval >= deadline {
+            return Err(last);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,1179 @@
+//! Every triggered action runs as a job, tracked from start to exit with its
+//! output captured, so a failed deploy can be diagnosed over HTTP.
+//!
+//! The store is bounded: past `max_jobs` the oldest finished jobs are
+//! evicted, and each job keeps at most `max_log_bytes` of output.
+//!
+//! Jobs for the same action go through its queue: at most `concurrency` of
+//! them run at once, and only the newest waiting job is kept. A job queued
+//! behind it is superseded by every later event, so a burst of pushes ends
+//! in one run against the latest commit rather than one run per push.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::{watch, Notify};
+use tokio::time::{sleep_until, Instant};
+
+use crate::auth::unauthorized;
+use crate::config::{DeployConfig, DeployRoute, HealthCheck, JobsConfig, SandboxConfig, Step};
+use crate::notify::Notifier;
+use crate::sandbox;
+use crate::{deploy, health};
+use crate::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    /// Superseded by a newer event, or cancelled through the API.
+    Cancelled,
+    /// The action ran but the app never became healthy, so the previous
+    /// deploy was restored.
+    RolledBack,
+}
+
+impl Status {
+    pub fn name(self) -> &'static str {
+        match self {
+            Status::Queued => "queued",
+            Status::Running => "running",
+            Status::Succeeded => "succeeded",
+            Status::Failed => "failed",
+            Status::Cancelled => "cancelled",
+            Status::RolledBack => "rolled_back",
+        }
+    }
+
+    pub fn is_finished(self) -> bool {
+        !matches!(self, Status::Queued | Status::Running)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// What a job runs and the event that caused it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Trigger {
+    /// The route that matched, if routing is configured.
+    pub route: Option<String>,
+    /// The script, or `deploy <app>` for built-in deploys. Jobs with the
+    /// same action share a queue.
+    pub action: String,
+    pub event: String,
+    pub repository: Option<String>,
+    #[serde(rename = "ref")]
+    pub git_ref: Option<String>,
+    pub sha: Option<String>,
+    pub pusher: Option<String>,
+    pub delivery_id: Option<String>,
+    /// The delivery's body, fed to the action on stdin.
+    #[serde(skip)]
+    pub payload: Bytes,
+    /// Run these steps rather than `action` as a script.
+    #[serde(skip)]
+    pub deploy: Option<DeployRoute>,
+    #[serde(skip)]
+    pub health_check: Option<HealthCheck>,
+    #[serde(skip)]
+    pub rollback: Option<String>,
+    #[serde(skip)]
+    pub sandbox: SandboxConfig,
+}
+
+/// One built-in deploy step of a job.
+#[derive(Debug, Clone, Serialize)]
+pub struct StepInfo {
+    pub name: String,
+    pub status: Status,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub duration_ms: Option<i64>,
+}
+
+/// What ends a running job early: a cancel, or its sandbox's timeout, which
+/// covers every command, the health check and any rollback together.
+struct Stop<'a> {
+    cancel: &'a Notify,
+    deadline: Instant,
+    timeout_secs: u64,
+}
+
+impl Stop<'_> {
+    fn timed_out(&self) -> String {
+        format!("timed out after {}s", self.timeout_secs)
+    }
+}
+
+/// How a command, or a sequence of them, ended.
+enum Outcome {
+    Succeeded,
+    /// Exited unsuccessfully.
+    Exited(ExitStatus),
+    Failed(String),
+    Cancelled,
+}
+
+impl Outcome {
+    fn status(&self) -> Status {
+        match self {
+            Outcome::Succeeded => Status::Succeeded,
+            Outcome::Cancelled => Status::Cancelled,
+            _ => Status::Failed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobInfo {
+    pub id: u64,
+    #[serde(flatten)]
+    pub trigger: Trigger,
+    pub status: Status,
+    pub queued_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub exit_code: Option<i32>,
+    /// Why the job failed or was cancelled when there is no exit code to
+    /// tell, e.g. the action could not be started.
+    pub error: Option<String>,
+    /// The last commit this action deployed successfully, which a rollback
+    /// restores.
+    pub previous_sha: Option<String>,
+    /// Built-in deploy steps run so far.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<StepInfo>,
+}
+
+#[derive(Serialize)]
+struct JobDetail {
+    #[serde(flatten)]
+    info: JobInfo,
+    stdout: String,
+    stderr: String,
+    /// Output past `max_log_bytes` was dropped.
+    truncated: bool,
+}
+
+struct Job {
+    info: JobInfo,
+    /// Output lines in the order they arrived, each ending in `\n`.
+    log: Vec<(Stream, String)>,
+    log_bytes: usize,
+    truncated: bool,
+    /// Bumped on every new line and on exit, for `/jobs/{id}/log` followers.
+    updates: watch::Sender<usize>,
+    /// Signalled to kill the running action.
+    cancel: Arc<Notify>,
+    cancel_reason: Option<String>,
+}
+
+impl Job {
+    fn cancelled(&mut self, reason: String, notifier: &Notifier) {
+        println!("job {}: cancelled: {}", self.info.id, reason);
+        self.info.status = Status::Cancelled;
+        self.info.finished_at = Some(Utc::now());
+        self.info.error = Some(reason);
+        self.updates.send_replace(self.log.len());
+        notifier.send(&self.info);
+    }
+}
+
+/// The jobs of one action that hold or are waiting for a run slot.
+#[derive(Default)]
+struct Queue {
+    running: Vec<u64>,
+    /// The newest queued job; older ones have been superseded.
+    pending: Option<u64>,
+    /// Notified whenever `running` or `pending` changes.
+    changed: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct Inner {
+    jobs: BTreeMap<u64, Job>,
+    queues: HashMap<String, Queue>,
+    /// The commit each action last deployed successfully.
+    deployed: HashMap<String, String>,
+    next_id: u64,
+    notifier: Notifier,
+}
+
+impl Inner {
+    /// Cancel a queued job on the spot, or ask a running one to stop. False
+    /// if the job is unknown or already finished.
+    fn cancel(&mut self, id: u64, reason: String) -> bool {
+        let Some(job) = self.jobs.get_mut(&id) else { return false };
+        match job.info.status {
+            Status::Queued => {
+                if let Some(queue) = self.queues.get_mut(&job.info.trigger.action) {
+                    if queue.pending == Some(id) {
+                        queue.pending = None;
+                        queue.changed.notify_waiters();
+                    }
+                }
+                job.cancelled(reason, &self.notifier);
+                true
+            }
+            Status::Running => {
+                job.cancel_reason.get_or_insert(reason);
+                job.cancel.notify_one();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct JobStore {
+    inner: Arc<Mutex<Inner>>,
+    config: JobsConfig,
+    deploy: DeployConfig,
+    /// Held by checkout steps: deploys of different apps share the checkout.
+    checkout: Arc<tokio::sync::Mutex<()>>,
+    client: reqwest::Client,
+}
+
+impl JobStore {
+    pub fn new(config: JobsConfig, deploy: DeployConfig, notifier: Notifier) -> Self {
+        let inner = Inner { notifier, ..Inner::default() };
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+            config,
+            deploy,
+            checkout: Arc::default(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Queue a job for `trigger`, superseding any job of the same action that
+    /// has not started yet. Returns the job ID.
+    pub fn enqueue(&self, trigger: Trigger) -> u64 {
+        let action = trigger.action.clone();
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.next_id += 1;
+            let id = inner.next_id;
+            let info = JobInfo {
+                id,
+                trigger,
+                status: Status::Queued,
+                queued_at: Utc::now(),
+                started_at: None,
+                finished_at: None,
+                exit_code: None,
+                error: None,
+                previous_sha: None,
+                steps: Vec::new(),
+            };
+            let job = Job {
+                info,
+                log: Vec::new(),
+                log_bytes: 0,
+                truncated: false,
+                updates: watch::Sender::new(0),
+                cancel: Arc::default(),
+                cancel_reason: None,
+            };
+            inner.notifier.send(&job.info);
+            inner.jobs.insert(id, job);
+            let queue = inner.queues.entry(action.clone()).or_default();
+            let superseded = queue.pending.take();
+            let running = if self.config.cancel_running { queue.running.clone() } else { Vec::new() };
+            for old in superseded.into_iter().chain(running) {
+                inner.cancel(old, format!("superseded by job {}", id));
+            }
+            let queue = inner.queues.entry(action.clone()).or_default();
+            queue.pending = Some(id);
+            queue.changed.notify_waiters();
+            self.evict(&mut inner);
+            id
+        };
+        println!("job {}: queued {}", id, action);
+        let store = self.clone();
+        tokio::spawn(async move { store.dispatch(id, action).await });
+        id
+    }
+
+    /// Wait out the debounce and a free slot in the action's queue, then run
+    /// the job unless a newer one superseded it meanwhile.
+    async fn dispatch(&self, id: u64, action: String) {
+        tokio::time::sleep(Duration::from_millis(self.config.debounce_ms)).await;
+        let Some(changed) = self.inner.lock().unwrap().queues.get(&action).map(|q| q.changed.clone()) else {
+            return;
+        };
+        let cancel = loop {
+            let notified = changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let mut inner = self.inner.lock().unwrap();
+                let inner = &mut *inner;
+                let Some(queue) = inner.queues.get_mut(&action) else { return };
+                if queue.pending != Some(id) {
+                    return;
+                }
+                if queue.running.len() < self.config.concurrency {
+                    queue.pending = None;
+                    queue.running.push(id);
+                    let previous = inner.deployed.get(&action).cloned();
+                    let job = inner.jobs.get_mut(&id).expect("queued jobs are not evicted");
+                    job.info.previous_sha = previous;
+                    job.info.status = Status::Running;
+                    job.info.started_at = Some(Utc::now());
+                    job.updates.send_replace(job.log.len());
+                    let cancel = job.cancel.clone();
+                    inner.notifier.send(&job.info);
+                    break cancel;
+                }
+            }
+            notified.await;
+        };
+        println!("job {}: running {}", id, action);
+        let trigger = self.inner.lock().unwrap().jobs[&id].info.trigger.clone();
+        let timeout_secs = trigger.sandbox.timeout_secs;
+        let deadline = Instant::now() + Duration::from_secs(timeout_secs);
+        self.run(id, &trigger, &Stop { cancel: &cancel, deadline, timeout_secs }).await;
+
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(queue) = inner.queues.get_mut(&action) {
+            queue.running.retain(|&r| r != id);
+            queue.changed.notify_waiters();
+            if queue.running.is_empty() && queue.pending.is_none() {
+                inner.queues.remove(&action);
+            }
+        }
+    }
+
+    async fn run(&self, id: u64, trigger: &Trigger, stop: &Stop<'_>) {
+        let outcome = match &trigger.deploy {
+            None => self.exec(id, trigger, script(trigger, &trigger.action), true, stop).await,
+            Some(deploy) => self.run_steps(id, trigger, deploy, "", stop).await,
+        };
+        let (status, exit_code, error) = match outcome {
+            Outcome::Succeeded => match &trigger.health_check {
+                None => (Status::Succeeded, Some(0), None),
+                Some(check) => match self.verify(id, trigger, check, stop).await {
+                    Some((status, error)) => (status, Some(0), error),
+                    None => return self.cancelled(id),
+                },
+            },
+            Outcome::Exited(status) if status.code().is_some() => (Status::Failed, status.code(), None),
+            Outcome::Exited(status) => (Status::Failed, None, Some(format!("terminated by {}", status))),
+            Outcome::Failed(e) => (Status::Failed, None, Some(e)),
+            Outcome::Cancelled => return self.cancelled(id),
+        };
+        self.finish(id, status, exit_code, error);
+    }
+
+    /// Run the deploy's steps in order, stopping at the first that fails.
+    /// Step names get `prefix`, to tell a rollback's steps apart.
+    async fn run_steps(
+        &self,
+        id: u64,
+        trigger: &Trigger,
+        deploy: &DeployRoute,
+        prefix: &str,
+        stop: &Stop<'_>,
+    ) -> Outcome {
+        for &step in &deploy.steps {
+            let index = self.step_started(id, &format!("{}{}", prefix, step.name()));
+            let _checkout = match step {
+                Step::Checkout => {
+                    // Another deploy may hold the checkout for as long as
+                    // its own timeout; this job's budget runs meanwhile.
+                    let guard = tokio::select! {
+                        guard = self.checkout.lock() => guard,
+                        _ = stop.cancel.notified() => {
+                            self.step_finished(id, index, Status::Cancelled);
+                            return Outcome::Cancelled;
+                        }
+                        _ = sleep_until(stop.deadline) => {
+                            self.step_finished(id, index, Status::Failed);
+                            return Outcome::Failed(format!("waiting for the checkout {}", stop.timed_out()));
+                        }
+                    };
+                    self.record_previous(id, &trigger.sandbox).await;
+                    Some(guard)
+                }
+                _ => None,
+            };
+            let outcome = match deploy::commands(&self.deploy, step, deploy.app.as_deref(), trigger.sha.as_deref()) {
+                Err(e) => Outcome::Failed(format!("{}: {:#}", step.name(), e)),
+                Ok(commands) => {
+                    let mut outcome = Outcome::Succeeded;
+                    for command in commands {
+                        outcome = self.exec(id, trigger, command, false, stop).await;
+                        if !matches!(outcome, Outcome::Succeeded) {
+                            break;
+                        }
+                    }
+                    outcome
+                }
+            };
+            self.step_finished(id, index, outcome.status());
+            if !matches!(outcome, Outcome::Succeeded) {
+                return outcome;
+            }
+        }
+        Outcome::Succeeded
+    }
+
+    /// Before the first checkout of an action, remember what was checked out
+    /// so there is something to roll back to.
+    async fn record_previous(&self, id: u64, sandbox: &SandboxConfig) {
+        let unknown = matches!(self.inner.lock().unwrap().jobs.get(&id), Some(job) if job.info.previous_sha.is_none());
+        if !unknown {
+            return;
+        }
+        let head = deploy::head(&self.deploy, sandbox).await;
+        if let Some(job) = self.inner.lock().unwrap().jobs.get_mut(&id) {
+            job.info.previous_sha = head;
+        }
+    }
+
+    /// Poll the health check and roll back if it never passes. Returns the
+    /// job's final status and error, or `None` if it was cancelled.
+    async fn verify(
+        &self,
+        id: u64,
+        trigger: &Trigger,
+        check: &HealthCheck,
+        stop: &Stop<'_>,
+    ) -> Option<(Status, Option<String>)> {
+        let index = self.step_started(id, "health");
+        let healthy = tokio::select! {
+            healthy = health::wait_healthy(&self.client, check) => healthy,
+            _ = stop.cancel.notified() => {
+                self.step_finished(id, index, Status::Cancelled);
+                return None;
+            }
+            _ = sleep_until(stop.deadline) => {
+                self.step_finished(id, index, Status::Failed);
+                return Some((Status::Failed, Some(format!("health check {}", stop.timed_out()))));
+            }
+        };
+        let problem = match healthy {
+            Ok(()) => {
+                self.step_finished(id, index, Status::Succeeded);
+                return Some((Status::Succeeded, None));
+            }
+            Err(problem) => problem,
+        };
+        self.append(id, Stream::Stderr, format!("health check failed: {}\n", problem));
+        self.step_finished(id, index, Status::Failed);
+        let unhealthy = format!("unhealthy after {}s: {}", check.timeout_secs, problem);
+
+        let previous = self.inner.lock().unwrap().jobs.get(&id).and_then(|job| job.info.previous_sha.clone());
+        let Some(previous) = previous else {
+            return Some((Status::Failed, Some(format!("{}; no previous commit to roll back to", unhealthy))));
+        };
+        let rollback = Trigger { sha: Some(previous.clone()), ..trigger.clone() };
+        let outcome = match (&trigger.rollback, &trigger.deploy) {
+            (Some(path), _) => {
+                let index = self.step_started(id, "rollback");
+                let outcome = self.exec(id, &rollback, script(trigger, path), true, stop).await;
+                self.step_finished(id, index, outcome.status());
+                outcome
+            }
+            (None, Some(deploy)) => self.run_steps(id, &rollback, deploy, "rollback ", stop).await,
+            (None, None) => return Some((Status::Failed, Some(format!("{}; no rollback configured", unhealthy)))),
+        };
+        match outcome {
+            Outcome::Succeeded => Some((Status::RolledBack, Some(format!("{}; rolled back to {}", unhealthy, previous)))),
+            Outcome::Cancelled => None,
+            Outcome::Exited(status) => {
+                Some((Status::Failed, Some(format!("{}; rollback to {} failed: {}", unhealthy, previous, status))))
+            }
+            Outcome::Failed(e) => {
+                Some((Status::Failed, Some(format!("{}; rollback to {} failed: {}", unhealthy, previous, e))))
+            }
+        }
+    }
+
+    /// Run one command in the trigger's sandbox with the event in its
+    /// environment and, for scripts, the raw payload on stdin.
+    async fn exec(&self, id: u64, trigger: &Trigger, mut command: Command, stdin: bool, stop: &Stop<'_>) -> Outcome {
+        let program = command.as_std().get_program().to_string_lossy().into_owned();
+        let var = |value: &Option<String>| value.clone().unwrap_or_default();
+        sandbox::apply(&mut command, &trigger.sandbox);
+        let child = command
+            .env("EVENT", &trigger.event)
+            .env("REPO", var(&trigger.repository))
+            .env("REF", var(&trigger.git_ref))
+            .env("SHA", var(&trigger.sha))
+            .env("PUSHER", var(&trigger.pusher))
+            .env("DELIVERY_ID", var(&trigger.delivery_id))
+            .stdin(if stdin { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => return Outcome::Failed(format!("failed to start {}: {}", program, e)),
+        };
+        let pid = child.id();
+        let input = child.stdin.take();
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let status = {
+            let exited = async {
+                // An action that ignores its stdin closes it on exit; the
+                // failed write is of no interest then.
+                let feed = async move {
+                    if let Some(mut input) = input {
+                        let _ = input.write_all(&trigger.payload).await;
+                    }
+                };
+                tokio::join!(
+                    feed,
+                    self.capture(id, Stream::Stdout, stdout),
+                    self.capture(id, Stream::Stderr, stderr),
+                    child.wait()
+                )
+                .3
+            };
+            tokio::select! {
+                status = exited => Ok(status),
+                _ = stop.cancel.notified() => Err(Outcome::Cancelled),
+                _ = sleep_until(stop.deadline) => Err(Outcome::Failed(format!("{} {}", program, stop.timed_out()))),
+            }
+        };
+        match status {
+            Ok(Ok(status)) if status.success() => Outcome::Succeeded,
+            Ok(Ok(status)) => Outcome::Exited(status),
+            Ok(Err(e)) => Outcome::Failed(format!("waiting for {}: {}", program, e)),
+            Err(outcome) => {
+                // Whatever the command started goes too, or it could hold
+                // the deploy checkout or the output pipes indefinitely.
+                if let Some(pid) = pid {
+                    sandbox::kill_group(pid);
+                }
+                let _ = child.kill().await;
+                outcome
+            }
+        }
+    }
+
+    fn step_started(&self, id: u64, name: &str) -> usize {
+        self.append(id, Stream::Stdout, format!("==> {}\n", name));
+        let mut inner = self.inner.lock().unwrap();
+        let Some(job) = inner.jobs.get_mut(&id) else { return 0 };
+        job.info.steps.push(StepInfo {
+            name: name.to_string(),
+            status: Status::Running,
+            started_at: Utc::now(),
+            finished_at: None,
+            duration_ms: None,
+        });
+        job.updates.send_replace(job.log.len());
+        job.info.steps.len() - 1
+    }
+
+    fn step_finished(&self, id: u64, index: usize, status: Status) {
+        let summary = {
+            let mut inner = self.inner.lock().unwrap();
+            let Some(step) = inner.jobs.get_mut(&id).and_then(|job| job.info.steps.get_mut(index)) else { return };
+            let now = Utc::now();
+            let duration = (now - step.started_at).num_milliseconds();
+            step.status = status;
+            step.finished_at = Some(now);
+            step.duration_ms = Some(duration);
+            format!("==> {} {} in {:.1}s\n", step.name, status.name(), duration as f64 / 1000.0)
+        };
+        self.append(id, Stream::Stdout, summary);
+    }
+
+    async fn capture(&self, id: u64, stream: Stream, reader: impl AsyncRead + Unpin) {
+        let mut reader = BufReader::new(reader);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {
+                    let mut line = String::from_utf8_lossy(&buf).into_owned();
+                    if !line.ends_with('\n') {
+                        line.push('\n');
+                    }
+                    self.append(id, stream, line);
+                }
+            }
+        }
+    }
+
+    fn append(&self, id: u64, stream: Stream, line: String) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(job) = inner.jobs.get_mut(&id) else { return };
+        if job.truncated {
+            return;
+        }
+        if job.log_bytes + line.len() > self.config.max_log_bytes {
+            job.truncated = true;
+            job.log.push((Stream::Stderr, "[output truncated]\n".into()));
+        } else {
+            job.log_bytes += line.len();
+            job.log.push((stream, line));
+        }
+        job.updates.send_replace(job.log.len());
+    }
+
+    fn finish(&self, id: u64, status: Status, exit_code: Option<i32>, error: Option<String>) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let Some(job) = inner.jobs.get_mut(&id) else { return };
+        let info = &mut job.info;
+        info.status = status;
+        info.finished_at = Some(Utc::now());
+        info.exit_code = exit_code;
+        info.error = error;
+        match (&info.exit_code, &info.error) {
+            (_, Some(e)) => println!("job {}: {}: {}", id, status.name(), e),
+            (Some(code), None) => println!("job {}: exited with {}", id, code),
+            (None, None) => println!("job {}: finished", id),
+        }
+        if let (Status::Succeeded, Some(sha)) = (status, &info.trigger.sha) {
+            inner.deployed.insert(info.trigger.action.clone(), sha.clone());
+        }
+        job.updates.send_replace(job.log.len());
+        inner.notifier.send(&job.info);
+        self.evict(inner);
+    }
+
+    fn cancelled(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        if let Some(job) = inner.jobs.get_mut(&id) {
+            let reason = job.cancel_reason.take().unwrap_or_else(|| "cancelled".into());
+            job.cancelled(reason, &inner.notifier);
+        }
+        self.evict(inner);
+    }
+
+    /// Drop the oldest finished jobs until at most `max_jobs` remain.
+    fn evict(&self, inner: &mut Inner) {
+        while inner.jobs.len() > self.config.max_jobs {
+            let oldest = inner.jobs.iter().find(|(_, job)| job.info.status.is_finished()).map(|(id, _)| *id);
+            match oldest {
+                Some(id) => inner.jobs.remove(&id),
+                None => break,
+            };
+        }
+    }
+
+    fn list(&self) -> Vec<JobInfo> {
+        self.inner.lock().unwrap().jobs.values().rev().map(|job| job.info.clone()).collect()
+    }
+
+    fn detail(&self, id: u64) -> Option<JobDetail> {
+        let inner = self.inner.lock().unwrap();
+        let job = inner.jobs.get(&id)?;
+        let collect = |stream: Stream| job.log.iter().filter(|(s, _)| *s == stream).map(|(_, l)| l.as_str()).collect();
+        Some(JobDetail {
+            info: job.info.clone(),
+            stdout: collect(Stream::Stdout),
+            stderr: collect(Stream::Stderr),
+            truncated: job.truncated,
+        })
+    }
+
+    /// The job's combined output so far, then each new line until it exits.
+    fn follow(&self, id: u64) -> Option<Body> {
+        let updates = self.inner.lock().unwrap().jobs.get(&id)?.updates.subscribe();
+        let stream = futures::stream::unfold((self.clone(), 0, updates), move |(store, pos, mut updates)| async move {
+            loop {
+                let (chunk, end, done) = {
+                    let inner = store.inner.lock().unwrap();
+                    let job = inner.jobs.get(&id)?;
+                    let chunk: Vec<&str> = job.log[pos..].iter().map(|(_, line)| line.as_str()).collect();
+                    (chunk.concat(), job.log.len(), job.info.status.is_finished())
+                };
+                if end > pos {
+                    return Some((Ok::<_, Infallible>(Bytes::from(chunk)), (store, end, updates)));
+                }
+                if done || updates.changed().await.is_err() {
+                    return None;
+                }
+            }
+        });
+        Some(Body::from_stream(stream))
+    }
+}
+
+/// A script run for `trigger`, in the sandbox's working directory if set.
+fn script(trigger: &Trigger, path: &str) -> Command {
+    let mut command = Command::new(path);
+    if let Some(cwd) = &trigger.sandbox.cwd {
+        command.current_dir(cwd);
+    }
+    command
+}
+
+/// `GET /jobs`: every retained job, newest first.
+pub async fn list_jobs(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Some(response) = unauthorized(&state.config.server, &headers) {
+        return response;
+    }
+    Json(state.jobs.list()).into_response()
+}
+
+/// `GET /jobs/:id`: one job with its captured stdout and stderr.
+pub async fn get_job(State(state): State<Arc<AppState>>, Path(id): Path<u64>, headers: HeaderMap) -> Response {
+    if let Some(response) = unauthorized(&state.config.server, &headers) {
+        return response;
+    }
+    match state.jobs.detail(id) {
+        Some(detail) => Json(detail).into_response(),
+        None => (StatusCode::NOT_FOUND, "no such job\n").into_response(),
+    }
+}
+
+/// `POST /jobs/:id/cancel`: drop a queued job, or kill a running one.
+pub async fn cancel_job(State(state): State<Arc<AppState>>, Path(id): Path<u64>, headers: HeaderMap) -> Response {
+    if let Some(response) = unauthorized(&state.config.server, &headers) {
+        return response;
+    }
+    let mut inner = state.jobs.inner.lock().unwrap();
+    if !inner.jobs.contains_key(&id) {
+        return (StatusCode::NOT_FOUND, "no such job\n").into_response();
+    }
+    if !inner.cancel(id, "cancelled through the API".into()) {
+        return (StatusCode::CONFLICT, "job already finished\n").into_response();
+    }
+    StatusCode::ACCEPTED.into_response()
+}
+
+/// `GET /jobs/:id/log`: combined output, streamed until the job exits.
+pub async fn job_log(State(state): State<Arc<AppState>>, Path(id): Path<u64>, headers: HeaderMap) -> Response {
+    if let Some(response) = unauthorized(&state.config.server, &headers) {
+        return response;
+    }
+    match state.jobs.follow(id) {
+        Some(body) => ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], body).into_response(),
+        None => (StatusCode::NOT_FOUND, "no such job\n").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    use super::*;
+
+    /// A directory for one test's scripts.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("webhook-jobs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// An executable shell script in `dir`. Returns its path.
+    fn script_file(dir: &std::path::Path, name: &str, body: &str) -> String {
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// An action that appends its SHA to `<script>.log`, then sleeps `secs`.
+    fn action(dir: &std::path::Path, name: &str, secs: f64) -> String {
+        script_file(dir, name, &format!("echo \"$SHA\" >> \"$0.log\"\nsleep {}\n", secs))
+    }
+
+    /// The SHAs the action ran for, in order.
+    fn runs(action: &str) -> Vec<String> {
+        fs::read_to_string(format!("{}.log", action)).unwrap_or_default().lines().map(String::from).collect()
+    }
+
+    pub(crate) fn trigger(action: &str, sha: &str) -> Trigger {
+        Trigger {
+            route: None,
+            action: action.into(),
+            event: "push".into(),
+            repository: Some("org/app".into()),
+            git_ref: Some("refs/heads/main".into()),
+            sha: Some(sha.into()),
+            pusher: None,
+            delivery_id: None,
+            payload: Bytes::new(),
+            deploy: None,
+            health_check: None,
+            rollback: None,
+            sandbox: SandboxConfig::default(),
+        }
+    }
+
+    fn store(config: JobsConfig) -> JobStore {
+        JobStore::new(config, DeployConfig::default(), Notifier::default())
+    }
+
+    /// Whether `pid` is still running; zombies left for init to reap are not.
+    fn alive(pid: &str) -> bool {
+        match fs::read_to_string(format!("/proc/{}/stat", pid.trim())) {
+            Ok(stat) => !stat.rsplit(')').next().unwrap_or("").trim_start().starts_with('Z'),
+            Err(_) => false,
+        }
+    }
+
+    /// Wait until job `id` is in `status`, and return it.
+    pub(crate) async fn wait_for(store: &JobStore, id: u64, status: Status) -> JobInfo {
+        for _ in 0..1000 {
+            let info = store.detail(id).expect("job is retained").info;
+            if info.status == status {
+                return info;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("job {} never became {}", id, status.name());
+    }
+
+    fn status(store: &JobStore, id: u64) -> Status {
+        store.detail(id).unwrap().info.status
+    }
+
+    #[tokio::test]
+    async fn newer_jobs_supersede_queued_ones() {
+        let dir = scratch("supersede");
+        let deploy = action(&dir, "deploy", 0.3);
+        let store = store(JobsConfig::default());
+        let first = store.enqueue(trigger(&deploy, "a1"));
+        wait_for(&store, first, Status::Running).await;
+        let second = store.enqueue(trigger(&deploy, "b2"));
+        let third = store.enqueue(trigger(&deploy, "c3"));
+
+        let superseded = wait_for(&store, second, Status::Cancelled).await;
+        assert_eq!(superseded.error.as_deref(), Some("superseded by job 3"));
+        assert_eq!(status(&store, first), Status::Running, "running jobs are left alone by default");
+        wait_for(&store, third, Status::Succeeded).await;
+        assert_eq!(status(&store, first), Status::Succeeded);
+        assert_eq!(runs(&deploy), ["a1", "c3"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancel_running_supersedes_running_jobs_too() {
+        let dir = scratch("cancel-running");
+        let deploy = action(&dir, "deploy", 30.0);
+        let store = store(JobsConfig { cancel_running: true, ..JobsConfig::default() });
+        let first = store.enqueue(trigger(&deploy, "a1"));
+        wait_for(&store, first, Status::Running).await;
+        let second = store.enqueue(trigger(&deploy, "b2"));
+        let killed = wait_for(&store, first, Status::Cancelled).await;
+        assert_eq!(killed.error.as_deref(), Some("superseded by job 2"));
+        wait_for(&store, second, Status::Running).await;
+        store.inner.lock().unwrap().cancel(second, "done".into());
+        wait_for(&store, second, Status::Cancelled).await;
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn debounce_merges_bursts() {
+        let dir = scratch("debounce");
+        let deploy = action(&dir, "deploy", 0.0);
+        let store = store(JobsConfig { debounce_ms: 200, ..JobsConfig::default() });
+        let burst: Vec<u64> = ["a1", "b2", "c3"].iter().map(|sha| store.enqueue(trigger(&deploy, sha))).collect();
+        for &id in &burst[..2] {
+            assert_eq!(status(&store, id), Status::Cancelled);
+        }
+        assert_eq!(status(&store, burst[2]), Status::Queued, "still waiting out the debounce");
+        wait_for(&store, burst[2], Status::Succeeded).await;
+        assert_eq!(runs(&deploy), ["c3"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn respects_concurrency_per_action() {
+        let dir = scratch("concurrency");
+        let deploy = action(&dir, "deploy", 0.5);
+        let other = action(&dir, "other", 0.0);
+        let store = store(JobsConfig { concurrency: 2, ..JobsConfig::default() });
+        let first = store.enqueue(trigger(&deploy, "a1"));
+        wait_for(&store, first, Status::Running).await;
+        let second = store.enqueue(trigger(&deploy, "b2"));
+        wait_for(&store, second, Status::Running).await;
+        let third = store.enqueue(trigger(&deploy, "c3"));
+        // Another action has its own queue and is not held up.
+        let unrelated = store.enqueue(trigger(&other, "d4"));
+        wait_for(&store, unrelated, Status::Succeeded).await;
+        assert_eq!(status(&store, third), Status::Queued);
+        assert_eq!(store.inner.lock().unwrap().queues[&deploy].running.len(), 2);
+
+        let started = wait_for(&store, third, Status::Running).await.started_at.unwrap();
+        let finished = |id| store.detail(id).unwrap().info.finished_at;
+        assert!(finished(first).or(finished(second)).is_some_and(|at| at <= started));
+        wait_for(&store, third, Status::Succeeded).await;
+        assert!(!store.inner.lock().unwrap().queues.contains_key(&deploy), "idle queues are dropped");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancels_running_and_queued_jobs() {
+        let dir = scratch("cancel");
+        let deploy = action(&dir, "deploy", 30.0);
+        let store = store(JobsConfig::default());
+        let running = store.enqueue(trigger(&deploy, "a1"));
+        wait_for(&store, running, Status::Running).await;
+        let queued = store.enqueue(trigger(&deploy, "b2"));
+
+        assert!(store.inner.lock().unwrap().cancel(queued, "cancelled through the API".into()));
+        assert_eq!(status(&store, queued), Status::Cancelled);
+        let started = Instant::now();
+        assert!(store.inner.lock().unwrap().cancel(running, "cancelled through the API".into()));
+        let cancelled = wait_for(&store, running, Status::Cancelled).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(cancelled.error.as_deref(), Some("cancelled through the API"));
+        assert!(!store.inner.lock().unwrap().cancel(running, "again".into()), "finished jobs cannot be cancelled");
+        assert_eq!(runs(&deploy), ["a1"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn kills_the_job_and_its_children_at_the_timeout() {
+        let dir = scratch("timeout");
+        let hang = script_file(&dir, "hang", "sleep 60 &\necho $! > \"$0.child\"\nsleep 60\n");
+        let store = store(JobsConfig::default());
+        let mut slow = trigger(&hang, "a1");
+        slow.sandbox.timeout_secs = 1;
+        let started = Instant::now();
+        let id = store.enqueue(slow);
+        let failed = wait_for(&store, id, Status::Failed).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(failed.error, Some(format!("{} timed out after 1s", hang)));
+        let child = fs::read_to_string(format!("{}.child", hang)).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!alive(&child), "background child {} survived the timeout", child.trim());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn waiting_for_the_checkout_counts_against_the_timeout() {
+        let store = store(JobsConfig::default());
+        let held = store.checkout.lock().await;
+        let mut blocked = trigger("deploy checkout", "0123abc");
+        blocked.deploy = Some(DeployRoute { app: None, steps: vec![Step::Checkout] });
+        blocked.sandbox.timeout_secs = 1;
+        let id = store.enqueue(blocked.clone());
+        let failed = wait_for(&store, id, Status::Failed).await;
+        assert_eq!(failed.error.as_deref(), Some("waiting for the checkout timed out after 1s"));
+        assert_eq!(failed.steps[0].status, Status::Failed);
+
+        blocked.sandbox.timeout_secs = 3600;
+        let id = store.enqueue(blocked);
+        wait_for(&store, id, Status::Running).await;
+        store.inner.lock().unwrap().cancel(id, "cancelled through the API".into());
+        let cancelled = wait_for(&store, id, Status::Cancelled).await;
+        assert_eq!(cancelled.steps[0].status, Status::Cancelled);
+        drop(held);
+    }
+
+    #[tokio::test]
+    async fn withholds_our_environment_from_actions() {
+        std::env::set_var("WEBHOOK_SECRET", "do-not-leak");
+        let dir = scratch("env");
+        let dump = script_file(&dir, "dump", "env > \"$0.env\"\ncat > \"$0.stdin\"\n");
+        let store = store(JobsConfig::default());
+        let mut push = trigger(&dump, "a1");
+        push.payload = Bytes::from_static(b"{\"ref\":\"refs/heads/main\"}");
+        let id = store.enqueue(push);
+        wait_for(&store, id, Status::Succeeded).await;
+
+        let env = fs::read_to_string(format!("{}.env", dump)).unwrap();
+        let names: Vec<&str> = env.lines().filter_map(|line| line.split_once('=')).map(|(name, _)| name).collect();
+        assert!(!names.contains(&"WEBHOOK_SECRET"), "{}", env);
+        assert!(names.contains(&"PATH"), "{}", env);
+        for var in ["EVENT=push", "REPO=org/app", "REF=refs/heads/main", "SHA=a1"] {
+            assert!(env.lines().any(|line| line == var), "{} missing from {}", var, env);
+        }
+        assert_eq!(fs::read(format!("{}.stdin", dump)).unwrap(), b"{\"ref\":\"refs/heads/main\"}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn passes_the_event_in_env_and_the_payload_on_stdin() {
+        let dir = scratch("event");
+        let dump = script_file(&dir, "dump", "env > \"$0.env\"\ncat > \"$0.stdin\"\n");
+        let deaf = script_file(&dir, "deaf", "exit 0\n");
+        // Larger than a pipe's buffer, so the action must read it as it runs.
+        let payload = Bytes::from(vec![b'x'; 256 * 1024]);
+        let store = store(JobsConfig::default());
+        let mut push = trigger(&dump, "a1");
+        push.pusher = Some("cy".into());
+        push.delivery_id = Some("d-1".into());
+        push.payload = payload.clone();
+        let id = store.enqueue(push);
+        wait_for(&store, id, Status::Succeeded).await;
+
+        let env = fs::read_to_string(format!("{}.env", dump)).unwrap();
+        for var in ["PUSHER=cy", "DELIVERY_ID=d-1"] {
+            assert!(env.lines().any(|line| line == var), "{} missing from {}", var, env);
+        }
+        assert_eq!(fs::read(format!("{}.stdin", dump)).unwrap(), payload);
+
+        // An action that never reads its stdin is not failed for it.
+        let mut push = trigger(&deaf, "a1");
+        push.payload = payload;
+        let id = store.enqueue(push);
+        wait_for(&store, id, Status::Succeeded).await;
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn applies_the_cpu_limit() {
+        let dir = scratch("rlimit");
+        let spin = script_file(&dir, "spin", "while :; do :; done\n");
+        let store = store(JobsConfig::default());
+        let mut busy = trigger(&spin, "a1");
+        busy.sandbox.cpu_secs = Some(1);
+        busy.sandbox.timeout_secs = 30;
+        let id = store.enqueue(busy);
+        let failed = wait_for(&store, id, Status::Failed).await;
+        assert!(failed.error.as_deref().is_some_and(|e| e.starts_with("terminated by signal")), "{:?}", failed.error);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A health URL answering with `status`, which the test can change.
+    async fn health_server(status: Arc<AtomicU16>) -> String {
+        let health = move || async move {
+            StatusCode::from_u16(status.load(Ordering::SeqCst)).unwrap()
+        };
+        let app = axum::Router::new().route("/health", axum::routing::get(health));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/health", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    #[tokio::test]
+    async fn rolls_back_to_the_last_healthy_commit() {
+        let dir = scratch("rollback");
+        let deploy = action(&dir, "deploy", 0.0);
+        let rollback = action(&dir, "rollback", 0.0);
+        let status = Arc::new(AtomicU16::new(200));
+        let url = health_server(status.clone()).await;
+        let store = store(JobsConfig::default());
+        let checked = |sha: &str| Trigger {
+            health_check: Some(HealthCheck { url: url.clone(), timeout_secs: 1, interval_secs: 1 }),
+            rollback: Some(rollback.clone()),
+            ..trigger(&deploy, sha)
+        };
+
+        // Nothing deployed before: unhealthy, but nothing to go back to.
+        status.store(503, Ordering::SeqCst);
+        let id = store.enqueue(checked("a1"));
+        let failed = wait_for(&store, id, Status::Failed).await;
+        assert!(failed.error.unwrap().ends_with("; no previous commit to roll back to"));
+
+        status.store(200, Ordering::SeqCst);
+        let id = store.enqueue(checked("b2"));
+        let healthy = wait_for(&store, id, Status::Succeeded).await;
+        assert_eq!(healthy.steps.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(), ["health"]);
+
+        status.store(503, Ordering::SeqCst);
+        let id = store.enqueue(checked("c3"));
+        let rolled_back = wait_for(&store, id, Status::RolledBack).await;
+        assert_eq!(rolled_back.previous_sha.as_deref(), Some("b2"));
+        let error = rolled_back.error.unwrap();
+        assert!(error.starts_with("unhealthy after 1s: ") && error.ends_with("; rolled back to b2"), "{}", error);
+        let steps: Vec<_> = rolled_back.steps.iter().map(|s| (s.name.as_str(), s.status)).collect();
+        assert_eq!(steps, [("health", Status::Failed), ("rollback", Status::Succeeded)]);
+        assert_eq!(runs(&deploy), ["a1", "b2", "c3"]);
+        assert_eq!(runs(&rollback), ["b2"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_jobs_their_output_and_cancellation() {
+        let dir = scratch("api");
+        let build =
+            script_file(&dir, "build", "echo \"built $SHA\"\necho warning >&2\n[ \"$SHA\" = b2 ] && sleep 30\nexit 0\n");
+        let url = crate::tests::serve(&format!(
+            r#"
+            [server]
+            api_token = "t0ken"
+
+            [[providers]]
+            kind = "github"
+            path = "/github"
+            secret = "s3cr3t-value"
+
+            [actions]
+            script = "{}"
+            "#,
+            build
+        ))
+        .await;
+        let github = format!("{}/github", url);
+        let push = |sha: &str| {
+            let (github, body) = (&github, format!(r#"{{"ref":"refs/heads/main","after":"{}"}}"#, sha));
+            async move { crate::tests::push_body(github, "s3cr3t-value", body.as_bytes()).await }
+        };
+        let client = reqwest::Client::new();
+        let get = |path: &str| client.get(format!("{}{}", url, path)).bearer_auth("t0ken").send();
+        let cancel = |id: u64| client.post(format!("{}/jobs/{}/cancel", url, id)).bearer_auth("t0ken").send();
+
+        let settled = |id: u64| async move {
+            let mut job = serde_json::Value::Null;
+            for _ in 0..500 {
+                job = get(&format!("/jobs/{}", id)).await.unwrap().json().await.unwrap();
+                if job["status"] != "queued" && job["status"] != "running" {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            job
+        };
+
+        assert_eq!(push("a1").await, StatusCode::ACCEPTED);
+        let job = settled(1).await;
+        assert_eq!(job["status"], "succeeded", "{}", job);
+        assert_eq!(job["sha"], "a1");
+        assert_eq!(job["exit_code"], 0);
+        assert_eq!(job["stdout"], "built a1\n");
+        assert_eq!(job["stderr"], "warning\n");
+        let log = get("/jobs/1/log").await.unwrap().text().await.unwrap();
+        assert!(log.contains("built a1\n") && log.contains("warning\n"), "{}", log);
+
+        assert_eq!(push("b2").await, StatusCode::ACCEPTED);
+        let jobs: Vec<serde_json::Value> = get("/jobs").await.unwrap().json().await.unwrap();
+        let ids: Vec<_> = jobs.iter().map(|job| job["id"].as_u64().unwrap()).collect();
+        assert_eq!(ids, [2, 1]);
+        assert_eq!(cancel(2).await.unwrap().status(), StatusCode::ACCEPTED);
+        assert_eq!(cancel(1).await.unwrap().status(), StatusCode::CONFLICT);
+        assert_eq!(cancel(9).await.unwrap().status(), StatusCode::NOT_FOUND);
+        assert_eq!(get("/jobs/9").await.unwrap().status(), StatusCode::NOT_FOUND);
+        let job = settled(2).await;
+        assert_eq!(job["status"], "cancelled", "{}", job);
+        assert_eq!(job["error"], "cancelled through the API");
+
+        let anonymous = client.get(format!("{}/jobs", url)).send().await.unwrap();
+        assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+        let wrong = client.get(format!("{}/jobs/1/log", url)).bearer_auth("wrong").send().await.unwrap();
+        assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
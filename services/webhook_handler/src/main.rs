@@ -1,66 +1,258 @@
-use std::{env, process::Stdio};
-use axum::{routing::post, Router, extract::State};
+use axum::{routing::{get, post}, Router, extract::{MatchedPath, State}, Json};
 use axum::http::StatusCode;
-use axum::response::IntoResponse;
-use hmac::{Hmac, Mac};
-use sha2::Sha256;
-use tokio::{process::Command, sync::Mutex};
+use axum::response::{IntoResponse, Response};
+use anyhow::{Context, Result};
+use serde_json::json;
 use std::sync::Arc;
+use std::time::Duration;
+
+mod auth;
+mod config;
+mod deliveries;
+mod deploy;
+mod health;
+mod jobs;
+mod notify;
+mod providers;
+mod replay;
+mod routing;
+mod sandbox;
+
+use config::{is_placeholder_secret, Config, ProviderConfig};
+use deliveries::{Delivery, DeliveryLog, Verification};
+use jobs::{JobStore, Trigger};
+use notify::Notifier;
+use replay::ReplayGuard;
 
-#[derive(Clone)]
 struct AppState {
-    secret: String,
-    script: Option<String>,
+    config: Config,
+    jobs: JobStore,
+    replay: ReplayGuard,
+    deliveries: DeliveryLog,
 }
 
 #[axum::debug_handler]
 async fn handle_webhook(
-    State(state): State<Arc<Mutex<AppState>>>,
+    State(state): State<Arc<AppState>>,
+    path: MatchedPath,
     headers: axum::http::HeaderMap,
     body: bytes::Bytes,
-) -> impl IntoResponse {
-    let signature = headers.get("X-Hub-Signature-256").and_then(|v| v.to_str().ok());
-    let secret = { state.lock().await.secret.clone() };
-    if let Some(sig) = signature {
-        if verify_signature(&secret, &body, sig) {
-            if let Some(script) = { state.lock().await.script.clone() } {
-                let _ = run_script(&script).await;
-            }
-            StatusCode::OK
-        } else {
-            StatusCode::UNAUTHORIZED
+) -> Response {
+    let provider = state.config.providers.iter().find(|p| p.path == path.as_str()).expect("routed by provider path");
+    let server = &state.config.server;
+    let reject = |verification, status, message: &'static str| {
+        state.deliveries.record(Delivery::new(provider, &headers, &body, verification));
+        (status, message).into_response()
+    };
+    if is_placeholder_secret(providers::secret(provider, server)) && !server.allow_default_secret {
+        return reject(Verification::NoSecret, StatusCode::FORBIDDEN, "no webhook secret configured\n");
+    }
+    if let Err(status) = providers::verify(provider, server, &headers, &body) {
+        let verification =
+            if status == StatusCode::BAD_REQUEST { Verification::MissingSignature } else { Verification::BadSignature };
+        return reject(verification, status, "");
+    }
+    if let Some(id) = providers::delivery_id(provider, &headers) {
+        if server.replay_ttl_secs > 0 && !state.replay.first_delivery(&format!("{} {}", provider.path, id)) {
+            println!("rejecting replayed delivery {} on {}", id, provider.path);
+            return reject(Verification::Duplicate, StatusCode::CONFLICT, "duplicate delivery\n");
         }
-    } else {
-        StatusCode::BAD_REQUEST
+    }
+    let delivery = Delivery::new(provider, &headers, &body, Verification::Verified);
+    match dispatch(&state, provider, &headers, body, delivery).0 {
+        Some(id) => (StatusCode::ACCEPTED, Json(json!({ "job_id": id }))).into_response(),
+        None => (StatusCode::OK, "no matching route\n").into_response(),
     }
 }
 
-fn verify_signature(secret: &str, body: &[u8], signature: &str) -> bool {
-    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac can take key of any size");
-    mac.update(body);
-    let result = mac.finalize();
-    let expected = hex::encode(result.into_bytes());
-    let sig_clean = signature.trim_start_matches("sha256=");
-    expected.eq_ignore_ascii_case(sig_clean)
+/// Route a verified delivery and queue its action, then record it. Returns
+/// the job ID, if a route matched, and the delivery's ID.
+fn dispatch(
+    state: &AppState,
+    provider: &ProviderConfig,
+    headers: &axum::http::HeaderMap,
+    body: bytes::Bytes,
+    mut delivery: Delivery,
+) -> (Option<u64>, u64) {
+    let event = providers::event(provider, headers, &body);
+    delivery.event = Some(event.kind.clone());
+
+    let (route, action) = if state.config.routes.is_empty() {
+        (None, state.config.actions.script.clone())
+    } else {
+        match routing::route(&state.config.routes, &event) {
+            Some(route) => {
+                println!("{} event matched route {}", event.kind, route.name);
+                (Some(route), Some(route.action_name()))
+            }
+            None => (None, None),
+        }
+    };
+    let Some(action) = action else {
+        println!(
+            "ignoring {} event for {} ({}): no matching route",
+            event.kind,
+            event.repository.as_deref().unwrap_or("-"),
+            event.git_ref.as_deref().unwrap_or("-")
+        );
+        return (None, state.deliveries.record(delivery));
+    };
+    delivery.route = route.map(|r| r.name.clone());
+    delivery.action = Some(action.clone());
+    let id = state.jobs.enqueue(Trigger {
+        route: route.map(|r| r.name.clone()),
+        action,
+        event: event.kind,
+        repository: event.repository,
+        git_ref: event.git_ref,
+        sha: event.sha,
+        pusher: event.pusher,
+        delivery_id: providers::delivery_id(provider, headers).map(str::to_string),
+        payload: body,
+        deploy: route.and_then(|r| r.deploy.clone()),
+        health_check: route.and_then(|r| r.health_check.clone()),
+        rollback: route.and_then(|r| r.rollback.clone()),
+        sandbox: route.and_then(|r| r.sandbox.clone()).unwrap_or_else(|| state.config.sandbox.clone()),
+    });
+    delivery.job_id = Some(id);
+    (Some(id), state.deliveries.record(delivery))
 }
 
-async fn run_script(path: &str) {
-    let _ = Command::new(path)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn();
+/// Each provider's path, and the jobs and deliveries APIs.
+fn router(state: Arc<AppState>) -> Router {
+    let mut app = Router::new();
+    for provider in &state.config.providers {
+        app = app.route(&provider.path, post(handle_webhook));
+    }
+    app.route("/jobs", get(jobs::list_jobs))
+        .route("/jobs/:id", get(jobs::get_job))
+        .route("/jobs/:id/log", get(jobs::job_log))
+        .route("/jobs/:id/cancel", post(jobs::cancel_job))
+        .route("/deliveries", get(deliveries::list_deliveries))
+        .route("/deliveries/:id", get(deliveries::get_delivery))
+        .route("/deliveries/:id/replay", post(deliveries::replay_delivery))
+        .with_state(state)
 }
 
 #[tokio::main]
-async fn main() {
-    let port = env::var("WEBHOOK_PORT").unwrap_or_else(|_| "9000".into());
-    let secret = env::var("WEBHOOK_SECRET").unwrap_or_else(|_| "secret".into());
-    let script = env::var("ACTION_SCRIPT").ok();
-    let state = Arc::new(Mutex::new(AppState { secret, script }));
-
-    let app = Router::new().route("/", post(handle_webhook)).with_state(state);
+async fn main() -> Result<()> {
+    let config = Config::load().context("loading configuration")?;
+    let port = config.server.port;
+    let notifier = Notifier::new(config.notify.clone());
+    let jobs = JobStore::new(config.jobs.clone(), config.deploy.clone(), notifier);
+    for provider in &config.providers {
+        println!("accepting {:?} deliveries on {}", provider.kind, provider.path);
+        if is_placeholder_secret(providers::secret(provider, &config.server)) && !config.server.allow_default_secret {
+            eprintln!("warning: no secret set for {}, its deliveries will be rejected", provider.path);
+        }
+    }
+    let replay = ReplayGuard::new(Duration::from_secs(config.server.replay_ttl_secs));
+    let deliveries = DeliveryLog::new(config.deliveries.clone());
+    deliveries.restore().context("restoring the delivery log")?;
+    let app = router(Arc::new(AppState { config, jobs, replay, deliveries }));
     let addr = format!("0.0.0.0:{}", port);
     println!("listening on {}", addr);
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::*;
+
+    /// Serve `config` on a local port and return its base URL.
+    pub(crate) async fn serve(config: &str) -> String {
+        let config: Config = toml::from_str(config).unwrap();
+        let jobs = JobStore::new(config.jobs.clone(), config.deploy.clone(), Notifier::default());
+        let replay = ReplayGuard::new(Duration::from_secs(config.server.replay_ttl_secs));
+        let deliveries = DeliveryLog::new(config.deliveries.clone());
+        let app = router(Arc::new(AppState { config, jobs, replay, deliveries }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    /// Post a push to `url` signed as GitHub would with `secret`.
+    async fn push(url: &str, secret: &str) -> reqwest::StatusCode {
+        push_body(url, secret, br#"{"ref":"refs/heads/main"}"#).await
+    }
+
+    /// Post `body` as a push to `url`, signed as GitHub would with `secret`.
+    pub(crate) async fn push_body(url: &str, secret: &str, body: &[u8]) -> reqwest::StatusCode {
+        signed_push(url, secret, body).send().await.unwrap().status()
+    }
+
+    /// A push of `body` to `url`, signed as GitHub would with `secret`.
+    fn signed_push(url: &str, secret: &str, body: &[u8]) -> reqwest::RequestBuilder {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+        reqwest::Client::new()
+            .post(url)
+            .header("X-GitHub-Event", "push")
+            .header("X-Hub-Signature-256", signature)
+            .body(body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn refuses_providers_left_on_a_placeholder_secret() {
+        let url = serve(
+            r#"
+            [server]
+            secret = "change_me"
+
+            [[providers]]
+            kind = "github"
+            path = "/github"
+
+            [[providers]]
+            kind = "github"
+            path = "/default"
+            secret = "secret"
+
+            [[providers]]
+            kind = "github"
+            path = "/real"
+            secret = "s3cr3t-value"
+            "#,
+        )
+        .await;
+        assert_eq!(push(&format!("{}/github", url), "change_me").await, StatusCode::FORBIDDEN);
+        assert_eq!(push(&format!("{}/default", url), "secret").await, StatusCode::FORBIDDEN);
+        assert_eq!(push(&format!("{}/real", url), "s3cr3t-value").await, StatusCode::OK);
+        assert_eq!(push(&format!("{}/real", url), "change_me").await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_delivery_id_seen_before() {
+        let url = serve(
+            r#"
+            [server]
+            replay_ttl_secs = 60
+
+            [[providers]]
+            kind = "github"
+            path = "/github"
+            secret = "s3cr3t-value"
+
+            [[providers]]
+            kind = "github"
+            path = "/other"
+            secret = "s3cr3t-value"
+            "#,
+        )
+        .await;
+        let send = |path: &str, id: &'static str| {
+            signed_push(&format!("{}{}", url, path), "s3cr3t-value", b"{}").header("X-GitHub-Delivery", id).send()
+        };
+        assert_eq!(send("/github", "d-1").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(send("/github", "d-2").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(send("/github", "d-1").await.unwrap().status(), StatusCode::CONFLICT);
+        assert_eq!(send("/other", "d-1").await.unwrap().status(), StatusCode::OK);
+    }
 }
@@ -0,0 +1,334 @@
+//! Tells developers how the deploys their pushes triggered went: commit or
+//! deployment statuses on the forge, and messages to chat webhooks.
+//!
+//! Job changes are queued and reported one at a time by a background task,
+//! so a slow or failing endpoint never holds up a job, and the statuses of a
+//! job reach the forge in the order they happened.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use reqwest::{Client, RequestBuilder};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::config::{ForgeApi, MessageFormat, NotifyConfig, StatusConfig};
+use crate::jobs::{JobInfo, Status};
+use crate::routing::glob;
+
+/// Descriptions longer than this are rejected by GitHub.
+const MAX_DESCRIPTION: usize = 140;
+
+#[derive(Clone, Default)]
+pub struct Notifier {
+    /// `None` when nothing is configured.
+    tx: Option<mpsc::UnboundedSender<JobInfo>>,
+}
+
+impl Notifier {
+    pub fn new(config: NotifyConfig) -> Self {
+        if config.status.is_none() && config.webhooks.is_empty() {
+            return Self::default();
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        let client = Client::builder().timeout(Duration::from_secs(10)).build().expect("building HTTP client");
+        let worker = Worker { config, client, deployments: HashMap::new() };
+        tokio::spawn(worker.run(rx));
+        Self { tx: Some(tx) }
+    }
+
+    /// Report `job`'s current state.
+    pub fn send(&self, job: &JobInfo) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(job.clone());
+        }
+    }
+}
+
+struct Worker {
+    config: NotifyConfig,
+    client: Client,
+    /// GitHub deployment IDs by job, created when the job is first reported.
+    deployments: HashMap<u64, u64>,
+}
+
+impl Worker {
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<JobInfo>) {
+        while let Some(job) = rx.recv().await {
+            if let Some(status) = self.config.status.clone() {
+                if let Err(e) = self.post_status(&status, &job).await {
+                    eprintln!("notify: status for job {}: {:#}", job.id, e);
+                }
+            }
+            for webhook in &self.config.webhooks {
+                if !webhook.on.iter().any(|s| s == job.status.name()) {
+                    continue;
+                }
+                let body = match webhook.format {
+                    MessageFormat::Slack => json!({ "text": self.message(&job) }),
+                    MessageFormat::Discord => json!({ "content": self.message(&job) }),
+                    MessageFormat::Json => json!(job),
+                };
+                let sent = send(self.client.post(&webhook.url).json(&body)).await;
+                if let Err(e) = sent {
+                    eprintln!("notify: {} for job {}: {:#}", webhook.url, job.id, e);
+                }
+            }
+            if job.status.is_finished() {
+                self.deployments.remove(&job.id);
+            }
+        }
+    }
+
+    async fn post_status(&mut self, config: &StatusConfig, job: &JobInfo) -> Result<()> {
+        let (Some(repo), Some(sha)) = (&job.trigger.repository, &job.trigger.sha) else { return Ok(()) };
+        if config.repository.as_deref().is_some_and(|pattern| !glob(pattern, repo)) {
+            return Ok(());
+        }
+        let api = config.api_url.trim_end_matches('/');
+        let description = truncate(&self.description(job), MAX_DESCRIPTION);
+        if !config.deployments {
+            let state = match job.status {
+                Status::Queued | Status::Running => "pending",
+                Status::Succeeded => "success",
+                Status::Failed | Status::RolledBack => "failure",
+                Status::Cancelled => "error",
+            };
+            let mut body = json!({ "state": state, "context": config.context, "description": description });
+            if let Some(url) = self.job_url(job) {
+                body["target_url"] = json!(url);
+            }
+            let url = format!("{}/repos/{}/statuses/{}", api, repo, sha);
+            return send(self.authorized(config, self.client.post(url)).json(&body)).await.map(drop);
+        }
+
+        let deployment = match self.deployments.get(&job.id) {
+            Some(&id) => id,
+            None => {
+                let body = json!({
+                    "ref": sha,
+                    "environment": config.environment,
+                    "description": description,
+                    "auto_merge": false,
+                    "required_contexts": [],
+                });
+                let url = format!("{}/repos/{}/deployments", api, repo);
+                let created = send(self.authorized(config, self.client.post(url)).json(&body)).await?;
+                let id = created["id"].as_u64().context("deployment response has no id")?;
+                self.deployments.insert(job.id, id);
+                id
+            }
+        };
+        let state = match job.status {
+            Status::Queued => "queued",
+            Status::Running => "in_progress",
+            Status::Succeeded => "success",
+            Status::Failed | Status::RolledBack => "failure",
+            Status::Cancelled => "inactive",
+        };
+        let mut body = json!({ "state": state, "environment": config.environment, "description": description });
+        if let Some(url) = self.job_url(job) {
+            body["log_url"] = json!(url);
+        }
+        let url = format!("{}/repos/{}/deployments/{}/statuses", api, repo, deployment);
+        send(self.authorized(config, self.client.post(url)).json(&body)).await.map(drop)
+    }
+
+    fn authorized(&self, config: &StatusConfig, request: RequestBuilder) -> RequestBuilder {
+        match config.kind {
+            ForgeApi::Github => request
+                .bearer_auth(&config.token)
+                .header("Accept", "application/vnd.github+json")
+                .header("User-Agent", "webhook_handler"),
+            ForgeApi::Gitea => request.header("Authorization", format!("token {}", config.token)),
+        }
+    }
+
+    fn job_url(&self, job: &JobInfo) -> Option<String> {
+        let base = self.config.public_url.as_deref()?;
+        Some(format!("{}/jobs/{}", base.trim_end_matches('/'), job.id))
+    }
+
+    /// e.g. `failed: unhealthy after 60s`.
+    fn description(&self, job: &JobInfo) -> String {
+        let status = job.status.name().replace('_', " ");
+        match (&job.error, job.exit_code) {
+            (Some(error), _) => format!("{}: {}", status, error),
+            (None, Some(code)) if code != 0 => format!("{} with exit code {}", status, code),
+            _ => status,
+        }
+    }
+
+    /// e.g. `job 12 (deploy api) for yourorg/stack@1a2b3c4 succeeded`.
+    fn message(&self, job: &JobInfo) -> String {
+        let mut text = format!("job {} ({})", job.id, job.trigger.action);
+        if let Some(repo) = &job.trigger.repository {
+            text.push_str(&format!(" for {}", repo));
+            if let Some(sha) = &job.trigger.sha {
+                text.push_str(&format!("@{}", sha.get(..7).unwrap_or(sha)));
+            }
+        }
+        text.push_str(&format!(" {}", self.description(job)));
+        if let Some(url) = self.job_url(job) {
+            text.push_str(&format!(" {}", url));
+        }
+        text
+    }
+}
+
+/// Send and fail on non-2xx answers. Returns the answer's JSON, or null.
+async fn send(request: RequestBuilder) -> Result<Value> {
+    let response = request.send().await?;
+    let status = response.status();
+    let text = response.text().await.unwrap_or_default();
+    if !status.is_success() {
+        anyhow::bail!("{}: {}", status, truncate(text.trim(), 200));
+    }
+    Ok(serde_json::from_str(&text).unwrap_or(Value::Null))
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let mut short: String = text.chars().take(max - 1).collect();
+    short.push('…');
+    short
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::extract::State;
+    use axum::http::{HeaderMap, Uri};
+    use axum::{Json, Router};
+    use chrono::Utc;
+
+    use super::*;
+    use crate::jobs::Trigger;
+
+    /// A request the mock forge received: path, `Authorization` and body.
+    type Received = (String, String, Value);
+
+    /// A forge API on a local port that records every request and answers
+    /// deployment creations with ID 7.
+    async fn mock_forge() -> (String, Arc<Mutex<Vec<Received>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let record = |State(received): State<Arc<Mutex<Vec<Received>>>>,
+                      uri: Uri,
+                      headers: HeaderMap,
+                      Json(body): Json<Value>| async move {
+            let auth = headers.get("authorization").and_then(|v| v.to_str().ok()).unwrap_or("").to_string();
+            let created = uri.path().ends_with("/deployments");
+            received.lock().unwrap().push((uri.path().to_string(), auth, body));
+            Json(if created { json!({ "id": 7 }) } else { json!({}) })
+        };
+        let app = Router::new().fallback(record).with_state(received.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, received)
+    }
+
+    fn job(status: Status) -> JobInfo {
+        JobInfo {
+            id: 3,
+            trigger: Trigger {
+                route: None,
+                action: "deploy api".into(),
+                event: "push".into(),
+                repository: Some("org/stack".into()),
+                git_ref: Some("refs/heads/main".into()),
+                sha: Some("1a2b3c4d".into()),
+                pusher: None,
+                delivery_id: None,
+                payload: Default::default(),
+                deploy: None,
+                health_check: None,
+                rollback: None,
+                sandbox: Default::default(),
+            },
+            status,
+            queued_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+            exit_code: None,
+            error: None,
+            previous_sha: None,
+            steps: Vec::new(),
+        }
+    }
+
+    fn worker(kind: ForgeApi, api_url: String, deployments: bool) -> (Worker, StatusConfig) {
+        let status = StatusConfig {
+            kind,
+            api_url,
+            token: "t0k".into(),
+            context: "deploy".into(),
+            deployments,
+            environment: "production".into(),
+            repository: None,
+        };
+        let config = NotifyConfig { public_url: Some("https://deploy.example.com/".into()), ..Default::default() };
+        (Worker { config, client: Client::new(), deployments: HashMap::new() }, status)
+    }
+
+    async fn report(kind: ForgeApi, api_url: String, deployments: bool, statuses: &[Status]) {
+        let (mut worker, config) = worker(kind, api_url, deployments);
+        for &status in statuses {
+            worker.post_status(&config, &job(status)).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_commit_statuses_to_github_and_gitea() {
+        let (url, received) = mock_forge().await;
+        report(ForgeApi::Github, format!("{}/", url), false, &[Status::Running, Status::Succeeded]).await;
+        let mut failed = job(Status::Failed);
+        failed.exit_code = Some(2);
+        let (mut gitea, config) = worker(ForgeApi::Gitea, format!("{}/api/v1", url), false);
+        gitea.post_status(&config, &job(Status::Queued)).await.unwrap();
+        gitea.post_status(&config, &failed).await.unwrap();
+
+        let received = received.lock().unwrap();
+        let states: Vec<_> = received.iter().map(|(_, _, body)| body["state"].as_str().unwrap()).collect();
+        assert_eq!(states, ["pending", "success", "pending", "failure"]);
+        for (path, auth, body) in &received[..2] {
+            assert_eq!(path, "/repos/org/stack/statuses/1a2b3c4d");
+            assert_eq!(auth, "Bearer t0k");
+            assert_eq!(body["context"], "deploy");
+            assert_eq!(body["target_url"], "https://deploy.example.com/jobs/3");
+        }
+        for (path, auth, _) in &received[2..] {
+            assert_eq!(path, "/api/v1/repos/org/stack/statuses/1a2b3c4d");
+            assert_eq!(auth, "token t0k");
+        }
+        assert_eq!(received[1].2["description"], "succeeded");
+        assert_eq!(received[3].2["description"], "failed with exit code 2");
+    }
+
+    #[tokio::test]
+    async fn reports_github_deployment_statuses() {
+        let (url, received) = mock_forge().await;
+        report(ForgeApi::Github, url, true, &[Status::Queued, Status::Running, Status::RolledBack]).await;
+
+        let received = received.lock().unwrap();
+        let paths: Vec<_> = received.iter().map(|(path, _, _)| path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/repos/org/stack/deployments",
+                "/repos/org/stack/deployments/7/statuses",
+                "/repos/org/stack/deployments/7/statuses",
+                "/repos/org/stack/deployments/7/statuses",
+            ]
+        );
+        assert_eq!(received[0].2["ref"], "1a2b3c4d");
+        assert_eq!(received[0].2["environment"], "production");
+        let states: Vec<_> = received[1..].iter().map(|(_, _, body)| body["state"].as_str().unwrap()).collect();
+        assert_eq!(states, ["queued", "in_progress", "failure"]);
+        assert_eq!(received[3].2["log_url"], "https://deploy.example.com/jobs/3");
+    }
+}
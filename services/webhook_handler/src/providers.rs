@@ -0,0 +1,320 @@
+//! The forges deliveries come from. Each signs requests its own way, names
+//! events in its own header and lays out its payload differently; this module
+//! checks the signature and reduces every payload to the same [`Event`].
+
+use std::borrow::Cow;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::http::{HeaderMap, StatusCode};
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+
+use crate::config::{Algorithm, ProviderConfig, ProviderKind, ServerConfig};
+use crate::routing::Event;
+
+pub fn secret<'a>(provider: &'a ProviderConfig, server: &'a ServerConfig) -> &'a str {
+    provider.secret.as_deref().unwrap_or(&server.secret)
+}
+
+/// Check a delivery's signature, and its timestamp if the provider signs
+/// one: 400 if either is missing, 401 if either is wrong.
+pub fn verify(provider: &ProviderConfig, server: &ServerConfig, headers: &HeaderMap, body: &[u8]) -> Result<(), StatusCode> {
+    let secret = secret(provider, server).as_bytes();
+    let name = signature_header(provider);
+    let (algorithm, prefix) = match provider.kind {
+        ProviderKind::Github | ProviderKind::Bitbucket => (Algorithm::Sha256, "sha256="),
+        ProviderKind::Gitea | ProviderKind::Forgejo => (Algorithm::Sha256, ""),
+        ProviderKind::Generic => (provider.algorithm, provider.prefix.as_str()),
+        ProviderKind::Gitlab => {
+            // GitLab sends the secret itself rather than a signature.
+            let token = header(headers, name).ok_or(StatusCode::BAD_REQUEST)?;
+            return if constant_time_eq(token.as_bytes(), secret) { Ok(()) } else { Err(StatusCode::UNAUTHORIZED) };
+        }
+    };
+    let signature = header(headers, name).ok_or(StatusCode::BAD_REQUEST)?;
+    let signature = signature.strip_prefix(prefix).unwrap_or(signature);
+    let signature = hex::decode(signature).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let signed = match &provider.timestamp_header {
+        Some(name) => {
+            let timestamp = header(headers, name).ok_or(StatusCode::BAD_REQUEST)?;
+            let at: u64 = timestamp.trim().parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            if now.abs_diff(at) > server.max_skew_secs {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+            Cow::Owned([timestamp.as_bytes(), b".", body].concat())
+        }
+        None => Cow::Borrowed(body),
+    };
+    if verify_hmac(algorithm, secret, &signed, &signature) {
+        Ok(())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// The header carrying the provider's signature, or for GitLab the secret
+/// itself.
+pub fn signature_header(provider: &ProviderConfig) -> &str {
+    match provider.kind {
+        ProviderKind::Github => "X-Hub-Signature-256",
+        ProviderKind::Gitlab => "X-Gitlab-Token",
+        ProviderKind::Gitea => "X-Gitea-Signature",
+        ProviderKind::Forgejo => "X-Forgejo-Signature",
+        ProviderKind::Bitbucket => "X-Hub-Signature",
+        ProviderKind::Generic => provider.header.as_deref().expect("validated: generic providers have a header"),
+    }
+}
+
+fn verify_hmac(algorithm: Algorithm, key: &[u8], body: &[u8], signature: &[u8]) -> bool {
+    fn verify<M: Mac + KeyInit>(key: &[u8], body: &[u8], signature: &[u8]) -> bool {
+        let mut mac = <M as KeyInit>::new_from_slice(key).expect("hmac can take key of any size");
+        mac.update(body);
+        mac.verify_slice(signature).is_ok()
+    }
+    match algorithm {
+        Algorithm::Sha1 => verify::<Hmac<Sha1>>(key, body, signature),
+        Algorithm::Sha256 => verify::<Hmac<Sha256>>(key, body, signature),
+        Algorithm::Sha512 => verify::<Hmac<Sha512>>(key, body, signature),
+    }
+}
+
+/// Compare secrets without leaking how much of them matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The provider's unique ID for this delivery, if it sends one. Redeliveries
+/// reuse it.
+pub fn delivery_id<'a>(provider: &ProviderConfig, headers: &'a HeaderMap) -> Option<&'a str> {
+    match provider.kind {
+        ProviderKind::Github => header(headers, "X-GitHub-Delivery"),
+        ProviderKind::Gitlab => header(headers, "X-Gitlab-Event-UUID"),
+        ProviderKind::Gitea => header(headers, "X-Gitea-Delivery"),
+        ProviderKind::Forgejo => header(headers, "X-Forgejo-Delivery"),
+        ProviderKind::Bitbucket => header(headers, "X-Request-UUID").or_else(|| header(headers, "X-Request-Id")),
+        ProviderKind::Generic => header(headers, provider.delivery_header.as_deref()?),
+    }
+}
+
+/// Extract the routing fields. A body that is not JSON simply has none.
+pub fn event(provider: &ProviderConfig, headers: &HeaderMap, body: &[u8]) -> Event {
+    let payload: Value = serde_json::from_slice(body).unwrap_or(Value::Null);
+    let text = |v: &Value| v.as_str().map(str::to_string);
+    let first = |values: &[&Value]| values.iter().find_map(|v| text(v));
+    let kind = |name: &str| header(headers, name).unwrap_or("").to_string();
+    // GitHub's layout, which Gitea and Forgejo copy. GitHub names the pusher,
+    // Gitea gives their login.
+    let github = |kind: String| Event {
+        kind,
+        repository: text(&payload["repository"]["full_name"]),
+        git_ref: text(&payload["ref"]),
+        sha: text(&payload["after"]),
+        pusher: first(&[&payload["pusher"]["name"], &payload["pusher"]["login"]]),
+    };
+    match provider.kind {
+        ProviderKind::Github => github(kind("X-GitHub-Event")),
+        ProviderKind::Gitea => github(kind("X-Gitea-Event")),
+        ProviderKind::Forgejo => github(kind("X-Forgejo-Event")),
+        ProviderKind::Generic => {
+            github(provider.event_header.as_deref().map(kind).unwrap_or_else(|| "push".into()))
+        }
+        ProviderKind::Gitlab => Event {
+            kind: gitlab_event(&kind("X-Gitlab-Event")),
+            repository: text(&payload["project"]["path_with_namespace"]),
+            git_ref: text(&payload["ref"]),
+            sha: first(&[&payload["checkout_sha"], &payload["after"]]),
+            pusher: first(&[&payload["user_username"], &payload["user_name"]]),
+        },
+        ProviderKind::Bitbucket => {
+            let event = kind("X-Event-Key");
+            Event {
+                kind: event.strip_prefix("repo:").unwrap_or(&event).to_string(),
+                repository: text(&payload["repository"]["full_name"]),
+                git_ref: bitbucket_ref(&payload),
+                sha: first(&[&payload["push"]["changes"][0]["new"]["target"]["hash"], &payload["changes"][0]["toHash"]]),
+                pusher: first(&[&payload["actor"]["nickname"], &payload["actor"]["name"]]),
+            }
+        }
+    }
+}
+
+/// `Push Hook` becomes `push` and `Merge Request Hook` `merge_request`. Tag
+/// pushes are plain `push` events, as on GitHub, told apart by their ref.
+fn gitlab_event(header: &str) -> String {
+    let name = header.trim_end_matches(" Hook").to_lowercase().replace(' ', "_");
+    if name == "tag_push" {
+        "push".into()
+    } else {
+        name
+    }
+}
+
+/// Bitbucket Cloud names the pushed branch or tag; Data Center gives the full ref.
+fn bitbucket_ref(payload: &Value) -> Option<String> {
+    if let Some(id) = payload["changes"][0]["ref"]["id"].as_str() {
+        return Some(id.to_string());
+    }
+    let new = &payload["push"]["changes"][0]["new"];
+    let name = new["name"].as_str()?;
+    match new["type"].as_str()? {
+        "branch" => Some(format!("refs/heads/{}", name)),
+        "tag" => Some(format!("refs/tags/{}", name)),
+        _ => None,
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    const BODY: &[u8] = br#"{"ref":"refs/heads/main","after":"1a2b3c4d"}"#;
+    const TAMPERED: &[u8] = br#"{"ref":"refs/heads/prod","after":"1a2b3c4d"}"#;
+
+    fn provider(toml: &str) -> ProviderConfig {
+        toml::from_str(&format!("path = \"/hook\"\nsecret = \"k3y\"\n{}", toml)).unwrap()
+    }
+
+    fn hmac_hex<M: Mac + KeyInit>(data: &[u8]) -> String {
+        let mut mac = <M as KeyInit>::new_from_slice(b"k3y").unwrap();
+        mac.update(data);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn headers(pairs: &[(&'static str, String)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    /// Check `signed` headers pass for `BODY`, fail for a tampered body, and
+    /// that without `name` the delivery is a bad request.
+    fn check(provider: &ProviderConfig, signed: HeaderMap, name: &str) {
+        let server = ServerConfig::default();
+        assert_eq!(verify(provider, &server, &signed, BODY), Ok(()));
+        assert_eq!(verify(provider, &server, &signed, TAMPERED), Err(StatusCode::UNAUTHORIZED));
+        let mut unsigned = signed.clone();
+        unsigned.remove(name);
+        assert_eq!(verify(provider, &server, &unsigned, BODY), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn verifies_sha256_prefixed_signatures() {
+        let signature = format!("sha256={}", hmac_hex::<Hmac<Sha256>>(BODY));
+        let github = provider("kind = \"github\"");
+        check(&github, headers(&[("X-Hub-Signature-256", signature.clone())]), "X-Hub-Signature-256");
+        let bitbucket = provider("kind = \"bitbucket\"");
+        check(&bitbucket, headers(&[("X-Hub-Signature", signature)]), "X-Hub-Signature");
+
+        let server = ServerConfig::default();
+        let garbage = headers(&[("X-Hub-Signature-256", "sha256=not-hex".into())]);
+        assert_eq!(verify(&github, &server, &garbage, BODY), Err(StatusCode::UNAUTHORIZED));
+        let sha1 = headers(&[("X-Hub-Signature-256", format!("sha256={}", hmac_hex::<Hmac<Sha1>>(BODY)))]);
+        assert_eq!(verify(&github, &server, &sha1, BODY), Err(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn verifies_raw_hex_signatures() {
+        let signature = hmac_hex::<Hmac<Sha256>>(BODY);
+        let gitea = provider("kind = \"gitea\"");
+        check(&gitea, headers(&[("X-Gitea-Signature", signature.clone())]), "X-Gitea-Signature");
+        let forgejo = provider("kind = \"forgejo\"");
+        check(&forgejo, headers(&[("X-Forgejo-Signature", signature.clone())]), "X-Forgejo-Signature");
+
+        // Each only looks at its own header.
+        let server = ServerConfig::default();
+        let wrong = headers(&[("X-Gitea-Signature", signature)]);
+        assert_eq!(verify(&forgejo, &server, &wrong, BODY), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn compares_gitlab_tokens() {
+        let gitlab = provider("kind = \"gitlab\"");
+        let server = ServerConfig::default();
+        let token = |value: &str| headers(&[("X-Gitlab-Token", value.into())]);
+        // The token is the secret itself and does not depend on the body.
+        assert_eq!(verify(&gitlab, &server, &token("k3y"), BODY), Ok(()));
+        assert_eq!(verify(&gitlab, &server, &token("k3y"), TAMPERED), Ok(()));
+        assert_eq!(verify(&gitlab, &server, &token("k3"), BODY), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(verify(&gitlab, &server, &token("k3yy"), BODY), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(verify(&gitlab, &server, &HeaderMap::new(), BODY), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn verifies_generic_algorithms_and_prefixes() {
+        let sha512 = provider("kind = \"generic\"\nheader = \"X-Signature\"\nalgorithm = \"sha512\"\nprefix = \"sha512=\"");
+        let signature = format!("sha512={}", hmac_hex::<Hmac<Sha512>>(BODY));
+        check(&sha512, headers(&[("X-Signature", signature)]), "X-Signature");
+
+        let sha1 = provider("kind = \"generic\"\nheader = \"X-Signature\"\nalgorithm = \"sha1\"");
+        check(&sha1, headers(&[("X-Signature", hmac_hex::<Hmac<Sha1>>(BODY))]), "X-Signature");
+    }
+
+    #[test]
+    fn verifies_generic_timestamps() {
+        let generic = provider("kind = \"generic\"\nheader = \"X-Signature\"\ntimestamp_header = \"X-Timestamp\"");
+        let server = ServerConfig::default();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let signed_at = |at: u64| {
+            let signed = [at.to_string().as_bytes(), b".", BODY].concat();
+            headers(&[("X-Signature", hmac_hex::<Hmac<Sha256>>(&signed)), ("X-Timestamp", at.to_string())])
+        };
+        check(&generic, signed_at(now), "X-Signature");
+        check(&generic, signed_at(now), "X-Timestamp");
+        let stale = now - server.max_skew_secs - 1;
+        assert_eq!(verify(&generic, &server, &signed_at(stale), BODY), Err(StatusCode::UNAUTHORIZED));
+
+        // The timestamp is part of what is signed.
+        let mut moved = signed_at(now);
+        moved.insert("X-Timestamp", HeaderValue::from_str(&(now + 1).to_string()).unwrap());
+        assert_eq!(verify(&generic, &server, &moved, BODY), Err(StatusCode::UNAUTHORIZED));
+        let body_only = headers(&[("X-Signature", hmac_hex::<Hmac<Sha256>>(BODY)), ("X-Timestamp", now.to_string())]);
+        assert_eq!(verify(&generic, &server, &body_only, BODY), Err(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn falls_back_to_the_server_secret() {
+        let github: ProviderConfig = toml::from_str("kind = \"github\"\npath = \"/hook\"").unwrap();
+        let server = ServerConfig { secret: "k3y".into(), ..ServerConfig::default() };
+        let signature = format!("sha256={}", hmac_hex::<Hmac<Sha256>>(BODY));
+        assert_eq!(verify(&github, &server, &headers(&[("X-Hub-Signature-256", signature)]), BODY), Ok(()));
+    }
+
+    #[test]
+    fn reduces_payloads_to_events() {
+        let gitlab = provider("kind = \"gitlab\"");
+        let payload = br#"{"project":{"path_with_namespace":"org/app"},"ref":"refs/tags/v1","checkout_sha":"abc1234","user_username":"ana"}"#;
+        let e = event(&gitlab, &headers(&[("X-Gitlab-Event", "Tag Push Hook".into())]), payload);
+        assert_eq!(e.kind, "push");
+        assert_eq!(e.repository.as_deref(), Some("org/app"));
+        assert_eq!(e.git_ref.as_deref(), Some("refs/tags/v1"));
+        assert_eq!(e.sha.as_deref(), Some("abc1234"));
+        assert_eq!(e.pusher.as_deref(), Some("ana"));
+
+        let bitbucket = provider("kind = \"bitbucket\"");
+        let payload = br#"{"repository":{"full_name":"org/app"},"actor":{"nickname":"bo"},
+            "push":{"changes":[{"new":{"type":"branch","name":"main","target":{"hash":"def5678"}}}]}}"#;
+        let e = event(&bitbucket, &headers(&[("X-Event-Key", "repo:push".into())]), payload);
+        assert_eq!(e.kind, "push");
+        assert_eq!(e.git_ref.as_deref(), Some("refs/heads/main"));
+        assert_eq!(e.sha.as_deref(), Some("def5678"));
+        assert_eq!(e.pusher.as_deref(), Some("bo"));
+
+        let gitea = provider("kind = \"gitea\"");
+        let payload = br#"{"repository":{"full_name":"org/app"},"ref":"refs/heads/main","after":"1a2b3c4d","pusher":{"login":"cy"}}"#;
+        let e = event(&gitea, &headers(&[("X-Gitea-Event", "push".into())]), payload);
+        assert_eq!((e.kind.as_str(), e.pusher.as_deref()), ("push", Some("cy")));
+        assert!(event(&gitea, &HeaderMap::new(), b"not json").repository.is_none());
+    }
+}
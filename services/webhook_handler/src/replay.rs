@@ -0,0 +1,62 @@
+//! Recently seen delivery IDs, so a captured delivery cannot be sent again
+//! while its signature is still valid. Kept in memory only: a restart forgets
+//! them.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub struct ReplayGuard {
+    ttl: Duration,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Default)]
+struct Seen {
+    ids: HashSet<String>,
+    /// The same IDs, oldest first, for expiry.
+    order: VecDeque<(Instant, String)>,
+}
+
+impl ReplayGuard {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, seen: Mutex::default() }
+    }
+
+    /// Record `id`. False if it was already seen within the TTL.
+    pub fn first_delivery(&self, id: &str) -> bool {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        while let Some((at, _)) = seen.order.front() {
+            if now.duration_since(*at) < self.ttl {
+                break;
+            }
+            let (_, expired) = seen.order.pop_front().expect("front exists");
+            seen.ids.remove(&expired);
+        }
+        if !seen.ids.insert(id.to_string()) {
+            return false;
+        }
+        seen.order.push_back((now, id.to_string()));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_ids_seen_within_the_ttl() {
+        let guard = ReplayGuard::new(Duration::from_millis(50));
+        assert!(guard.first_delivery("/github a"));
+        assert!(guard.first_delivery("/github b"));
+        assert!(!guard.first_delivery("/github a"));
+        assert!(guard.first_delivery("/gitea a"));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(guard.first_delivery("/github a"));
+        assert!(!guard.first_delivery("/github a"));
+        assert_eq!(guard.seen.lock().unwrap().order.len(), 1);
+    }
+}
@@ -0,0 +1,167 @@
+//! Picks the action for an incoming event from the `[[routes]]` table.
+
+use crate::config::RouteConfig;
+
+/// The parts of a delivery that routes match on, whichever forge sent it.
+#[derive(Debug)]
+pub struct Event {
+    /// GitHub's name for the event, e.g. `push`; see `providers`.
+    pub kind: String,
+    /// `repository.full_name`, e.g. `yourorg/yourrepo`.
+    pub repository: Option<String>,
+    /// `ref`, e.g. `refs/heads/main`, for events that have one.
+    pub git_ref: Option<String>,
+    /// The commit a push moved its ref to.
+    pub sha: Option<String>,
+    /// Who pushed.
+    pub pusher: Option<String>,
+}
+
+impl Event {
+    pub fn branch(&self) -> Option<&str> {
+        self.git_ref.as_deref()?.strip_prefix("refs/heads/")
+    }
+}
+
+/// The first route matching `event`.
+pub fn route<'a>(routes: &'a [RouteConfig], event: &Event) -> Option<&'a RouteConfig> {
+    routes.iter().find(|r| matches(r, event))
+}
+
+fn matches(route: &RouteConfig, event: &Event) -> bool {
+    let field = |pattern: &Option<String>, value: Option<&str>| match (pattern, value) {
+        (None, _) => true,
+        (Some(pattern), Some(value)) => glob(pattern, value),
+        (Some(_), None) => false,
+    };
+    (route.events.is_empty() || route.events.contains(&event.kind))
+        && field(&route.repository, event.repository.as_deref())
+        && field(&route.branch, event.branch())
+        && field(&route.ref_, event.git_ref.as_deref())
+}
+
+/// Match `text` against a pattern where `*` is any run of characters
+/// (including `/`) and `?` is exactly one.
+pub fn glob(pattern: &str, text: &str) -> bool {
+    let (p, t): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut pi, mut ti) = (0, 0);
+    // Position of the last `*` and the text index it is currently absorbing up to.
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn routes(toml: &str) -> Vec<RouteConfig> {
+        toml::from_str::<Config>(toml).unwrap().routes
+    }
+
+    fn event(kind: &str, repository: &str, git_ref: &str) -> Event {
+        Event {
+            kind: kind.into(),
+            repository: Some(repository.into()),
+            git_ref: Some(git_ref.into()).filter(|r: &String| !r.is_empty()),
+            sha: None,
+            pusher: None,
+        }
+    }
+
+    fn routed(routes: &[RouteConfig], event: &Event) -> Option<String> {
+        route(routes, event).map(|r| r.name.clone())
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob("main", "main"));
+        assert!(!glob("main", "main2"));
+        assert!(!glob("main", "mai"));
+        assert!(glob("release/*", "release/1.2"));
+        assert!(glob("release/*", "release/1.2/hotfix"));
+        assert!(!glob("release/*", "releases/1.2"));
+        assert!(glob("**", "yourorg/yourrepo"));
+        assert!(glob("yourorg/**", "yourorg/a/b"));
+        assert!(glob("*", ""));
+        assert!(glob("v?.*", "v1.10"));
+        assert!(!glob("v?.*", "v10.1"));
+        assert!(glob("*-svc*", "yourorg/api-svc-v2"));
+        assert!(!glob("*-svc", "yourorg/api-svc-v2"));
+    }
+
+    #[test]
+    fn filters_on_events_repository_branch_and_ref() {
+        let routes = routes(
+            r#"
+            [[routes]]
+            name = "tags"
+            events = ["push"]
+            ref = "refs/tags/v*"
+            action = "/bin/true"
+
+            [[routes]]
+            name = "main"
+            events = ["push"]
+            repository = "yourorg/*"
+            branch = "main"
+            action = "/bin/true"
+
+            [[routes]]
+            name = "releases"
+            events = ["release", "create"]
+            action = "/bin/true"
+            "#,
+        );
+        assert_eq!(routed(&routes, &event("push", "yourorg/api", "refs/heads/main")).as_deref(), Some("main"));
+        assert_eq!(routed(&routes, &event("push", "other/api", "refs/heads/main")), None);
+        assert_eq!(routed(&routes, &event("push", "yourorg/api", "refs/heads/feature")), None);
+        // A branch filter never matches a tag, nor a ref filter an event without one.
+        assert_eq!(routed(&routes, &event("push", "yourorg/api", "refs/tags/main")), None);
+        assert_eq!(routed(&routes, &event("push", "yourorg/api", "refs/tags/v1.0")).as_deref(), Some("tags"));
+        assert_eq!(routed(&routes, &event("create", "yourorg/api", "")).as_deref(), Some("releases"));
+        assert_eq!(routed(&routes, &event("star", "yourorg/api", "")), None);
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let routes = routes(
+            r#"
+            [[routes]]
+            name = "hotfix"
+            branch = "release/*-hotfix"
+            action = "/bin/true"
+
+            [[routes]]
+            name = "release"
+            branch = "release/**"
+            action = "/bin/true"
+
+            [[routes]]
+            name = "anything"
+            action = "/bin/true"
+            "#,
+        );
+        let e = |git_ref| event("push", "yourorg/api", git_ref);
+        assert_eq!(routed(&routes, &e("refs/heads/release/2-hotfix")).as_deref(), Some("hotfix"));
+        assert_eq!(routed(&routes, &e("refs/heads/release/2")).as_deref(), Some("release"));
+        assert_eq!(routed(&routes, &e("refs/heads/main")).as_deref(), Some("anything"));
+        assert_eq!(routed(&routes, &event("ping", "yourorg/api", "")).as_deref(), Some("anything"));
+        assert_eq!(routed(&routes[..2], &e("refs/heads/main")), None);
+    }
+}
@@ -0,0 +1,57 @@
+//! Confinement of the commands jobs run. Each starts in its own process
+//! group, so a timeout or cancel can kill whatever it spawned too, with a
+//! scrubbed environment and, if configured, another user and rlimits.
+
+use std::env;
+use std::io;
+
+use tokio::process::Command;
+
+use crate::config::SandboxConfig;
+
+/// Set up `command` to run confined by `sandbox`. The caller adds the
+/// event's variables afterwards.
+pub fn apply(command: &mut Command, sandbox: &SandboxConfig) {
+    command.env_clear();
+    for name in &sandbox.env {
+        if let Some(value) = env::var_os(name) {
+            command.env(name, value);
+        }
+    }
+    command.process_group(0);
+    if let Some(gid) = sandbox.gid {
+        command.gid(gid);
+    }
+    if let Some(uid) = sandbox.uid {
+        command.uid(uid);
+    }
+    let cpu = sandbox.cpu_secs;
+    let memory = sandbox.memory_mb.map(|mb| mb.saturating_mul(1 << 20));
+    if cpu.is_none() && memory.is_none() {
+        return;
+    }
+    // SAFETY: the closure runs in the forked child before exec and only
+    // calls setrlimit, which is async-signal-safe.
+    unsafe {
+        command.pre_exec(move || {
+            let limits = [(libc::RLIMIT_CPU, cpu), (libc::RLIMIT_AS, memory)];
+            for (resource, value) in limits {
+                let Some(value) = value else { continue };
+                let limit = libc::rlimit { rlim_cur: value as libc::rlim_t, rlim_max: value as libc::rlim_t };
+                if libc::setrlimit(resource, &limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Kill the process group led by `pid`: the command and everything it
+/// started that has not moved to a group of its own.
+pub fn kill_group(pid: u32) {
+    // SAFETY: kill has no memory safety requirements.
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}